@@ -0,0 +1,84 @@
+//! How the budget chat room's broadcast scales with the number of participants.
+//!
+//! Run with `cargo bench --bench chat_broadcast`.
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use protohacker_in_rust::protohackers::problem3::{
+    ChatRoom as Room, ChatText, ClientId, RoomConfig, UserHandle, Username,
+};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+const PARTICIPANTS: [usize; 3] = [10, 100, 1000];
+
+fn client_id(n: usize) -> ClientId {
+    let port = 10_000 + n as u16;
+    ClientId::new(SocketAddr::from(([127, 0, 0, 1], port)))
+}
+
+/// A room holding `participants` users, with every join notification already drained.
+async fn full_room(participants: usize) -> (Room, Vec<UserHandle>) {
+    let room = Room::with_config(RoomConfig {
+        capacity: participants,
+        ..RoomConfig::default()
+    });
+    let mut users = Vec::with_capacity(participants);
+    for n in 0..participants {
+        let username = Username::parse(&format!("user{n}")).unwrap();
+        users.push(room.join(client_id(n), username).unwrap());
+    }
+    wait_for_room(&room).await;
+    drain(&mut users);
+    (room, users)
+}
+
+/// The room handles its messages in order, so once this answers every message
+/// sent before has been fanned out.
+async fn wait_for_room(room: &Room) {
+    room.list_users(client_id(0)).await.unwrap();
+}
+
+fn drain(users: &mut [UserHandle]) -> usize {
+    users
+        .iter_mut()
+        .map(|user| std::iter::from_fn(|| user.receiver.try_recv().ok()).count())
+        .sum()
+}
+
+/// Time `iters` chat messages from the first user, until every other user received them.
+fn broadcast(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("chat_broadcast");
+
+    for participants in PARTICIPANTS {
+        let (room, mut users) = runtime.block_on(full_room(participants));
+        let sender = client_id(0);
+
+        group.throughput(Throughput::Elements(participants as u64 - 1));
+        group.bench_function(BenchmarkId::from_parameter(participants), |b| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        room.send_chat(sender.clone(), ChatText::from("hello, everyone"))
+                            .unwrap();
+                    }
+                    wait_for_room(&room).await;
+                    let delivered = drain(&mut users);
+                    let elapsed = start.elapsed();
+
+                    assert_eq!(delivered, iters as usize * (participants - 1));
+                    elapsed
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(5));
+    targets = broadcast
+}
+criterion_main!(benches);
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use protohacker_in_rust::protohackers::problem7::parse_packet;
+
+// Arbitrary UDP payloads must be answered with Ok or Err, never a panic.
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_packet(data);
+});
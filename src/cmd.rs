@@ -1,4 +1,17 @@
+use crate::maelstrom::DEFAULT_MAX_LINE_LENGTH;
+use crate::protohackers::problem0::DEFAULT_IDLE_TIMEOUT;
+use crate::protohackers::problem1::PrimeStrategy;
+use crate::protohackers::problem3::{
+    ChatEncoding, ChatStyle, DEFAULT_MAX_MESSAGE_LENGTH, DEFAULT_MESSAGES_PER_SECOND,
+    DEFAULT_ROOM_CAPACITY,
+};
+use crate::protohackers::problem5::{DEFAULT_PROXY_IDLE_TIMEOUT, TONY_ACCOUNT, UPSTREAM_ADDR};
+use crate::protohackers::problem6::DEFAULT_SPEED_TOLERANCE_100X;
+use crate::protohackers::problem7::LineApp;
+use crate::protohackers::{DEFAULT_GRACE_PERIOD, HOST};
+use crate::{Error, Result};
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[clap(author = "zhaowei", version, about)]
@@ -24,46 +37,201 @@ pub enum ProtohackerCases {
     SmokeEcho {
         #[arg(short, long, default_value_t = default_port())]
         port: u32,
+        /// Seconds a client may stay silent before its connection is closed
+        #[arg(long, default_value_t = DEFAULT_IDLE_TIMEOUT.as_secs())]
+        idle_timeout_secs: u64,
+        #[command(flatten)]
+        server: ServerArgs,
     },
     PrimeTime {
         #[arg(short, long, default_value_t = default_port())]
         port: u32,
+        #[arg(long, value_enum, default_value_t)]
+        strategy: PrimeStrategy,
+        #[command(flatten)]
+        server: ServerArgs,
     },
     MeanToAnEnd {
         #[arg(short, long, default_value_t = default_port())]
         port: u32,
+        /// Skip messages with an unknown op code instead of closing the connection
+        #[arg(long)]
+        lenient: bool,
+        #[command(flatten)]
+        server: ServerArgs,
     },
     BudgetChat {
         #[arg(short, long, default_value_t = default_port())]
         port: u32,
+        #[arg(long, value_enum, default_value_t)]
+        encoding: ChatEncoding,
+        /// Lines sent to the users, legacy keeps the participant line of earlier versions
+        #[arg(long, value_enum, default_value_t)]
+        chat_format: ChatStyle,
+        /// Seconds between keepalive lines sent to each user, none when omitted
+        #[arg(long)]
+        keepalive_secs: Option<u64>,
+        /// Maximum number of users in the room
+        #[arg(long, default_value_t = DEFAULT_ROOM_CAPACITY)]
+        room_capacity: usize,
+        /// Longest chat message in bytes, longer ones are not broadcast
+        #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_LENGTH)]
+        max_message_length: usize,
+        /// Chat messages each user may send per second, 0 disables the limit
+        #[arg(long, default_value_t = DEFAULT_MESSAGES_PER_SECOND)]
+        messages_per_second: u32,
+        /// Let a client sending a blank line or /anon as its name join as guestN
+        #[arg(long)]
+        guest_names: bool,
+        #[command(flatten)]
+        server: ServerArgs,
     },
     BudgetChatExample {
         #[arg(short, long, default_value_t = default_port())]
         port: u32,
+        #[command(flatten)]
+        server: ServerArgs,
     },
     UnusualDatabase {
         #[arg(short, long, default_value_t = default_port())]
         port: u32,
+        /// Seconds an inserted key lives, keys never expire when omitted
+        #[arg(long)]
+        key_ttl_secs: Option<u64>,
+        /// Seconds between two sweeps of expired keys
+        #[arg(long, default_value_t = 60)]
+        sweep_interval_secs: u64,
+        /// Longest value in bytes an insert may store, longer ones are ignored
+        #[arg(long, default_value_t = 1000)]
+        max_value_bytes: usize,
+        /// Also accept key@SECONDS=value, inserting a key which expires after that long,
+        /// and key~ deleting a key
+        #[arg(long)]
+        extended_syntax: bool,
     },
     ModInMiddle {
         #[arg(short, long, default_value_t = default_port())]
         port: u32,
+        /// host:port of the budget chat server to relay to
+        #[arg(long, default_value_t = UPSTREAM_ADDR.to_string())]
+        upstream: String,
+        /// Boguscoin address written over the ones in relayed messages
+        #[arg(long, default_value_t = TONY_ACCOUNT.to_string())]
+        target_account: String,
+        /// Seconds without traffic either way before both sides are closed
+        #[arg(long, default_value_t = DEFAULT_PROXY_IDLE_TIMEOUT.as_secs())]
+        idle_timeout_secs: u64,
+        #[command(flatten)]
+        server: ServerArgs,
     },
     SpeedDaemon {
         #[arg(short, long, default_value_t = default_port())]
         port: u32,
+        /// Record the bytes of every connection into a file in this directory
+        #[arg(long)]
+        transcript_dir: Option<PathBuf>,
+        /// How far above the limit a car may go before it is ticketed, in hundredths of mph
+        #[arg(long, default_value_t = DEFAULT_SPEED_TOLERANCE_100X)]
+        speed_tolerance: u16,
+        #[command(flatten)]
+        server: ServerArgs,
     },
     LineReversal {
         #[arg(short, long, default_value_t = default_port())]
         port: u32,
+        /// Application run over the reliable transport
+        #[arg(long, value_enum, default_value_t)]
+        app: LineApp,
+    },
+    /// Run a problem by its number, with the default options of its subcommand
+    Run {
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..PROBLEM_CASES.len() as i64))]
+        problem: u8,
+        #[arg(short, long, default_value_t = default_port())]
+        port: u32,
     },
 }
 
+/// The subcommand of each problem, indexed by its number.
+const PROBLEM_CASES: [&str; 8] = [
+    "smoke-echo",
+    "prime-time",
+    "mean-to-an-end",
+    "budget-chat",
+    "unusual-database",
+    "mod-in-middle",
+    "speed-daemon",
+    "line-reversal",
+];
+
+impl ProtohackerCases {
+    /// Turn `Run` into the subcommand of its problem, as if that one had been typed
+    /// with only `--port`. Other cases are returned as they are.
+    pub fn resolve(self) -> Result<ProtohackerCases> {
+        let ProtohackerCases::Run { problem, port } = self else {
+            return Ok(self);
+        };
+        let case = PROBLEM_CASES
+            .get(problem as usize)
+            .ok_or_else(|| Error::Other(format!("there is no problem {}", problem)))?;
+        let args = Args::try_parse_from([
+            "protohacker-in-rust",
+            "protohackers",
+            case,
+            "--port",
+            &port.to_string(),
+        ])
+        .map_err(|e| Error::Other(e.to_string()))?;
+        match args.cmd {
+            Command::Protohackers { case } => Ok(case),
+            Command::Maelstrom { .. } => unreachable!("parsed a protohackers command"),
+        }
+    }
+}
+
 #[derive(Subcommand, Debug, Clone)]
 pub enum MaelstromCases {
     Echo,
     UniqueIds,
-    Broadcast,
+    Broadcast {
+        /// Longest accepted input line in bytes, longer lines are skipped
+        #[arg(long, default_value_t = DEFAULT_MAX_LINE_LENGTH)]
+        max_line_length: usize,
+        /// Gossip to every other node when no topology is received
+        #[arg(long)]
+        full_mesh_fallback: bool,
+        /// Milliseconds between pings to each neighbor, no keepalive when omitted
+        #[arg(long)]
+        keepalive_millis: Option<u64>,
+        /// Milliseconds a neighbor may stay silent before it is no longer gossiped to
+        #[arg(long, default_value_t = 1000)]
+        keepalive_timeout_millis: u64,
+    },
+    /// Append-only logs per key, the Kafka-style workload
+    Kafka,
+}
+
+/// Options of the TCP servers built on `ServerBuilder`.
+#[derive(clap::Args, Debug, Clone)]
+pub struct ServerArgs {
+    /// Address to listen on
+    #[arg(long, default_value_t = HOST.to_string())]
+    pub host: String,
+    /// Connections served at once, more wait for a slot; unlimited when omitted
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+    /// Connections served at once from one IP, more are closed; unlimited when omitted
+    #[arg(long)]
+    pub max_connections_per_ip: Option<usize>,
+    /// Seconds a connection may be served before it is closed, unlimited when omitted
+    #[arg(long)]
+    pub connection_timeout_secs: Option<u64>,
+    /// Seconds open connections may keep running once the server is asked to stop
+    #[arg(long, default_value_t = DEFAULT_GRACE_PERIOD.as_secs())]
+    pub grace_period_secs: u64,
+    /// Port printing the connection counters to whoever connects, none when omitted
+    #[arg(long)]
+    pub admin_port: Option<u32>,
 }
 
 fn default_port() -> u32 {
@@ -73,3 +241,85 @@ fn default_port() -> u32 {
         .and_then(|s| s.parse().ok())
         .unwrap_or(3000)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(args: &[&str]) -> Result<ProtohackerCases> {
+        let args = Args::try_parse_from(["protohacker-in-rust", "protohackers"].iter().chain(args))
+            .map_err(|e| Error::Other(e.to_string()))?;
+        let Command::Protohackers { case } = args.cmd else {
+            panic!("expected a protohackers command");
+        };
+        case.resolve()
+    }
+
+    #[test]
+    fn problem_numbers_resolve_to_their_subcommand() -> Result<()> {
+        for problem in 0..8 {
+            let case = resolve(&["run", "--problem", &problem.to_string(), "--port", "4000"])?;
+            let port = match (problem, case) {
+                (0, ProtohackerCases::SmokeEcho { port, .. }) => port,
+                (1, ProtohackerCases::PrimeTime { port, .. }) => port,
+                (2, ProtohackerCases::MeanToAnEnd { port, .. }) => port,
+                (3, ProtohackerCases::BudgetChat { port, .. }) => port,
+                (4, ProtohackerCases::UnusualDatabase { port, .. }) => port,
+                (5, ProtohackerCases::ModInMiddle { port, .. }) => port,
+                (6, ProtohackerCases::SpeedDaemon { port, .. }) => port,
+                (7, ProtohackerCases::LineReversal { port, .. }) => port,
+                (problem, case) => panic!("problem {problem} resolved to {case:?}"),
+            };
+            assert_eq!(port, 4000);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn run_uses_the_defaults_of_the_subcommand() -> Result<()> {
+        let case = resolve(&["run", "--problem", "3", "--port", "4000"])?;
+        let ProtohackerCases::BudgetChat {
+            room_capacity,
+            chat_format,
+            ..
+        } = case
+        else {
+            panic!("expected budget chat, got {case:?}");
+        };
+        assert_eq!(room_capacity, DEFAULT_ROOM_CAPACITY);
+        assert_eq!(chat_format, ChatStyle::Legacy);
+
+        // the named subcommands are left alone
+        let case = resolve(&["prime-time", "--port", "4000"])?;
+        assert!(matches!(
+            case,
+            ProtohackerCases::PrimeTime { port: 4000, .. }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_servers_share_the_server_options() -> Result<()> {
+        let case = resolve(&[
+            "speed-daemon",
+            "--max-connections",
+            "5",
+            "--admin-port",
+            "9000",
+        ])?;
+        let ProtohackerCases::SpeedDaemon { server, .. } = case else {
+            panic!("expected speed daemon, got {case:?}");
+        };
+        assert_eq!(server.max_connections, Some(5));
+        assert_eq!(server.admin_port, Some(9000));
+        assert_eq!(server.host, HOST);
+        assert_eq!(server.grace_period_secs, DEFAULT_GRACE_PERIOD.as_secs());
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_problem_numbers_are_rejected() {
+        assert!(resolve(&["run", "--problem", "8"]).is_err());
+        assert!(resolve(&["run", "--problem", "-1"]).is_err());
+    }
+}
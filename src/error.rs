@@ -10,16 +10,82 @@ pub enum Error {
     Serde(serde_json::Error),
     InvalidBinaryFormat(TryFromSliceError),
     InvalidProtocol(String),
+    /// A binary message starting with an op code the protocol does not define.
+    UnknownOpCode(u8),
     InvalidSessionState(String),
+    /// A line longer than the maximum length, in bytes, a codec accepts.
+    LineTooLong(usize),
+    /// `source` with a description of what was being done when it happened, see `Context`.
+    Context {
+        context: String,
+        source: Box<Error>,
+    },
 }
 
 impl core::fmt::Display for Error {
     fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
-        write!(fmt, "{self:?}")
+        match self {
+            Self::Context { context, source } => write!(fmt, "{context}: {source}"),
+            _ => write!(fmt, "{self:?}"),
+        }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// Wrap the error with `context`, e.g. which connection it happened on.
+    pub fn context(self, context: impl Into<String>) -> Self {
+        Self::Context {
+            context: context.into(),
+            source: Box::new(self),
+        }
+    }
+
+    /// The innermost error, without any context wrapped around it.
+    pub fn root_cause(&self) -> &Error {
+        match self {
+            Self::Context { source, .. } => source.root_cause(),
+            _ => self,
+        }
+    }
+}
+
+/// Attach context to the error of a `Result`, like `anyhow::Context` does:
+///
+/// ```ignore
+/// handle_client(socket).await.context("budget chat")?;
+/// ```
+pub trait Context<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+
+    /// Like `context`, but only builds the context when there is an error.
+    fn with_context<C, F>(self, f: F) -> Result<T>
+    where
+        C: Into<String>,
+        F: FnOnce() -> C;
+}
+
+impl<T, E: Into<Error>> Context<T> for core::result::Result<T, E> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|e| e.into().context(context))
+    }
+
+    fn with_context<C, F>(self, f: F) -> Result<T>
+    where
+        C: Into<String>,
+        F: FnOnce() -> C,
+    {
+        self.map_err(|e| e.into().context(f()))
+    }
+}
 
 impl From<std::io::Error> for Error {
     fn from(value: std::io::Error) -> Self {
@@ -45,3 +111,34 @@ impl From<Box<dyn std::error::Error>> for Error {
         Self::Other(value.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_is_displayed_before_the_cause() {
+        let result: Result<()> = Err(Error::InvalidProtocol("bad opcode".into()));
+        let err = result
+            .context("speed daemon")
+            .with_context(|| format!("connection {} from {}", 3, "127.0.0.1:4242"))
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "connection 3 from 127.0.0.1:4242: speed daemon: InvalidProtocol(\"bad opcode\")"
+        );
+        assert!(matches!(err.root_cause(), Error::InvalidProtocol(msg) if msg == "bad opcode"));
+        let source = std::error::Error::source(&err).unwrap();
+        assert!(source.to_string().starts_with("speed daemon: "));
+    }
+
+    #[test]
+    fn io_errors_can_be_given_context() {
+        let result: std::io::Result<()> = Err(std::io::ErrorKind::UnexpectedEof.into());
+        let err = result.context("reading the first line").unwrap_err();
+
+        assert!(err.to_string().starts_with("reading the first line: Io("));
+        assert!(matches!(err.root_cause(), Error::Io(_)));
+    }
+}
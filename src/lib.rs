@@ -1,5 +1,7 @@
 pub mod error;
 pub mod maelstrom;
 pub mod protohackers;
+#[cfg(test)]
+pub mod testutil;
 pub mod tracer;
-pub use error::{Error, Result};
+pub use error::{Context, Error, Result};
@@ -1,10 +1,13 @@
 mod node;
 mod nodes;
 mod protocol;
+#[cfg(test)]
+mod validate;
 
 pub use node::Node;
-pub use nodes::broadcast::BroadcastNode;
+pub use nodes::broadcast::{BroadcastNode, DEFAULT_MAX_LINE_LENGTH, Keepalive};
 pub use nodes::echo::EchoNode;
+pub use nodes::kafka::KafkaNode;
 pub use nodes::unique_ids::UniqueIdsNode;
 
 pub use protocol::*;
@@ -1,6 +1,7 @@
-use super::protocol::Message;
+use super::protocol::{Message, MessageBody, Payload};
 use crate::Result;
-use tokio::io::AsyncWriteExt;
+use std::collections::HashMap;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 pub trait Node {
     /// Handle a message and optionally send a reply.
@@ -13,24 +14,49 @@ pub trait Node {
     fn run(&mut self) -> impl std::future::Future<Output = Result<()>>;
 }
 
+/// The rest of a multi-step handler, run once the reply of the RPC it waits on arrives.
+/// It receives the reply and may produce a message to send, e.g. the answer to the original client request.
+pub type Continuation = Box<dyn FnOnce(&mut BaseNode, Message) -> Option<Message> + Send>;
+
 /// It is concrete struct that encapsulates shared
 /// state and behavior common to all Maelstrom node implementations.
 /// Other specific node reuse it via composition, delegate common feature to it.
-#[derive(Debug)]
 pub struct BaseNode {
     pub node_id: String,
     pub node_ids: Vec<String>,
     msg_counter: usize,
-    pub output: tokio::io::Stdout,
+    output: Box<dyn AsyncWrite + Send + Unpin>,
+    /// Key is the msg_id of a pending RPC, value is what to do when its reply arrives
+    continuations: HashMap<usize, Continuation>,
+}
+
+impl std::fmt::Debug for BaseNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BaseNode")
+            .field("node_id", &self.node_id)
+            .field("node_ids", &self.node_ids)
+            .field("msg_counter", &self.msg_counter)
+            .field(
+                "pending_rpcs",
+                &self.continuations.keys().collect::<Vec<_>>(),
+            )
+            .finish()
+    }
 }
 
 impl BaseNode {
     pub fn new() -> Self {
+        Self::with_output(tokio::io::stdout())
+    }
+
+    /// Write messages to `output` instead of stdout, e.g. an in-memory pipe in tests.
+    pub fn with_output(output: impl AsyncWrite + Send + Unpin + 'static) -> Self {
         Self {
             node_id: String::new(),
             node_ids: Vec::new(),
             msg_counter: 1, // start at 1 for msg_id
-            output: tokio::io::stdout(),
+            output: Box::new(output),
+            continuations: HashMap::new(),
         }
     }
 
@@ -47,12 +73,88 @@ impl BaseNode {
 
     pub async fn send_msg_to_output(&mut self, msg: Message) -> Result<()> {
         let json = serde_json::to_string(&msg)?;
+        #[cfg(test)]
+        if let Err(e) = super::validate::validate_message(&json) {
+            panic!("node emitted an invalid message {json}: {e}");
+        }
 
         self.output
             .write_all(format!("{}\n", json).as_bytes())
             .await?;
+        // tokio's stdout buffers, Maelstrom would wait for a reply still sitting in it
+        self.output.flush().await?;
         Ok(())
     }
+
+    /// Send `payload` to `dst` as a fire-and-forget message (no msg_id, not a reply).
+    pub async fn send_to(&mut self, dst: &str, payload: Payload) -> Result<()> {
+        let msg = Message {
+            src: self.node_id.clone(),
+            dst: dst.to_string(),
+            body: MessageBody {
+                msg_id: None,
+                in_reply_to: None,
+                payload,
+            },
+        };
+        self.send_msg_to_output(msg).await
+    }
+
+    /// Send the same `payload` to each node in `nodes`.
+    pub async fn broadcast_to(&mut self, nodes: &[String], payload: Payload) -> Result<()> {
+        for node in nodes {
+            self.send_to(node, payload.clone()).await?;
+        }
+        Ok(())
+    }
+
+    /// Send `payload` to `dst` as a request and run `continuation` once its reply arrives.
+    /// Returns the msg_id of the request.
+    #[allow(unused)]
+    pub async fn rpc<C>(&mut self, dst: &str, payload: Payload, continuation: C) -> Result<usize>
+    where
+        C: FnOnce(&mut BaseNode, Message) -> Option<Message> + Send + 'static,
+    {
+        let msg_id = self.next_msg_id();
+        let msg = Message {
+            src: self.node_id.clone(),
+            dst: dst.to_string(),
+            body: MessageBody {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                payload,
+            },
+        };
+
+        self.continuations.insert(msg_id, Box::new(continuation));
+        self.send_msg_to_output(msg).await?;
+
+        Ok(msg_id)
+    }
+
+    /// Forget the RPC `msg_id`, its reply will be handled by the node like any other message.
+    /// Returns `false` when it was not pending anymore.
+    pub fn cancel_rpc(&mut self, msg_id: usize) -> bool {
+        self.continuations.remove(&msg_id).is_some()
+    }
+
+    /// If `msg` is the reply of a pending RPC, run its continuation and return `true`.
+    /// Otherwise return `false` so the node handles the message as usual.
+    pub async fn resolve_rpc(&mut self, msg: &Message) -> Result<bool> {
+        let Some(continuation) = msg
+            .body
+            .in_reply_to
+            .and_then(|id| self.continuations.remove(&id))
+        else {
+            return Ok(false);
+        };
+
+        if let Some(out) = continuation(self, msg.clone()) {
+            self.send_msg_to_output(out).await?;
+        }
+
+        Ok(true)
+    }
 }
 
 /// Extract ID generation feature in a shared abstraction.
@@ -73,3 +175,146 @@ impl IdGenerator {
         format!("{}-{}", node_id, self.counter)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncBufReadExt, BufReader, DuplexStream, Lines};
+
+    fn node_with_pipe(node_id: &str) -> (BaseNode, Lines<BufReader<DuplexStream>>) {
+        let (writer, reader) = tokio::io::duplex(4096);
+        let mut node = BaseNode::with_output(writer);
+        node.handle_init(node_id, &vec![node_id.to_string(), "n2".to_string()]);
+        (node, BufReader::new(reader).lines())
+    }
+
+    async fn next_message(lines: &mut Lines<BufReader<DuplexStream>>) -> Message {
+        let line = lines.next_line().await.unwrap().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn continuation_replies_to_client_after_rpc_resolves() -> Result<()> {
+        let (mut node, mut output) = node_with_pipe("n1");
+
+        // a client request which can only be answered after asking n2
+        let request: Message = serde_json::from_str(
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":7,"echo":"hi"}}"#,
+        )?;
+
+        let rpc_id = node
+            .rpc("n2", Payload::Read, move |base, reply| {
+                let echo = match reply.body.payload {
+                    Payload::ReadOk { messages } => format!("n2 knows {}", messages.len()),
+                    _ => return None,
+                };
+                Some(request.into_reply(Some(base.next_msg_id()), Payload::EchoOk { echo }))
+            })
+            .await?;
+
+        let rpc = next_message(&mut output).await;
+        assert_eq!(rpc.dst, "n2");
+        assert_eq!(rpc.body.msg_id, Some(rpc_id));
+        assert_eq!(rpc.body.payload, Payload::Read);
+
+        // an unrelated message is left to the node
+        assert!(!node.resolve_rpc(&rpc).await?);
+
+        let reply = rpc.into_reply(
+            None,
+            Payload::ReadOk {
+                messages: [1, 2].into(),
+            },
+        );
+        assert!(node.resolve_rpc(&reply).await?);
+
+        let answer = next_message(&mut output).await;
+        assert_eq!(answer.dst, "c1");
+        assert_eq!(answer.body.in_reply_to, Some(7));
+        assert_eq!(
+            answer.body.payload,
+            Payload::EchoOk {
+                echo: "n2 knows 2".to_string()
+            }
+        );
+
+        // the continuation runs only once
+        assert!(!node.resolve_rpc(&reply).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broadcast_to_sends_one_message_per_node() -> Result<()> {
+        let (mut node, mut output) = node_with_pipe("n1");
+        let targets = vec!["n2".to_string(), "n3".to_string(), "n4".to_string()];
+        let payload = Payload::Gossip {
+            messages: [1, 2, 3].into(),
+        };
+
+        node.broadcast_to(&targets, payload.clone()).await?;
+
+        for target in targets {
+            let msg = next_message(&mut output).await;
+            assert_eq!(msg.src, "n1");
+            assert_eq!(msg.dst, target);
+            assert_eq!(msg.body.msg_id, None);
+            assert_eq!(msg.body.in_reply_to, None);
+            assert_eq!(msg.body.payload, payload);
+        }
+
+        Ok(())
+    }
+
+    /// Counts the flushes of the writer it wraps.
+    struct FlushCounter {
+        inner: DuplexStream,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncWrite for FlushCounter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn output_is_flushed_after_each_message() -> Result<()> {
+        let (writer, reader) = tokio::io::duplex(4096);
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let mut node = BaseNode::with_output(FlushCounter {
+            inner: writer,
+            flushes: flushes.clone(),
+        });
+        node.handle_init("n1", &vec!["n1".to_string(), "n2".to_string()]);
+        let mut output = BufReader::new(reader).lines();
+
+        for expected in 1..=3 {
+            node.send_to("n2", Payload::Read).await?;
+            assert_eq!(flushes.load(Ordering::SeqCst), expected);
+            next_message(&mut output).await;
+        }
+
+        Ok(())
+    }
+}
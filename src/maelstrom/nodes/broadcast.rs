@@ -1,12 +1,30 @@
 use crate::maelstrom::node::*;
 use crate::maelstrom::*;
 use crate::{Error, Result};
+use bytes::BytesMut;
+use futures::StreamExt;
 use std::collections::{HashMap, HashSet};
 use std::time::Duration;
+use tokio::io::AsyncRead;
 use tokio::sync::mpsc;
-use tracing::error;
+use tokio::time::Instant;
+use tokio_util::codec::{Decoder, FramedRead, LinesCodec, LinesCodecError};
+use tracing::{error, info};
 
 const GOSSIP_INTERVAL_IN_MILLIS: u64 = 300;
+/// Every this many gossip rounds, neighbors get every known message instead of the
+/// new ones only, in case some gossip was lost.
+const FULL_DIGEST_EVERY_ROUNDS: u64 = 10;
+/// Longest stdin line accepted by default, longer ones are skipped.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+/// How often neighbors are pinged, and how long one may stay silent before it is
+/// considered unreachable and left out of gossip.
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
 
 pub struct BroadcastNode {
     base: BaseNode,
@@ -16,28 +34,94 @@ pub struct BroadcastNode {
     neighbors: Vec<String>,
     /// Key is the node_id, value is HashSet which is the messages they already known
     gossip_records: HashMap<String, HashSet<usize>>,
+    /// Key is a neighbor, value is the messages it is not known to have and was not sent
+    /// yet, kept up to date as messages arrive so a round does not scan `messages`.
+    pending_gossip: HashMap<String, HashSet<usize>>,
+    /// Gossip rounds so far, see `FULL_DIGEST_EVERY_ROUNDS`.
+    gossip_rounds: u64,
     myself_tx: Option<mpsc::UnboundedSender<NodeEvent>>,
+    max_line_length: usize,
+    /// Gossip to every other node until a topology is received.
+    full_mesh_fallback: bool,
+    keepalive: Option<Keepalive>,
+    /// When each other node was last heard from, whatever it sent.
+    last_heard: HashMap<String, Instant>,
+    /// Neighbors silent for longer than the keepalive timeout.
+    unreachable: HashSet<String>,
+    /// msg_id of the ping each neighbor has not answered yet.
+    pending_pings: HashMap<String, usize>,
+    /// Nodes never heard from are silent since then.
+    started: Instant,
 }
 
 impl BroadcastNode {
     pub fn new() -> Self {
+        Self::with_base(BaseNode::new())
+    }
+
+    pub(crate) fn with_base(base: BaseNode) -> Self {
         Self {
-            base: BaseNode::new(),
+            base,
             id_gen: IdGenerator::new(),
             topology: HashMap::new(),
             messages: HashSet::new(),
             neighbors: Vec::new(),
             gossip_records: HashMap::new(),
+            pending_gossip: HashMap::new(),
+            gossip_rounds: 0,
             myself_tx: None,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            full_mesh_fallback: false,
+            keepalive: None,
+            last_heard: HashMap::new(),
+            unreachable: HashSet::new(),
+            pending_pings: HashMap::new(),
+            started: Instant::now(),
         }
     }
+
+    /// Skip stdin lines longer than `max` bytes instead of buffering them.
+    pub fn with_max_line_length(mut self, max: usize) -> Self {
+        self.max_line_length = max;
+        self
+    }
+
+    /// Until a `topology` message arrives, treat every other node as a neighbor,
+    /// so broadcast still converges when the harness never sends a topology.
+    /// Off by default, like real Maelstrom which always sends one.
+    pub fn with_full_mesh_fallback(mut self, enabled: bool) -> Self {
+        self.full_mesh_fallback = enabled;
+        self
+    }
+
+    /// Ping neighbors periodically and stop gossiping to those which stop answering,
+    /// until they are heard from again. Off by default.
+    pub fn with_keepalive(mut self, keepalive: Option<Keepalive>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
 }
 
 impl Node for BroadcastNode {
     async fn handle_message(&mut self, msg: Message) -> Result<()> {
+        // replies of RPCs we issued are handled by their continuation
+        self.heard_from(&msg.src);
+        if self.base.resolve_rpc(&msg).await? {
+            return Ok(());
+        }
+
         match &msg.body.payload {
             Payload::Init { node_id, node_ids } => {
                 self.base.handle_init(node_id, node_ids);
+                if self.full_mesh_fallback {
+                    // replaced by the real neighbors once a topology arrives
+                    self.neighbors = node_ids
+                        .iter()
+                        .filter(|each| *each != node_id)
+                        .cloned()
+                        .collect();
+                    self.reset_pending_gossip();
+                }
 
                 let reply = msg.into_reply(Some(self.base.next_msg_id()), Payload::InitOk);
                 self.base.send_msg_to_output(reply).await?;
@@ -60,33 +144,54 @@ impl Node for BroadcastNode {
                         self.base.node_id
                     ))
                 })?;
+                self.reset_pending_gossip();
 
                 self.base.send_msg_to_output(reply).await?;
             }
 
             Payload::Broadcast { message } => {
-                self.messages.insert(*message);
+                if self.messages.insert(*message) {
+                    self.queue_gossip(*message, None);
+                }
 
                 let reply = msg.into_reply(None, Payload::BroadcastOk);
                 self.base.send_msg_to_output(reply).await?;
 
-                let myself_tx_clone = self.myself_tx.clone().unwrap();
-                let _x = myself_tx_clone.send(NodeEvent::Internal(NodeMessage::Gossip));
+                if let Some(myself_tx) = &self.myself_tx {
+                    let _x = myself_tx.send(NodeEvent::Internal(NodeMessage::Gossip));
+                }
             }
             Payload::Read => {
+                let mut messages: Vec<usize> = self.messages.iter().copied().collect();
+                messages.sort_unstable();
+                let reply = msg.into_reply(None, Payload::ReadOk { messages });
+                self.base.send_msg_to_output(reply).await?;
+            }
+            Payload::ReadCoverage => {
                 let reply = msg.into_reply(
                     None,
-                    Payload::ReadOk {
+                    Payload::ReadCoverageOk {
                         messages: self.messages.clone(),
+                        gossip_nodes: self.gossip_records.len(),
                     },
                 );
                 self.base.send_msg_to_output(reply).await?;
             }
             // receive gossip message sent by other node
             Payload::Gossip { messages } => {
-                self.messages.extend(messages);
                 self.udpate_gossiped_message(&msg.src, messages.clone());
+                for message in messages {
+                    if self.messages.insert(*message) {
+                        self.queue_gossip(*message, Some(&msg.src));
+                    }
+                }
+            }
+            Payload::Ping => {
+                let reply = msg.into_reply(None, Payload::PingOk);
+                self.base.send_msg_to_output(reply).await?;
             }
+            // a late answer to a replaced ping, the sender is already marked as heard from
+            Payload::PingOk => {}
             Payload::TopologyOk | Payload::BroadcastOk | Payload::ReadOk { .. } => {
                 error!("ignore: {:?}", msg)
             }
@@ -115,13 +220,26 @@ impl Node for BroadcastNode {
         let (cancel_tx, _) = tokio::sync::broadcast::channel::<()>(1);
 
         // Spawn tasks with their own cancellation receivers
-        let mut stdin_task = tokio::spawn(BroadcastNode::generate_events_from_stdin_with_cancel(
+        let mut stdin_task = tokio::spawn(BroadcastNode::generate_events_from_reader_with_cancel(
+            tokio::io::stdin(),
+            self.max_line_length,
             tx,
             cancel_tx.subscribe(),
         ));
+        if let Some(keepalive) = self.keepalive {
+            // stopped with the other tasks by the cancellation signal
+            tokio::spawn(BroadcastNode::generate_events_from_time_ticker_with_cancel(
+                tx_clone.clone(),
+                keepalive.interval,
+                NodeMessage::Keepalive,
+                cancel_tx.subscribe(),
+            ));
+        }
         let mut ticker_task =
             tokio::spawn(BroadcastNode::generate_events_from_time_ticker_with_cancel(
                 tx_clone,
+                Duration::from_millis(GOSSIP_INTERVAL_IN_MILLIS),
+                NodeMessage::Gossip,
                 cancel_tx.subscribe(),
             ));
 
@@ -158,20 +276,26 @@ impl Node for BroadcastNode {
 }
 
 impl BroadcastNode {
-    async fn generate_events_from_stdin_with_cancel(
+    /// Parse each line of `input` (stdin when running under Maelstrom) into a message.
+    /// A line longer than `max_line_length` is logged and skipped, never buffered whole.
+    async fn generate_events_from_reader_with_cancel(
+        input: impl AsyncRead + Unpin,
+        max_line_length: usize,
         tx: mpsc::UnboundedSender<NodeEvent>,
         mut cancel_rx: tokio::sync::broadcast::Receiver<()>,
     ) -> Result<()> {
-        use tokio::io::{AsyncBufReadExt, BufReader};
-
-        let stdin = tokio::io::stdin();
-        let mut reader = BufReader::new(stdin).lines();
+        let mut reader = FramedRead::new(input, SkipLongLines::new(max_line_length));
 
         loop {
             tokio::select! {
-                line_result = reader.next_line() => {
-                    let line = match line_result? {
-                        Some(l) => l,
+                line_result = reader.next() => {
+                    let line = match line_result {
+                        Some(Ok(Some(l))) => l,
+                        Some(Ok(None)) => {
+                            error!("Skip line longer than {} bytes", max_line_length);
+                            continue;
+                        }
+                        Some(Err(e)) => return Err(e.into()),
                         None => break, // EOF reached
                     };
                     if line.trim().is_empty() {
@@ -199,16 +323,19 @@ impl BroadcastNode {
         Ok(())
     }
 
+    /// Emit `message` every `period`.
     async fn generate_events_from_time_ticker_with_cancel(
         tx: mpsc::UnboundedSender<NodeEvent>,
+        period: Duration,
+        message: NodeMessage,
         mut cancel_rx: tokio::sync::broadcast::Receiver<()>,
     ) -> Result<()> {
-        let mut interval = tokio::time::interval(Duration::from_millis(GOSSIP_INTERVAL_IN_MILLIS));
+        let mut interval = tokio::time::interval(period);
 
         loop {
             tokio::select! {
                 _ = interval.tick() => {
-                    if tx.send(NodeEvent::Internal(NodeMessage::Gossip)).is_err() {
+                    if tx.send(NodeEvent::Internal(message)).is_err() {
                         break;
                     }
                 }
@@ -229,38 +356,122 @@ impl BroadcastNode {
     }
 
     fn udpate_gossiped_message(&mut self, node: &str, messages: HashSet<usize>) {
+        if let Some(pending) = self.pending_gossip.get_mut(node) {
+            pending.retain(|message| !messages.contains(message));
+        }
         self.gossip_records
             .entry(node.to_string())
             .or_insert_with(|| HashSet::new())
             .extend(messages);
     }
 
+    /// A new `message` has to be gossiped to every neighbor, except to `from` which sent it.
+    fn queue_gossip(&mut self, message: usize, from: Option<&str>) {
+        for neighbor in &self.neighbors {
+            if Some(neighbor.as_str()) != from && !self.is_message_gossiped(neighbor, message) {
+                self.pending_gossip
+                    .entry(neighbor.clone())
+                    .or_default()
+                    .insert(message);
+            }
+        }
+    }
+
+    /// The neighbors changed, work out again what each of them is missing.
+    fn reset_pending_gossip(&mut self) {
+        self.pending_gossip.clear();
+        for message in self.messages.clone() {
+            self.queue_gossip(message, None);
+        }
+    }
+
     /// Different from handle_message, this one handle the NodeMessage  
     /// which represents the messages communicated internally between nodes themselves.
     async fn handle_node_message(&mut self, msg: NodeMessage) -> Result<()> {
         match msg {
             NodeMessage::Gossip => {
-                use rand::prelude::*;
-
-                let selected_neighbors = self.neighbors.clone();
-                for each_node in selected_neighbors.clone() {
-                    let (already_known, mut not_known): (HashSet<usize>, HashSet<usize>) =
-                        self.messages.iter().partition(|each_message| {
-                            !self.is_message_gossiped(&each_node, **each_message)
-                        });
-
-                    // Include some of "already_known" ones into not_known:
-                    // This is used for solving the gossip may not reached to other nodes because of network partial failure.
-                    not_known.extend(already_known.iter().filter(|_| {
-                        rand::rng().random_ratio(
-                            10.min(already_known.len() as u32),
-                            already_known.len() as u32,
-                        )
-                    }));
-
-                    let _ = self.send_gossip_message(&each_node, &not_known).await?;
+                self.gossip_rounds += 1;
+                let full_digest = self.gossip_rounds.is_multiple_of(FULL_DIGEST_EVERY_ROUNDS);
+                let selected_neighbors: Vec<String> = self
+                    .neighbors
+                    .iter()
+                    .filter(|each| !self.unreachable.contains(*each))
+                    .cloned()
+                    .collect();
+                if full_digest {
+                    self.send_full_digest(&selected_neighbors).await?;
+                } else {
+                    for each_node in selected_neighbors {
+                        let messages = self.pending_gossip.remove(&each_node).unwrap_or_default();
+                        // in steady state there is nothing new, and nothing is sent
+                        if !messages.is_empty() {
+                            self.send_gossip_message(&each_node, &messages).await?;
+                        }
+                    }
                 }
             }
+            NodeMessage::Keepalive => self.ping_neighbors().await?,
+        }
+        Ok(())
+    }
+
+    /// Any message from another node shows it is alive.
+    fn heard_from(&mut self, src: &str) {
+        if !self.base.node_ids.iter().any(|each| each == src) {
+            return; // a client
+        }
+        self.last_heard.insert(src.to_string(), Instant::now());
+        if self.unreachable.remove(src) {
+            info!("{} is reachable again, resume gossiping to it", src);
+        }
+    }
+
+    /// Mark the neighbors silent for too long as unreachable, then ping every neighbor.
+    async fn ping_neighbors(&mut self) -> Result<()> {
+        let Some(keepalive) = self.keepalive else {
+            return Ok(());
+        };
+
+        for neighbor in self.neighbors.clone() {
+            let silent_for = self
+                .last_heard
+                .get(&neighbor)
+                .copied()
+                .unwrap_or(self.started)
+                .elapsed();
+            if silent_for > keepalive.timeout && self.unreachable.insert(neighbor.clone()) {
+                info!(
+                    "{} silent for {:?}, stop gossiping to it",
+                    neighbor, silent_for
+                );
+            }
+
+            // replace the unanswered ping, so a dead neighbor does not pile them up
+            if let Some(previous) = self.pending_pings.remove(&neighbor) {
+                self.base.cancel_rpc(previous);
+            }
+            let ping = self.base.rpc(&neighbor, Payload::Ping, |_, _| None).await?;
+            self.pending_pings.insert(neighbor, ping);
+        }
+        Ok(())
+    }
+
+    /// Send every message to each of `neighbors`: gossip may not reach other nodes because
+    /// of network partial failure. It covers whatever was pending for them.
+    async fn send_full_digest(&mut self, neighbors: &[String]) -> Result<()> {
+        for neighbor in neighbors {
+            self.pending_gossip.remove(neighbor);
+        }
+        if self.messages.is_empty() {
+            return Ok(());
+        }
+
+        let payload = Payload::Gossip {
+            messages: self.messages.clone(),
+        };
+        self.base.broadcast_to(neighbors, payload).await?;
+        for neighbor in neighbors {
+            self.udpate_gossiped_message(neighbor, self.messages.clone());
         }
         Ok(())
     }
@@ -270,21 +481,428 @@ impl BroadcastNode {
         target_node: &str,
         messages: &HashSet<usize>,
     ) -> Result<()> {
-        let msg = Message {
-            src: self.base.node_id.clone(),
-            dst: target_node.to_string(),
-            body: MessageBody {
-                msg_id: None,
-                in_reply_to: None,
-                payload: Payload::Gossip {
-                    messages: messages.clone(),
-                },
-            },
+        let payload = Payload::Gossip {
+            messages: messages.clone(),
         };
-        let _ = self.base.send_msg_to_output(msg).await?;
+        let _ = self.base.send_to(target_node, payload).await?;
 
         self.udpate_gossiped_message(&target_node, messages.clone());
 
         Ok(())
     }
 }
+
+/// `LinesCodec` with a max length, which yields `None` for a too long line and goes on
+/// with the next one. A plain `LinesCodec` error would end the `FramedRead` stream.
+struct SkipLongLines(LinesCodec);
+
+impl SkipLongLines {
+    fn new(max_length: usize) -> Self {
+        Self(LinesCodec::new_with_max_length(max_length))
+    }
+
+    fn skip_long(
+        result: std::result::Result<Option<String>, LinesCodecError>,
+    ) -> std::io::Result<Option<Option<String>>> {
+        match result {
+            Ok(line) => Ok(line.map(Some)),
+            Err(LinesCodecError::MaxLineLengthExceeded) => Ok(Some(None)),
+            Err(LinesCodecError::Io(e)) => Err(e),
+        }
+    }
+}
+
+impl Decoder for SkipLongLines {
+    type Item = Option<String>;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> std::io::Result<Option<Self::Item>> {
+        Self::skip_long(self.0.decode(src))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> std::io::Result<Option<Self::Item>> {
+        Self::skip_long(self.0.decode_eof(src))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use tokio::io::{AsyncBufReadExt, BufReader, DuplexStream, Lines};
+
+    /// A few `BroadcastNode`s wired together in memory, gossip is driven by hand.
+    struct Cluster {
+        nodes: HashMap<String, BroadcastNode>,
+        outputs: HashMap<String, Lines<BufReader<DuplexStream>>>,
+        /// Nodes which neither receive nor send anything, as if they were down.
+        silent: HashSet<String>,
+        /// Every message sent between nodes so far, in order.
+        delivered: Vec<Message>,
+    }
+
+    impl Cluster {
+        async fn new(topology: HashMap<String, Vec<String>>) -> Result<Cluster> {
+            let node_ids: Vec<String> = topology.keys().cloned().collect();
+            let mut cluster = Cluster::init(&node_ids, false).await?;
+            for node_id in &node_ids {
+                cluster
+                    .client_request(
+                        node_id,
+                        Payload::Topology {
+                            topology: topology.clone(),
+                        },
+                    )
+                    .await?;
+            }
+            Ok(cluster)
+        }
+
+        /// Only initialize the nodes, no topology is sent.
+        async fn init(node_ids: &[String], full_mesh_fallback: bool) -> Result<Cluster> {
+            let mut cluster = Cluster {
+                nodes: HashMap::new(),
+                outputs: HashMap::new(),
+                silent: HashSet::new(),
+                delivered: Vec::new(),
+            };
+            for node_id in node_ids {
+                let (writer, reader) = tokio::io::duplex(64 * 1024);
+                let node = BroadcastNode::with_base(BaseNode::with_output(writer))
+                    .with_full_mesh_fallback(full_mesh_fallback);
+                cluster.nodes.insert(node_id.clone(), node);
+                cluster
+                    .outputs
+                    .insert(node_id.clone(), BufReader::new(reader).lines());
+
+                cluster
+                    .client_request(
+                        node_id,
+                        Payload::Init {
+                            node_id: node_id.clone(),
+                            node_ids: node_ids.to_vec(),
+                        },
+                    )
+                    .await?;
+            }
+            Ok(cluster)
+        }
+
+        /// Send a request from client `c1` to `node_id` and return the reply.
+        async fn client_request(&mut self, node_id: &str, payload: Payload) -> Result<Payload> {
+            let msg = Message {
+                src: "c1".to_string(),
+                dst: node_id.to_string(),
+                body: MessageBody {
+                    msg_id: Some(1),
+                    in_reply_to: None,
+                    payload,
+                },
+            };
+            self.nodes
+                .get_mut(node_id)
+                .unwrap()
+                .handle_message(msg)
+                .await?;
+            let line = self.outputs.get_mut(node_id).unwrap().next_line().await?;
+            let reply: Message = serde_json::from_str(&line.unwrap())?;
+            Ok(reply.body.payload)
+        }
+
+        /// Every node gossips once, then every message sent between nodes is delivered.
+        async fn gossip_round(&mut self) -> Result<()> {
+            self.tick(NodeMessage::Gossip).await
+        }
+
+        /// Every node pings its neighbors, then pings and answers are delivered.
+        async fn keepalive_round(&mut self) -> Result<()> {
+            self.tick(NodeMessage::Keepalive).await
+        }
+
+        async fn tick(&mut self, event: NodeMessage) -> Result<()> {
+            for (node_id, node) in &mut self.nodes {
+                if !self.silent.contains(node_id) {
+                    node.handle_node_message(event).await?;
+                }
+            }
+
+            // until nothing is left in flight, answers included
+            loop {
+                let mut in_flight = Vec::new();
+                for output in self.outputs.values_mut() {
+                    while let Some(line) = output.next_line().now_or_never() {
+                        in_flight.push(serde_json::from_str::<Message>(&line?.unwrap())?);
+                    }
+                }
+                if in_flight.is_empty() {
+                    return Ok(());
+                }
+                for msg in in_flight {
+                    if !self.silent.contains(&msg.dst) {
+                        self.delivered.push(msg.clone());
+                        let node = self.nodes.get_mut(&msg.dst).unwrap();
+                        node.handle_message(msg).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn over_length_stdin_line_is_skipped() -> Result<()> {
+        let valid = r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":1}}"#;
+        let input = format!("{}\n{}\n", "x".repeat(200), valid);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (_cancel_tx, cancel_rx) = tokio::sync::broadcast::channel(1);
+
+        BroadcastNode::generate_events_from_reader_with_cancel(
+            input.as_bytes(),
+            100,
+            tx,
+            cancel_rx,
+        )
+        .await?;
+
+        match rx.recv().await {
+            Some(NodeEvent::External(msg)) => assert_eq!(msg.body.payload, Payload::Read),
+            _ => panic!("the valid message should be processed"),
+        }
+        assert!(rx.recv().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gossip_records_eventually_cover_all_neighbors() -> Result<()> {
+        let topology: HashMap<String, Vec<String>> = [
+            ("n1", vec!["n2"]),
+            ("n2", vec!["n1", "n3"]),
+            ("n3", vec!["n2", "n4"]),
+            ("n4", vec!["n3"]),
+        ]
+        .into_iter()
+        .map(|(node, neighbors)| {
+            (
+                node.to_string(),
+                neighbors.into_iter().map(String::from).collect(),
+            )
+        })
+        .collect();
+        let mut cluster = Cluster::new(topology.clone()).await?;
+
+        let reply = cluster
+            .client_request("n1", Payload::Broadcast { message: 7 })
+            .await?;
+        assert_eq!(reply, Payload::BroadcastOk);
+
+        // the message needs one round per hop to reach the far end of the line
+        for _ in 0..topology.len() {
+            cluster.gossip_round().await?;
+        }
+
+        for (node_id, neighbors) in &topology {
+            let reply = cluster
+                .client_request(node_id, Payload::ReadCoverage)
+                .await?;
+            assert_eq!(
+                reply,
+                Payload::ReadCoverageOk {
+                    messages: [7].into(),
+                    gossip_nodes: neighbors.len(),
+                },
+                "coverage of {}",
+                node_id
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn full_mesh_fallback_converges_without_topology() -> Result<()> {
+        let node_ids: Vec<String> = ["n1", "n2", "n3"].map(String::from).to_vec();
+
+        // by default a node without topology has nobody to gossip to
+        let mut cluster = Cluster::init(&node_ids, false).await?;
+        cluster
+            .client_request("n1", Payload::Broadcast { message: 7 })
+            .await?;
+        cluster.gossip_round().await?;
+        let reply = cluster.client_request("n2", Payload::Read).await?;
+        assert_eq!(
+            reply,
+            Payload::ReadOk {
+                messages: [].into()
+            }
+        );
+
+        let mut cluster = Cluster::init(&node_ids, true).await?;
+        cluster
+            .client_request("n1", Payload::Broadcast { message: 7 })
+            .await?;
+        cluster.gossip_round().await?;
+        for node_id in &node_ids {
+            let reply = cluster.client_request(node_id, Payload::Read).await?;
+            assert_eq!(
+                reply,
+                Payload::ReadOk {
+                    messages: [7].into()
+                },
+                "messages of {}",
+                node_id
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_replies_with_sorted_messages() -> Result<()> {
+        let mut cluster = Cluster::init(&["n1".to_string()], false).await?;
+        for message in [30, 1, 20, 1] {
+            let reply = cluster
+                .client_request("n1", Payload::Broadcast { message })
+                .await?;
+            assert_eq!(reply, Payload::BroadcastOk);
+        }
+
+        let reply = cluster.client_request("n1", Payload::Read).await?;
+        assert_eq!(
+            reply,
+            Payload::ReadOk {
+                messages: vec![1, 20, 30]
+            }
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_neighbor_is_not_gossiped_to_until_it_answers_again() -> Result<()> {
+        let topology: HashMap<String, Vec<String>> = [
+            ("n1", vec!["n2", "n3"]),
+            ("n2", vec!["n1"]),
+            ("n3", vec!["n1"]),
+        ]
+        .into_iter()
+        .map(|(node, neighbors)| {
+            (
+                node.to_string(),
+                neighbors.into_iter().map(String::from).collect(),
+            )
+        })
+        .collect();
+        let mut cluster = Cluster::new(topology).await?;
+        let keepalive = Keepalive {
+            interval: Duration::from_millis(100),
+            timeout: Duration::from_millis(250),
+        };
+        for node in cluster.nodes.values_mut() {
+            node.keepalive = Some(keepalive);
+        }
+
+        cluster.keepalive_round().await?;
+        cluster.silent.insert("n3".to_string());
+        for _ in 0..2 {
+            tokio::time::advance(keepalive.interval).await;
+            cluster.keepalive_round().await?;
+        }
+        // silent for 200ms, still within the timeout
+        assert!(cluster.nodes["n1"].unreachable.is_empty());
+
+        tokio::time::advance(keepalive.interval).await;
+        cluster.keepalive_round().await?;
+        cluster
+            .client_request("n1", Payload::Broadcast { message: 7 })
+            .await?;
+        cluster.gossip_round().await?;
+        let n1 = &cluster.nodes["n1"];
+        assert!(n1.is_message_gossiped("n2", 7));
+        assert!(!n1.is_message_gossiped("n3", 7));
+
+        // n3 is back, its ping is enough for n1 to gossip to it again
+        cluster.silent.clear();
+        tokio::time::advance(keepalive.interval).await;
+        cluster.keepalive_round().await?;
+        cluster.gossip_round().await?;
+        let reply = cluster.client_request("n3", Payload::Read).await?;
+        assert_eq!(reply, Payload::ReadOk { messages: vec![7] });
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn steady_state_gossip_sends_only_new_messages() -> Result<()> {
+        let topology: HashMap<String, Vec<String>> = [("n1", vec!["n2"]), ("n2", vec!["n1"])]
+            .into_iter()
+            .map(|(node, neighbors)| {
+                (
+                    node.to_string(),
+                    neighbors.into_iter().map(String::from).collect(),
+                )
+            })
+            .collect();
+        let mut cluster = Cluster::new(topology).await?;
+        let gossip_round = async |cluster: &mut Cluster| -> Result<Vec<(String, Vec<usize>)>> {
+            cluster.delivered.clear();
+            cluster.gossip_round().await?;
+            Ok(cluster
+                .delivered
+                .iter()
+                .map(|msg| match &msg.body.payload {
+                    Payload::Gossip { messages } => {
+                        let mut messages: Vec<usize> = messages.iter().copied().collect();
+                        messages.sort_unstable();
+                        (format!("{}->{}", msg.src, msg.dst), messages)
+                    }
+                    other => panic!("unexpected {:?}", other),
+                })
+                .collect())
+        };
+
+        for message in [1, 2] {
+            cluster
+                .client_request("n1", Payload::Broadcast { message })
+                .await?;
+        }
+        let sent = gossip_round(&mut cluster).await?;
+        assert_eq!(sent, vec![("n1->n2".to_string(), vec![1, 2])]);
+
+        // n2 got them from n1, it does not send them back
+        assert_eq!(gossip_round(&mut cluster).await?, vec![]);
+
+        cluster
+            .client_request("n2", Payload::Broadcast { message: 3 })
+            .await?;
+        let sent = gossip_round(&mut cluster).await?;
+        assert_eq!(sent, vec![("n2->n1".to_string(), vec![3])]);
+
+        // up to the full digest every node sends everything it knows, once
+        for _ in 4..FULL_DIGEST_EVERY_ROUNDS {
+            assert_eq!(gossip_round(&mut cluster).await?, vec![]);
+        }
+        let mut sent = gossip_round(&mut cluster).await?;
+        sent.sort();
+        assert_eq!(
+            sent,
+            vec![
+                ("n1->n2".to_string(), vec![1, 2, 3]),
+                ("n2->n1".to_string(), vec![1, 2, 3]),
+            ]
+        );
+        assert_eq!(gossip_round(&mut cluster).await?, vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn gossip_is_serialized_as_a_sorted_list() {
+        let payload = Payload::Gossip {
+            messages: [30, 1, 20].into(),
+        };
+        assert_eq!(
+            serde_json::to_string(&payload).unwrap(),
+            r#"{"type":"gossip","messages":[1,20,30]}"#
+        );
+    }
+}
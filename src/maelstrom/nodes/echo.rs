@@ -10,9 +10,11 @@ pub struct EchoNode {
 
 impl EchoNode {
     pub fn new() -> Self {
-        Self {
-            base: BaseNode::new(),
-        }
+        Self::with_base(BaseNode::new())
+    }
+
+    pub(crate) fn with_base(base: BaseNode) -> Self {
+        Self { base }
     }
 }
 
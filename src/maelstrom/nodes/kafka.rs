@@ -0,0 +1,276 @@
+use crate::maelstrom::node::*;
+use crate::maelstrom::*;
+use crate::{Error, Result};
+use std::collections::HashMap;
+
+/// Single node Kafka-style log: every key has its own append-only log,
+/// a message's offset is its index in that log.
+pub struct KafkaNode {
+    base: BaseNode,
+    logs: HashMap<String, Vec<serde_json::Value>>,
+    /// Offsets committed by clients, per key.
+    committed: HashMap<String, usize>,
+}
+
+impl KafkaNode {
+    pub fn new() -> Self {
+        Self::with_base(BaseNode::new())
+    }
+
+    pub(crate) fn with_base(base: BaseNode) -> Self {
+        Self {
+            base,
+            logs: HashMap::new(),
+            committed: HashMap::new(),
+        }
+    }
+
+    /// Append `msg` to the log of `key` and return its offset.
+    fn append(&mut self, key: &str, msg: serde_json::Value) -> usize {
+        let log = self.logs.entry(key.to_string()).or_default();
+        log.push(msg);
+        log.len() - 1
+    }
+
+    /// For each key, the messages from the requested offset to the end of its log.
+    /// Unknown keys and offsets past the end give an empty list.
+    fn poll(
+        &self,
+        offsets: &HashMap<String, usize>,
+    ) -> HashMap<String, Vec<(usize, serde_json::Value)>> {
+        offsets
+            .iter()
+            .map(|(key, &from)| {
+                let msgs = self
+                    .logs
+                    .get(key)
+                    .map(|log| {
+                        log.iter()
+                            .enumerate()
+                            .skip(from)
+                            .map(|(offset, msg)| (offset, msg.clone()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                (key.clone(), msgs)
+            })
+            .collect()
+    }
+
+    /// A committed offset never moves back, a late commit of an older offset is ignored.
+    fn commit(&mut self, offsets: &HashMap<String, usize>) {
+        for (key, &offset) in offsets {
+            let committed = self.committed.entry(key.clone()).or_insert(offset);
+            *committed = (*committed).max(offset);
+        }
+    }
+
+    fn committed_offsets(&self, keys: &[String]) -> HashMap<String, usize> {
+        keys.iter()
+            .filter_map(|key| Some((key.clone(), *self.committed.get(key)?)))
+            .collect()
+    }
+}
+
+impl Default for KafkaNode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Node for KafkaNode {
+    async fn handle_message(&mut self, msg: Message) -> Result<()> {
+        let payload = match &msg.body.payload {
+            Payload::Init { node_id, node_ids } => {
+                self.base.handle_init(node_id, node_ids);
+                Payload::InitOk
+            }
+            Payload::Send { key, msg } => Payload::SendOk {
+                offset: self.append(key, msg.clone()),
+            },
+            Payload::Poll { offsets } => Payload::PollOk {
+                msgs: self.poll(offsets),
+            },
+            Payload::CommitOffsets { offsets } => {
+                self.commit(offsets);
+                Payload::CommitOffsetsOk
+            }
+            Payload::ListCommittedOffsets { keys } => Payload::ListCommittedOffsetsOk {
+                offsets: self.committed_offsets(keys),
+            },
+            other => return Err(Error::Other(format!("{:?} should not happend", other))),
+        };
+
+        let reply = msg.into_reply(Some(self.base.next_msg_id()), payload);
+        self.base.send_msg_to_output(reply).await
+    }
+
+    async fn run(&mut self) -> Result<()> {
+        let stdin = std::io::stdin();
+
+        let deserializer = serde_json::Deserializer::from_reader(stdin.lock());
+        for result in deserializer.into_iter::<Message>() {
+            self.handle_message(result?).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::io::{AsyncBufReadExt, BufReader, DuplexStream, Lines};
+
+    struct KafkaTest {
+        node: KafkaNode,
+        output: Lines<BufReader<DuplexStream>>,
+        next_msg_id: usize,
+    }
+
+    impl KafkaTest {
+        async fn new() -> Result<Self> {
+            let (writer, reader) = tokio::io::duplex(64 * 1024);
+            let mut test = KafkaTest {
+                node: KafkaNode::with_base(BaseNode::with_output(writer)),
+                output: BufReader::new(reader).lines(),
+                next_msg_id: 1,
+            };
+            test.request(Payload::Init {
+                node_id: "n1".to_string(),
+                node_ids: vec!["n1".to_string()],
+            })
+            .await?;
+            Ok(test)
+        }
+
+        /// Send `payload` from client `c1` and return the payload of the reply.
+        async fn request(&mut self, payload: Payload) -> Result<Payload> {
+            let msg_id = self.next_msg_id;
+            self.next_msg_id += 1;
+            let request = Message {
+                src: "c1".to_string(),
+                dst: "n1".to_string(),
+                body: MessageBody {
+                    msg_id: Some(msg_id),
+                    in_reply_to: None,
+                    payload,
+                },
+            };
+            self.node.handle_message(request).await?;
+
+            let line = self.output.next_line().await?.unwrap();
+            let reply: Message = serde_json::from_str(&line)?;
+            assert_eq!(reply.body.in_reply_to, Some(msg_id));
+            Ok(reply.body.payload)
+        }
+
+        async fn send(&mut self, key: &str, msg: serde_json::Value) -> Result<usize> {
+            let key = key.to_string();
+            match self.request(Payload::Send { key, msg }).await? {
+                Payload::SendOk { offset } => Ok(offset),
+                other => panic!("expected send_ok, got {other:?}"),
+            }
+        }
+    }
+
+    fn offsets(entries: &[(&str, usize)]) -> HashMap<String, usize> {
+        entries
+            .iter()
+            .map(|(key, offset)| (key.to_string(), *offset))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn send_returns_increasing_offsets_per_key() -> Result<()> {
+        let mut test = KafkaTest::new().await?;
+        assert_eq!(test.send("k1", json!(10)).await?, 0);
+        assert_eq!(test.send("k1", json!(11)).await?, 1);
+        // each key has its own log
+        assert_eq!(test.send("k2", json!(20)).await?, 0);
+        assert_eq!(test.send("k1", json!(12)).await?, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn poll_returns_the_log_from_the_requested_offset() -> Result<()> {
+        let mut test = KafkaTest::new().await?;
+        for value in [10, 11, 12] {
+            test.send("k1", json!(value)).await?;
+        }
+        test.send("k2", json!("twenty")).await?;
+
+        let reply = test
+            .request(Payload::Poll {
+                offsets: offsets(&[("k1", 1), ("k2", 0), ("k3", 0)]),
+            })
+            .await?;
+        let Payload::PollOk { msgs } = reply else {
+            panic!("expected poll_ok, got {reply:?}");
+        };
+        assert_eq!(msgs["k1"], vec![(1, json!(11)), (2, json!(12))]);
+        assert_eq!(msgs["k2"], vec![(0, json!("twenty"))]);
+        assert!(msgs["k3"].is_empty());
+
+        // past the end of the log there is nothing yet
+        let reply = test
+            .request(Payload::Poll {
+                offsets: offsets(&[("k1", 3)]),
+            })
+            .await?;
+        assert_eq!(
+            reply,
+            Payload::PollOk {
+                msgs: HashMap::from([("k1".to_string(), Vec::new())])
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn committed_offsets_are_listed_back() -> Result<()> {
+        let mut test = KafkaTest::new().await?;
+        for _ in 0..3 {
+            test.send("k1", json!(1)).await?;
+            test.send("k2", json!(2)).await?;
+        }
+
+        let reply = test
+            .request(Payload::CommitOffsets {
+                offsets: offsets(&[("k1", 2), ("k2", 1)]),
+            })
+            .await?;
+        assert_eq!(reply, Payload::CommitOffsetsOk);
+        // an older commit arriving late does not move the offset back
+        test.request(Payload::CommitOffsets {
+            offsets: offsets(&[("k1", 1)]),
+        })
+        .await?;
+
+        let reply = test
+            .request(Payload::ListCommittedOffsets {
+                keys: vec!["k1".to_string(), "k2".to_string(), "k3".to_string()],
+            })
+            .await?;
+        assert_eq!(
+            reply,
+            Payload::ListCommittedOffsetsOk {
+                offsets: offsets(&[("k1", 2), ("k2", 1)]),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn poll_ok_is_serialized_as_offset_message_pairs() -> Result<()> {
+        let payload = Payload::PollOk {
+            msgs: HashMap::from([("k1".to_string(), vec![(0, json!(9)), (1, json!(5))])]),
+        };
+        assert_eq!(
+            serde_json::to_value(&payload)?,
+            json!({"type": "poll_ok", "msgs": {"k1": [[0, 9], [1, 5]]}})
+        );
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
 pub mod broadcast;
 pub mod echo;
+pub mod kafka;
 pub mod unique_ids;
@@ -10,10 +10,14 @@ pub struct UniqueIdsNode {
 
 impl UniqueIdsNode {
     pub fn new() -> Self {
+        Self::with_base(BaseNode::new())
+    }
+
+    pub(crate) fn with_base(base: BaseNode) -> Self {
         // In real impl, you'd use logical clock or coordination
         // For now, just a simple counter
         Self {
-            base: BaseNode::new(),
+            base,
             id_gen: IdGenerator::new(),
         }
     }
@@ -67,15 +67,55 @@ pub enum Payload {
     },
     BroadcastOk,
     Read,
+    /// The messages are sorted, so a reply does not depend on set iteration order.
     ReadOk {
-        messages: HashSet<usize>,
+        messages: Vec<usize>,
     },
     Topology {
         topology: HashMap<String, Vec<String>>,
     },
     TopologyOk,
     Gossip {
+        #[serde(serialize_with = "serialize_sorted")]
+        messages: HashSet<usize>,
+    },
+    /// Internal self-check, like `Read` but it also reports how many distinct nodes
+    /// a `BroadcastNode` has gossip records for, so tests can assert topology coverage.
+    ReadCoverage,
+    ReadCoverageOk {
         messages: HashSet<usize>,
+        gossip_nodes: usize,
+    },
+    /// Keepalive between nodes, a node which stops answering is not gossiped to.
+    Ping,
+    PingOk,
+    /// Append `msg` to the log of `key`.
+    Send {
+        key: String,
+        msg: serde_json::Value,
+    },
+    /// The offset `msg` was stored at.
+    SendOk {
+        offset: usize,
+    },
+    /// For each key, ask the messages starting at the given offset.
+    Poll {
+        offsets: HashMap<String, usize>,
+    },
+    /// For each key, `[offset, msg]` pairs in offset order.
+    PollOk {
+        msgs: HashMap<String, Vec<(usize, serde_json::Value)>>,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, usize>,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    /// Keys without a committed offset are left out.
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, usize>,
     },
 }
 
@@ -84,6 +124,16 @@ pub enum NodeEvent {
     Internal(NodeMessage),
 }
 
+#[derive(Debug, Clone, Copy)]
 pub enum NodeMessage {
     Gossip,
+    Keepalive,
+}
+
+/// Write a set as a sorted list, so the same set always gives the same JSON.
+fn serialize_sorted<S: serde::Serializer>(
+    set: &HashSet<usize>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(set.iter().collect::<std::collections::BTreeSet<_>>())
 }
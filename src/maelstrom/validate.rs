@@ -0,0 +1,214 @@
+//! Test-only check that what a node writes out is a well formed Maelstrom message.
+//! `BaseNode::send_msg_to_output` runs it on every message in tests.
+use serde_json::{Map, Value};
+
+/// Check the wire shape of one serialized message: `src`, `dest` and `body` are present,
+/// `body.type` tags the payload, the payload carries the fields of its type and
+/// replies (`*_ok`) point at the request they answer with `in_reply_to`.
+pub fn validate_message(json: &str) -> Result<(), String> {
+    let msg: Value = serde_json::from_str(json).map_err(|e| format!("not JSON: {e}"))?;
+    let msg = msg.as_object().ok_or("message is not an object")?;
+
+    require_str(msg, "src")?;
+    require_str(msg, "dest")?;
+    let body = msg
+        .get("body")
+        .and_then(Value::as_object)
+        .ok_or("missing body object")?;
+
+    let msg_type = require_str(body, "type")?;
+    optional_id(body, "msg_id")?;
+    let in_reply_to = optional_id(body, "in_reply_to")?;
+    if msg_type.ends_with("_ok") && in_reply_to.is_none() {
+        return Err(format!("reply {msg_type} without in_reply_to"));
+    }
+
+    let fields: &[&str] = match msg_type {
+        "echo" | "echo_ok" => &["echo"],
+        "init" => &["node_id", "node_ids"],
+        "generate_ok" => &["id"],
+        "broadcast" => &["message"],
+        "read_ok" | "gossip" => &["messages"],
+        "read_coverage_ok" => &["messages", "gossip_nodes"],
+        "topology" => &["topology"],
+        "send" => &["key", "msg"],
+        "send_ok" => &["offset"],
+        "poll" | "commit_offsets" | "list_committed_offsets_ok" => &["offsets"],
+        "poll_ok" => &["msgs"],
+        "list_committed_offsets" => &["keys"],
+        "init_ok" | "generate" | "broadcast_ok" | "read" | "topology_ok" | "read_coverage"
+        | "ping" | "ping_ok" | "commit_offsets_ok" => &[],
+        other => return Err(format!("unknown message type {other}")),
+    };
+    for field in fields {
+        if !body.contains_key(*field) {
+            return Err(format!("{msg_type} is missing {field}"));
+        }
+    }
+
+    Ok(())
+}
+
+fn require_str<'a>(object: &'a Map<String, Value>, key: &str) -> Result<&'a str, String> {
+    object
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("missing string field {key}"))
+}
+
+/// An id may be absent or null, but when set it is a non negative integer.
+fn optional_id(object: &Map<String, Value>, key: &str) -> Result<Option<u64>, String> {
+    match object.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| format!("{key} is not an id: {value}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Result;
+    use crate::maelstrom::node::{BaseNode, Node};
+    use crate::maelstrom::nodes::broadcast::BroadcastNode;
+    use crate::maelstrom::nodes::echo::EchoNode;
+    use crate::maelstrom::nodes::kafka::KafkaNode;
+    use crate::maelstrom::nodes::unique_ids::UniqueIdsNode;
+    use crate::maelstrom::{Message, MessageBody, Payload};
+    use futures::FutureExt;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncBufReadExt, BufReader, DuplexStream, Lines};
+
+    fn init() -> Payload {
+        Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+        }
+    }
+
+    /// Send each payload from client `c1` to the node, collect everything it wrote
+    /// and check it against the validator.
+    async fn run_scenario<N: Node>(
+        node: &mut N,
+        output: &mut Lines<BufReader<DuplexStream>>,
+        requests: Vec<Payload>,
+    ) -> Result<Vec<String>> {
+        let mut emitted = Vec::new();
+        for (msg_id, payload) in requests.into_iter().enumerate() {
+            let request = Message {
+                src: "c1".to_string(),
+                dst: "n1".to_string(),
+                body: MessageBody {
+                    msg_id: Some(msg_id),
+                    in_reply_to: None,
+                    payload,
+                },
+            };
+            node.handle_message(request).await?;
+            while let Some(line) = output.next_line().now_or_never() {
+                let line = line?.unwrap();
+                if let Err(e) = validate_message(&line) {
+                    panic!("invalid message {line}: {e}");
+                }
+                emitted.push(line);
+            }
+        }
+        Ok(emitted)
+    }
+
+    fn pipe() -> (BaseNode, Lines<BufReader<DuplexStream>>) {
+        let (writer, reader) = tokio::io::duplex(64 * 1024);
+        (
+            BaseNode::with_output(writer),
+            BufReader::new(reader).lines(),
+        )
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        let valid = r#"{"src":"n1","dest":"c1","body":{"type":"echo_ok","msg_id":2,"in_reply_to":1,"echo":"hi"}}"#;
+        assert_eq!(validate_message(valid), Ok(()));
+
+        let cases = [
+            r#"{"dest":"c1","body":{"type":"init_ok","in_reply_to":1}}"#,
+            r#"{"src":"n1","dest":"c1","body":{"in_reply_to":1}}"#,
+            r#"{"src":"n1","dest":"c1","body":{"type":"init_ok","in_reply_to":null}}"#,
+            r#"{"src":"n1","dest":"c1","body":{"type":"echo_ok","in_reply_to":1}}"#,
+            r#"{"src":"n1","dest":"c1","body":{"type":"init_ok","in_reply_to":"1"}}"#,
+        ];
+        for case in cases {
+            assert!(validate_message(case).is_err(), "{case} should be rejected");
+        }
+    }
+
+    #[tokio::test]
+    async fn echo_node_emits_valid_messages() -> Result<()> {
+        let (base, mut output) = pipe();
+        let mut node = EchoNode::with_base(base);
+        let requests = vec![
+            init(),
+            Payload::Echo {
+                echo: "hi".to_string(),
+            },
+        ];
+        let emitted = run_scenario(&mut node, &mut output, requests).await?;
+        assert_eq!(emitted.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unique_ids_node_emits_valid_messages() -> Result<()> {
+        let (base, mut output) = pipe();
+        let mut node = UniqueIdsNode::with_base(base);
+        let requests = vec![init(), Payload::Generate, Payload::Generate];
+        let emitted = run_scenario(&mut node, &mut output, requests).await?;
+        assert_eq!(emitted.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broadcast_node_emits_valid_messages() -> Result<()> {
+        let (base, mut output) = pipe();
+        let mut node = BroadcastNode::with_base(base);
+        let topology = HashMap::from([
+            ("n1".to_string(), vec!["n2".to_string()]),
+            ("n2".to_string(), vec!["n1".to_string()]),
+        ]);
+        let requests = vec![
+            init(),
+            Payload::Topology { topology },
+            Payload::Broadcast { message: 3 },
+            Payload::Read,
+            Payload::ReadCoverage,
+        ];
+        let emitted = run_scenario(&mut node, &mut output, requests).await?;
+        assert_eq!(emitted.len(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kafka_node_emits_valid_messages() -> Result<()> {
+        let (base, mut output) = pipe();
+        let mut node = KafkaNode::with_base(base);
+        let offsets = HashMap::from([("k1".to_string(), 0)]);
+        let requests = vec![
+            init(),
+            Payload::Send {
+                key: "k1".to_string(),
+                msg: 7.into(),
+            },
+            Payload::Poll {
+                offsets: offsets.clone(),
+            },
+            Payload::CommitOffsets { offsets },
+            Payload::ListCommittedOffsets {
+                keys: vec!["k1".to_string()],
+            },
+        ];
+        let emitted = run_scenario(&mut node, &mut output, requests).await?;
+        assert_eq!(emitted.len(), 5);
+        Ok(())
+    }
+}
@@ -2,14 +2,19 @@ mod cmd;
 mod error;
 mod maelstrom;
 mod protohackers;
+#[cfg(test)]
+mod testutil;
 mod tracer;
 
 use crate::maelstrom::*;
 use clap::Parser;
 use cmd::*;
-pub use error::{Error, Result};
+pub use error::{Context, Error, Result};
 use protohacker_in_rust::tracer::setup_simple_tracing;
-use protohackers::{run_server, run_server_with_state};
+use protohackers::problem7::LineApp;
+use protohackers::{ServerBuilder, cancel_on_signal};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -17,28 +22,128 @@ async fn main() -> Result<()> {
     match args.cmd {
         Command::Protohackers { case } => {
             let _ = tracer::setup_simple_tracing();
+            // stop accepting on Ctrl-C or SIGTERM, servers tracking their connections
+            // give them a grace period to finish
+            let shutdown = CancellationToken::new();
+            let _signal_handler = cancel_on_signal(shutdown.clone())?;
 
-            match case {
-                ProtohackerCases::SmokeEcho { port } => protohackers::problem0::run(port).await?,
-                ProtohackerCases::PrimeTime { port } => {
-                    run_server(port, protohackers::problem1::handle_client).await?
+            match case.resolve()? {
+                ProtohackerCases::SmokeEcho {
+                    port,
+                    idle_timeout_secs,
+                    server,
+                } => {
+                    let server = server_builder(port, server).shutdown(shutdown);
+                    protohackers::problem0::run(server, Duration::from_secs(idle_timeout_secs))
+                        .await?
+                }
+                ProtohackerCases::PrimeTime {
+                    port,
+                    strategy,
+                    server,
+                } => {
+                    server_builder(port, server)
+                        .shutdown(shutdown)
+                        .run(move |socket| protohackers::problem1::handle_client(socket, strategy))
+                        .await?
+                }
+                ProtohackerCases::MeanToAnEnd {
+                    port,
+                    lenient,
+                    server,
+                } => {
+                    let server = server_builder(port, server).shutdown(shutdown);
+                    protohackers::problem2::run(server, lenient).await?
+                }
+                ProtohackerCases::BudgetChat {
+                    port,
+                    encoding,
+                    chat_format,
+                    keepalive_secs,
+                    room_capacity,
+                    max_message_length,
+                    messages_per_second,
+                    guest_names,
+                    server,
+                } => {
+                    let config = protohackers::problem3::ChatConfig {
+                        encoding,
+                        format: protohackers::problem3::ChatFormat::from(chat_format),
+                        keepalive: keepalive_secs.map(Duration::from_secs),
+                        max_message_length,
+                        messages_per_second: (messages_per_second > 0)
+                            .then_some(messages_per_second),
+                        guest_names,
+                        room: protohackers::problem3::RoomConfig {
+                            capacity: room_capacity,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    };
+                    let server = server_builder(port, server);
+                    protohackers::problem3::run(server.shutdown(shutdown), config).await?
                 }
-                ProtohackerCases::MeanToAnEnd { port } => protohackers::problem2::run(port).await?,
-                ProtohackerCases::BudgetChat { port } => protohackers::problem3::run(port).await?,
-                ProtohackerCases::BudgetChatExample { port } => {
+                ProtohackerCases::BudgetChatExample { port, server } => {
                     let room = protohackers::problem3::Room::new();
-                    run_server_with_state(port, room, protohackers::problem3::handle_client).await?
+                    server_builder(port, server)
+                        .shutdown(shutdown)
+                        .run_with_state(room, protohackers::problem3::handle_client)
+                        .await?
                 }
                 // UDP example
-                ProtohackerCases::UnusualDatabase { port } => {
-                    protohackers::problem4::run(port).await?
+                ProtohackerCases::UnusualDatabase {
+                    port,
+                    key_ttl_secs,
+                    sweep_interval_secs,
+                    max_value_bytes,
+                    extended_syntax,
+                } => {
+                    let config = protohackers::problem4::DbConfig {
+                        key_ttl: key_ttl_secs.map(Duration::from_secs),
+                        sweep_interval: Duration::from_secs(sweep_interval_secs),
+                        max_value_bytes,
+                        extended_syntax,
+                    };
+                    protohackers::problem4::run(port, config, shutdown).await?
                 }
-                ProtohackerCases::ModInMiddle { port } => protohackers::problem5::run(port).await?,
-                ProtohackerCases::SpeedDaemon { port } => protohackers::problem6::run(port).await?,
-                // Custom reliable transport protocol built on UDP
-                ProtohackerCases::LineReversal { port } => {
-                    protohackers::problem7::run(port).await?
+                ProtohackerCases::ModInMiddle {
+                    port,
+                    upstream,
+                    target_account,
+                    idle_timeout_secs,
+                    server,
+                } => {
+                    let config = protohackers::problem5::ProxyConfig {
+                        upstream_addr: upstream,
+                        target_account,
+                        idle_timeout: Duration::from_secs(idle_timeout_secs),
+                    };
+                    let server = server_builder(port, server).shutdown(shutdown);
+                    protohackers::problem5::run(server, config).await?
+                }
+                ProtohackerCases::SpeedDaemon {
+                    port,
+                    transcript_dir,
+                    speed_tolerance,
+                    server,
+                } => {
+                    let config = protohackers::problem6::TicketConfig {
+                        speed_tolerance_100x: speed_tolerance,
+                        ..Default::default()
+                    };
+                    let server = server_builder(port, server);
+                    protohackers::problem6::run(server.shutdown(shutdown), transcript_dir, config)
+                        .await?
                 }
+                // Custom reliable transport protocol built on UDP
+                ProtohackerCases::LineReversal { port, app } => match app {
+                    LineApp::Reverse => protohackers::problem7::run(port, shutdown).await?,
+                    LineApp::Echo => {
+                        let handler = protohackers::problem7::EchoHandler;
+                        protohackers::problem7::run_with(port, handler, shutdown).await?
+                    }
+                },
+                ProtohackerCases::Run { .. } => unreachable!("resolved to its problem"),
             }
         }
         Command::Maelstrom { case } => {
@@ -53,8 +158,24 @@ async fn main() -> Result<()> {
                     let mut node = UniqueIdsNode::new();
                     let _ = node.run().await?;
                 }
-                MaelstromCases::Broadcast => {
-                    let mut node = BroadcastNode::new();
+                MaelstromCases::Broadcast {
+                    max_line_length,
+                    full_mesh_fallback,
+                    keepalive_millis,
+                    keepalive_timeout_millis,
+                } => {
+                    let keepalive = keepalive_millis.map(|millis| Keepalive {
+                        interval: Duration::from_millis(millis),
+                        timeout: Duration::from_millis(keepalive_timeout_millis),
+                    });
+                    let mut node = BroadcastNode::new()
+                        .with_max_line_length(max_line_length)
+                        .with_full_mesh_fallback(full_mesh_fallback)
+                        .with_keepalive(keepalive);
+                    let _ = node.run().await?;
+                }
+                MaelstromCases::Kafka => {
+                    let mut node = KafkaNode::new();
                     let _ = node.run().await?;
                 }
             }
@@ -63,3 +184,22 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+fn server_builder(port: u32, args: ServerArgs) -> ServerBuilder {
+    let mut server = ServerBuilder::new(port)
+        .host(&args.host)
+        .grace_period(Duration::from_secs(args.grace_period_secs));
+    if let Some(max) = args.max_connections {
+        server = server.max_connections(max);
+    }
+    if let Some(max) = args.max_connections_per_ip {
+        server = server.max_connections_per_ip(max);
+    }
+    if let Some(secs) = args.connection_timeout_secs {
+        server = server.timeout(Duration::from_secs(secs));
+    }
+    if let Some(port) = args.admin_port {
+        server = server.admin_port(port);
+    }
+    server
+}
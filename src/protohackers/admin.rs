@@ -0,0 +1,90 @@
+//! Connection counters of a server, and the admin port printing them.
+use super::ACCEPT_RETRY_DELAY;
+use crate::Result;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tracing::{debug, warn};
+
+/// Updated by the accept loop of a server, shared with whoever reports on it.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    active: AtomicUsize,
+    handled: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+#[allow(unused)]
+impl ServerStats {
+    /// Connections accepted and not finished yet, including those waiting for a slot.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Connections finished, whatever their outcome.
+    pub fn handled(&self) -> usize {
+        self.handled.load(Ordering::Relaxed)
+    }
+
+    /// Connections whose handler returned an error.
+    pub fn errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Count a connection as active until the returned guard is dropped,
+    /// which also covers a connection aborted at shutdown.
+    pub(crate) fn connection_started(self: &Arc<Self>) -> ActiveConnection {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self.clone())
+    }
+
+    pub(crate) fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// `active=1 handled=5 errors=0`
+impl fmt::Display for ServerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "active={} handled={} errors={}",
+            self.active(),
+            self.handled(),
+            self.errors()
+        )
+    }
+}
+
+pub(crate) struct ActiveConnection(Arc<ServerStats>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+        self.0.handled.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Answer every connection to `listener` with the current `stats` as one line, then close it.
+/// A failed accept, e.g. when out of file descriptors, is logged and retried shortly after.
+pub async fn serve_stats(listener: TcpListener, stats: Arc<ServerStats>) -> Result<()> {
+    loop {
+        let (mut socket, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Admin listener failed to accept a connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let line = format!("{}\n", stats);
+        tokio::spawn(async move {
+            if let Err(e) = socket.write_all(line.as_bytes()).await {
+                debug!("Could not send stats to {}: {}", address, e);
+            }
+            let _ = socket.shutdown().await;
+        });
+    }
+}
@@ -0,0 +1,89 @@
+//! End a stream of frames when the peer stays silent for too long.
+use futures::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep, sleep};
+use tracing::debug;
+
+/// Wraps a stream (usually a `Framed` or its read half) and ends it, yielding `None`
+/// like an EOF, once no item has arrived for `duration`.
+/// Unlike a timeout around each read, the deadline only moves when an item arrives,
+/// so it holds however often the reading task wakes up for other reasons.
+pub struct IdleTimeout<S> {
+    inner: S,
+    duration: Duration,
+    deadline: Pin<Box<Sleep>>,
+    timed_out: bool,
+}
+
+impl<S> IdleTimeout<S> {
+    pub fn new(inner: S, duration: Duration) -> Self {
+        Self {
+            inner,
+            duration,
+            deadline: Box::pin(sleep(duration)),
+            timed_out: false,
+        }
+    }
+}
+
+impl<S: Stream + Unpin> Stream for IdleTimeout<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.timed_out {
+            return Poll::Ready(None);
+        }
+
+        if let Poll::Ready(item) = Pin::new(&mut this.inner).poll_next(cx) {
+            if item.is_some() {
+                this.deadline.as_mut().reset(Instant::now() + this.duration);
+            }
+            return Poll::Ready(item);
+        }
+
+        if this.deadline.as_mut().poll(cx).is_ready() {
+            debug!("no message received within {:?}, disconnect", this.duration);
+            this.timed_out = true;
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::codec::{FramedRead, LinesCodec};
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_stream_ends_after_the_timeout() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut lines = IdleTimeout::new(
+            FramedRead::new(server, LinesCodec::new()),
+            Duration::from_secs(5),
+        );
+        let start = Instant::now();
+
+        // each line moves the deadline
+        for line in ["one", "two"] {
+            tokio::time::sleep(Duration::from_secs(4)).await;
+            client
+                .write_all(format!("{line}\n").as_bytes())
+                .await
+                .unwrap();
+            assert_eq!(lines.next().await.unwrap().unwrap(), line);
+        }
+
+        // the client stays connected, but silent
+        assert!(lines.next().await.is_none());
+        assert_eq!(start.elapsed(), Duration::from_secs(13));
+        // and the stream stays ended
+        client.write_all(b"late\n").await.unwrap();
+        assert!(lines.next().await.is_none());
+    }
+}
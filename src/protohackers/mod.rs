@@ -1,3 +1,5 @@
+pub mod admin;
+pub mod idle_timeout;
 pub mod problem0;
 pub mod problem1;
 pub mod problem2;
@@ -6,40 +8,1097 @@ pub mod problem4;
 pub mod problem5;
 pub mod problem6;
 pub mod problem7;
+pub mod transcript;
 
-use crate::Result;
-use std::{future::Future, net::SocketAddr};
-use tokio::net::{TcpListener, TcpStream};
-use tracing::{debug, error, info};
+use crate::{Error, Result};
+use admin::ServerStats;
+use futures::{Stream, StreamExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
 pub const HOST: &str = "0.0.0.0";
+/// How long a stopping server waits for its open connections before aborting them.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5);
 
-pub async fn run_server<H, F>(port: u32, handler: H) -> Result<()>
+/// Pause after a failed accept, so a lasting error does not spin the accept loop.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// A client dropping the connection in the middle of an exchange, which is its own
+/// business rather than a failure of the server.
+fn is_peer_disconnect(err: &Error) -> bool {
+    matches!(
+        err.root_cause(),
+        Error::Io(e) if matches!(
+            e.kind(),
+            ErrorKind::ConnectionReset | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof
+        )
+    )
+}
+
+/// How a connection handler finished.
+/// Only `Error` is reported as a failure, the others are normal ways for a session to end.
+#[derive(Debug)]
+pub enum HandlerOutcome {
+    /// The server finished serving the client and closed the connection itself.
+    Completed,
+    /// The client closed the connection (EOF).
+    ClientClosed,
+    /// Something went wrong while serving the client.
+    Error(Error),
+}
+
+impl From<Result<()>> for HandlerOutcome {
+    fn from(value: Result<()>) -> Self {
+        match value {
+            Ok(()) => HandlerOutcome::Completed,
+            Err(e) => HandlerOutcome::Error(e),
+        }
+    }
+}
+
+impl From<Result<HandlerOutcome>> for HandlerOutcome {
+    fn from(value: Result<HandlerOutcome>) -> Self {
+        value.unwrap_or_else(HandlerOutcome::Error)
+    }
+}
+
+/// Cancel `token` on the first SIGINT (Ctrl-C) or SIGTERM, e.g. the token given to
+/// `ServerBuilder::shutdown`, and exit right away on a second one instead of waiting
+/// out the grace period. The handlers are installed before this returns.
+pub fn cancel_on_signal(token: CancellationToken) -> Result<JoinHandle<()>> {
+    #[cfg(unix)]
+    let signals = {
+        use tokio::signal::unix::{Signal, SignalKind, signal};
+        fn named(signal: Signal, name: &'static str) -> impl Stream<Item = &'static str> {
+            futures::stream::unfold(signal, move |mut signal| async move {
+                signal.recv().await.map(|()| (name, signal))
+            })
+        }
+        futures::stream::select(
+            named(signal(SignalKind::interrupt())?, "SIGINT"),
+            named(signal(SignalKind::terminate())?, "SIGTERM"),
+        )
+    };
+    #[cfg(not(unix))]
+    let signals = futures::stream::unfold((), |()| async {
+        tokio::signal::ctrl_c().await.ok().map(|()| ("Ctrl-C", ()))
+    });
+
+    Ok(tokio::spawn(async move {
+        if cancel_on(token, signals).await {
+            std::process::exit(130);
+        }
+    }))
+}
+
+/// Cancel `token` on the first item of `signals`, then wait for another one.
+/// Returns true when a second signal arrived, false when `signals` ended first.
+async fn cancel_on<S>(token: CancellationToken, signals: S) -> bool
 where
-    H: Fn(TcpStream) -> F,
-    F: Future<Output = Result<()>> + Send + 'static,
+    S: Stream<Item = &'static str>,
 {
-    run_server_with_state(port, (), |_, stream, _| handler(stream)).await
+    let mut signals = std::pin::pin!(signals);
+    let Some(name) = signals.next().await else {
+        return false;
+    };
+    info!("Received {}, shutting down", name);
+    token.cancel();
+
+    match signals.next().await {
+        Some(name) => {
+            info!(
+                "Received {} again, exiting without waiting for connections",
+                name
+            );
+            true
+        }
+        None => false,
+    }
+}
+
+/// Optional limits and controls applied by the accept loop.
+#[derive(Debug, Clone, Default)]
+struct ServerOptions {
+    /// Maximum number of concurrent connections, unlimited when `None`.
+    max_connections: Option<usize>,
+    /// Maximum number of concurrent connections from the same source IP, unlimited when `None`.
+    max_connections_per_ip: Option<usize>,
+    /// How long a single connection may be served before it is closed, unlimited when `None`.
+    connection_timeout: Option<Duration>,
+    /// Stops accepting connections once cancelled.
+    shutdown: CancellationToken,
+    /// How long to wait for open connections after shutdown, `DEFAULT_GRACE_PERIOD` when `None`.
+    grace_period: Option<Duration>,
+    /// Told about every connection whose handler failed.
+    on_error: ErrorObserver,
+    /// Counters of the served connections.
+    stats: Arc<ServerStats>,
+    /// Port of the admin listener printing `stats`, none when `None`.
+    admin_port: Option<u32>,
+}
+
+/// Callback invoked with the peer address and the error of each failed connection.
+#[derive(Clone)]
+struct ErrorObserver(Arc<ErrorCallback>);
+
+type ErrorCallback = dyn Fn(SocketAddr, &Error) + Send + Sync;
+
+impl Default for ErrorObserver {
+    fn default() -> Self {
+        Self(Arc::new(|_, _| {}))
+    }
+}
+
+impl std::fmt::Debug for ErrorObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ErrorObserver")
+    }
+}
+
+/// Configure a TCP server step by step, then `run` it.
+///
+/// ```ignore
+/// ServerBuilder::new(port)
+///     .max_connections(100)
+///     .timeout(Duration::from_secs(60))
+///     .shutdown(token)
+///     .run(handler)
+///     .await?;
+/// ```
+#[derive(Debug)]
+pub struct ServerBuilder {
+    host: String,
+    port: u32,
+    options: ServerOptions,
+    /// Already bound listener served instead of binding `host:port`.
+    listener: Option<TcpListener>,
+    /// Already bound listener of the admin port, takes precedence over `options.admin_port`.
+    admin_listener: Option<TcpListener>,
+}
+
+impl ServerBuilder {
+    pub fn new(port: u32) -> Self {
+        Self {
+            host: HOST.to_string(),
+            port,
+            options: ServerOptions::default(),
+            listener: None,
+            admin_listener: None,
+        }
+    }
+
+    /// Serve connections of `listener` instead of binding `host:port`, e.g. a listener
+    /// bound to port 0 whose `local_addr` tells the port picked for it.
+    #[allow(unused)]
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Listen on `host` instead of all interfaces (`HOST`).
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = host.to_string();
+        self
+    }
+
+    /// Connections beyond the cap are accepted but wait until a served connection finishes.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.options.max_connections = Some(max);
+        self
+    }
+
+    /// Connections beyond the cap of their source IP are closed right after being accepted.
+    pub fn max_connections_per_ip(mut self, max: usize) -> Self {
+        self.options.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Close a connection once it has been served for `duration`, whatever its state.
+    pub fn timeout(mut self, duration: Duration) -> Self {
+        self.options.connection_timeout = Some(duration);
+        self
+    }
+
+    /// The server stops accepting connections and returns once `token` is cancelled.
+    pub fn shutdown(mut self, token: CancellationToken) -> Self {
+        self.options.shutdown = token;
+        self
+    }
+
+    /// Call `observer` whenever a connection handler returns an error, e.g. to count failures.
+    #[allow(unused)]
+    pub fn on_error<E>(mut self, observer: E) -> Self
+    where
+        E: Fn(SocketAddr, &Error) + Send + Sync + 'static,
+    {
+        self.options.on_error = ErrorObserver(Arc::new(observer));
+        self
+    }
+
+    /// How long open connections may keep running after shutdown before they are aborted.
+    pub fn grace_period(mut self, duration: Duration) -> Self {
+        self.options.grace_period = Some(duration);
+        self
+    }
+
+    /// Count connections into `stats` instead of counters of the server's own.
+    #[allow(unused)]
+    pub fn stats(mut self, stats: Arc<ServerStats>) -> Self {
+        self.options.stats = stats;
+        self
+    }
+
+    /// Also listen on `port` of the same host, each connection there gets
+    /// the connection counters as a line of text, see `admin::serve_stats`.
+    pub fn admin_port(mut self, port: u32) -> Self {
+        self.options.admin_port = Some(port);
+        self
+    }
+
+    /// Serve the connection counters on `listener`, like `admin_port` does on its port.
+    #[allow(unused)]
+    pub fn admin_listener(mut self, listener: TcpListener) -> Self {
+        self.admin_listener = Some(listener);
+        self
+    }
+
+    pub async fn run<H, F, O>(self, handler: H) -> Result<()>
+    where
+        H: Fn(TcpStream) -> F,
+        F: Future<Output = O> + Send + 'static,
+        O: Into<HandlerOutcome>,
+    {
+        self.run_with_state((), |_, stream, _| handler(stream))
+            .await
+    }
+
+    pub async fn run_with_state<H, S, F, O>(self, state: S, handler: H) -> Result<()>
+    where
+        S: Clone,
+        H: Fn(S, TcpStream, SocketAddr) -> F,
+        F: Future<Output = O> + Send + 'static,
+        O: Into<HandlerOutcome>,
+    {
+        let listener = match self.listener {
+            Some(listener) => listener,
+            None => bind_tcp(&format!("{}:{}", self.host, self.port)).await?,
+        };
+        let admin_listener = match (self.admin_listener, self.options.admin_port) {
+            (Some(listener), _) => Some(listener),
+            (None, Some(port)) => Some(bind_tcp(&format!("{}:{}", self.host, port)).await?),
+            (None, None) => None,
+        };
+        let admin = match admin_listener {
+            Some(admin_listener) => {
+                info!("Serving stats at {}", admin_listener.local_addr()?);
+                let stats = self.options.stats.clone();
+                Some(tokio::spawn(admin::serve_stats(admin_listener, stats)))
+            }
+            None => None,
+        };
+
+        info!("Starting server at {}", listener.local_addr()?);
+        let result = serve(listener, self.options, state, handler).await;
+        if let Some(admin) = admin {
+            admin.abort();
+        }
+        result
+    }
+
+    /// Like `run_with_state`, but each connection also gets its own state, built by
+    /// `factory` from the shared state and the peer address before the handler runs.
+    pub async fn run_with_factory<H, S, P, M, F, O>(
+        self,
+        shared: S,
+        factory: M,
+        handler: H,
+    ) -> Result<()>
+    where
+        S: Clone,
+        M: Fn(&S, SocketAddr) -> P,
+        H: Fn(S, P, TcpStream, SocketAddr) -> F,
+        F: Future<Output = O> + Send + 'static,
+        O: Into<HandlerOutcome>,
+    {
+        self.run_with_state(shared, move |shared, stream, address| {
+            let connection_state = factory(&shared, address);
+            handler(shared, connection_state, stream, address)
+        })
+        .await
+    }
+}
+
+/// Bind a TCP listener with `SO_REUSEADDR` set, so a restarted server can rebind
+/// its port immediately even if connections of the previous run linger in TIME_WAIT.
+pub async fn bind_tcp(addr: &str) -> Result<TcpListener> {
+    let addr = resolve(addr).await?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
 }
 
-pub async fn run_server_with_state<H, S, F>(port: u32, state: S, handler: H) -> Result<()>
+/// Bind a UDP socket with `SO_REUSEADDR` set, see `bind_tcp`.
+pub async fn bind_udp(addr: &str) -> Result<UdpSocket> {
+    let addr = resolve(addr).await?;
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+async fn resolve(addr: &str) -> Result<SocketAddr> {
+    tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| Error::Other(format!("{} does not resolve to any address", addr)))
+}
+
+async fn serve<H, S, F, O>(
+    listener: TcpListener,
+    options: ServerOptions,
+    state: S,
+    handler: H,
+) -> Result<()>
 where
     S: Clone,
     H: Fn(S, TcpStream, SocketAddr) -> F,
-    F: Future<Output = Result<()>> + Send + 'static,
+    F: Future<Output = O> + Send + 'static,
+    O: Into<HandlerOutcome>,
 {
-    let listener = TcpListener::bind(&format!("{}:{}", HOST, port)).await?;
+    let connection_limiter = options
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max)));
+    let per_ip_limiter = options.max_connections_per_ip.map(PerIpLimiter::new);
+    let mut connections = JoinSet::new();
+    let started = Instant::now();
+    let mut served = 0usize;
+    let mut refused = 0usize;
 
-    info!("Starting server at {}:{}", HOST, port);
-    loop {
-        let (socket, address) = listener.accept().await?;
+    let result = loop {
+        let (socket, address) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                // e.g. out of file descriptors, which frees up as connections close
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+            _ = options.shutdown.cancelled() => {
+                info!("Server shutdown requested, stop accepting connections");
+                break Ok(());
+            }
+        };
+        // reap finished connections so the set does not grow with the server's lifetime
+        while connections.try_join_next().is_some() {}
 
         debug!("Got connection from {}", address);
+        let permit = match &per_ip_limiter {
+            Some(limiter) => match limiter.try_acquire(address.ip()) {
+                Some(permit) => Some(permit),
+                None => {
+                    info!(
+                        "Refuse connection {}: too many connections from its IP",
+                        address
+                    );
+                    drop(socket);
+                    refused += 1;
+                    continue;
+                }
+            },
+            None => None,
+        };
+
         let future = handler(state.clone(), socket, address);
-        tokio::task::spawn(async move {
-            if let Err(err) = future.await {
-                error!("Error handling connection {}: {}", address, err);
+        let active = options.stats.connection_started();
+        served += 1;
+        let connection_id = served;
+        let connection_timeout = options.connection_timeout;
+        let on_error = options.on_error.clone();
+        let stats = options.stats.clone();
+        let connection_limiter = connection_limiter.clone();
+        connections.spawn(async move {
+            let _active = active;
+            // a connection beyond the cap waits for a slot before being served
+            let connection_permit = match connection_limiter {
+                Some(limiter) => match limiter.acquire_owned().await {
+                    Ok(permit) => Some(permit),
+                    Err(_) => return,
+                },
+                None => None,
+            };
+            // keep the connection slots taken until the handler finishes
+            let _permits = (connection_permit, permit);
+            let outcome = match connection_timeout {
+                // dropping the handler future closes its connection
+                Some(duration) => match tokio::time::timeout(duration, future).await {
+                    Ok(outcome) => outcome.into(),
+                    Err(_elapsed) => {
+                        debug!("Connection {} timed out after {:?}", address, duration);
+                        HandlerOutcome::Completed
+                    }
+                },
+                None => future.await.into(),
+            };
+            match outcome {
+                HandlerOutcome::Completed => debug!("Connection {} completed", address),
+                HandlerOutcome::ClientClosed => debug!("Connection {} closed by client", address),
+                HandlerOutcome::Error(err) => {
+                    stats.record_error();
+                    (on_error.0)(address, &err);
+                    let err = err.context(format!("connection {} from {}", connection_id, address));
+                    if is_peer_disconnect(&err) {
+                        debug!("Client went away, {}", err);
+                    } else {
+                        error!("Error handling {}", err);
+                    }
+                }
             }
         });
+    };
+
+    let grace_period = options.grace_period.unwrap_or(DEFAULT_GRACE_PERIOD);
+    let drained = tokio::time::timeout(grace_period, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        info!(
+            "Abort {} connections still open after {:?}",
+            connections.len(),
+            grace_period
+        );
+        connections.abort_all();
+    }
+    info!(
+        connections = served,
+        refused,
+        uptime = ?started.elapsed(),
+        "Server stopped"
+    );
+    result
+}
+
+/// Counts the live connections of each source IP.
+#[derive(Debug, Clone)]
+struct PerIpLimiter {
+    max_per_ip: usize,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// A connection slot of an IP, given back when dropped.
+#[derive(Debug)]
+struct PerIpPermit {
+    ip: IpAddr,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl PerIpLimiter {
+    fn new(max_per_ip: usize) -> Self {
+        Self {
+            max_per_ip,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn try_acquire(&self, ip: IpAddr) -> Option<PerIpPermit> {
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+
+        Some(PerIpPermit {
+            ip,
+            connections: self.connections.clone(),
+        })
+    }
+}
+
+impl Drop for PerIpPermit {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Await a single message read (a `read_exact`, a `Framed::next`, ...) for at most `duration`.
+/// Returns `None` when the deadline elapses, so a stalled client can be treated as a disconnect
+/// instead of holding its task forever.
+pub async fn read_message_with_timeout<F, T>(duration: Duration, read: F) -> Option<T>
+where
+    F: Future<Output = T>,
+{
+    match tokio::time::timeout(duration, read).await {
+        Ok(message) => Some(message),
+        Err(_elapsed) => {
+            debug!("no message received within {:?}, disconnect", duration);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn per_ip_limiter_caps_each_ip_independently() {
+        let limiter = PerIpLimiter::new(2);
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let first = limiter.try_acquire(local).unwrap();
+        let _second = limiter.try_acquire(local).unwrap();
+        assert!(limiter.try_acquire(local).is_none());
+
+        // a different source is unaffected
+        assert!(limiter.try_acquire(other).is_some());
+
+        // a finished connection frees its slot
+        drop(first);
+        assert!(limiter.try_acquire(local).is_some());
+    }
+
+    #[tokio::test]
+    async fn tcp_port_can_be_rebound_right_after_close() -> Result<()> {
+        let listener = bind_tcp("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        // the server closes first, which leaves its side of the connection in TIME_WAIT
+        let client = TcpStream::connect(addr).await?;
+        let (server_side, _) = listener.accept().await?;
+        drop(server_side);
+        drop(listener);
+        drop(client);
+
+        let listener = bind_tcp(&addr.to_string()).await?;
+        assert_eq!(listener.local_addr()?, addr);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn udp_port_can_be_rebound_right_after_close() -> Result<()> {
+        let socket = bind_udp("127.0.0.1:0").await?;
+        let addr = socket.local_addr()?;
+        drop(socket);
+
+        let socket = bind_udp(&addr.to_string()).await?;
+        assert_eq!(socket.local_addr()?, addr);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connections_beyond_per_ip_cap_are_closed() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(
+            ServerBuilder::new(0)
+                .listener(listener)
+                .max_connections_per_ip(1)
+                .run(problem0::handle_client),
+        );
+
+        let mut first = TcpStream::connect(addr).await?;
+        first.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        first.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+
+        // same source IP while the first connection is alive: refused
+        let mut second = TcpStream::connect(addr).await?;
+        assert_eq!(second.read(&mut buf).await?, 0);
+
+        // once the first connection is gone, the IP can connect again
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut third = TcpStream::connect(addr).await?;
+        third.write_all(b"pong").await?;
+        third.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"pong");
+
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn builder_caps_connections_and_times_them_out() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(
+            ServerBuilder::new(0)
+                .listener(listener)
+                .max_connections(1)
+                .timeout(Duration::from_millis(200))
+                .run(problem0::handle_client),
+        );
+
+        let mut first = TcpStream::connect(addr).await?;
+        first.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        first.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+
+        // the only slot is taken: the second connection waits
+        let mut second = TcpStream::connect(addr).await?;
+        second.write_all(b"pong").await?;
+        let waiting = tokio::time::timeout(Duration::from_millis(100), second.read(&mut buf)).await;
+        assert!(
+            waiting.is_err(),
+            "second connection should not be served yet"
+        );
+
+        // the first connection is closed by the server after the timeout, freeing the slot
+        let closed = tokio::time::timeout(Duration::from_secs(2), first.read(&mut buf)).await;
+        assert_eq!(closed.expect("connection should time out")?, 0);
+
+        second.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"pong");
+
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn builder_shutdown_stops_the_server() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let token = CancellationToken::new();
+        let server = tokio::spawn(
+            ServerBuilder::new(0)
+                .listener(listener)
+                .max_connections_per_ip(4)
+                .shutdown(token.clone())
+                .run(problem0::handle_client),
+        );
+
+        token.cancel();
+        let result = tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("server should stop once shutdown is requested");
+        assert!(result.unwrap().is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_on_all_interfaces_accepts_loopback_clients() -> Result<()> {
+        let listener = bind_tcp("0.0.0.0:0").await?;
+        let port = listener.local_addr()?.port();
+        let server = tokio::spawn(
+            ServerBuilder::new(0)
+                .listener(listener)
+                .run(problem0::handle_client),
+        );
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await?;
+        client.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_open_connections_then_returns() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let token = CancellationToken::new();
+        let server = tokio::spawn(
+            ServerBuilder::new(0)
+                .listener(listener)
+                .shutdown(token.clone())
+                .grace_period(Duration::from_secs(10))
+                .run(problem0::handle_client),
+        );
+
+        let mut client = TcpStream::connect(addr).await?;
+        client.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await?;
+
+        token.cancel();
+        // the open connection keeps the server in its grace period
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!server.is_finished());
+
+        drop(client);
+        let result = tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("server should return once its connections are done");
+        assert!(result.unwrap().is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_aborts_connections_after_grace_period() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let token = CancellationToken::new();
+        let server = tokio::spawn(
+            ServerBuilder::new(0)
+                .listener(listener)
+                .shutdown(token.clone())
+                .grace_period(Duration::from_secs(1))
+                .run(problem0::handle_client),
+        );
+
+        let mut client = TcpStream::connect(addr).await?;
+        client.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await?;
+
+        token.cancel();
+        let result = tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("server should give up on connections after the grace period");
+        assert!(result.unwrap().is_ok());
+        // the aborted handler closed the connection
+        assert_eq!(client.read(&mut buf).await?, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn error_observer_fires_once_per_failing_connection() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let failures = Arc::new(AtomicUsize::new(0));
+        let counter = failures.clone();
+        // fail every connection whose first byte is 'x'
+        let server = tokio::spawn(
+            ServerBuilder::new(0)
+                .listener(listener)
+                .on_error(move |_, _| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                })
+                .run(|mut socket| async move {
+                    let mut byte = [0u8; 1];
+                    socket.read_exact(&mut byte).await?;
+                    if byte[0] == b'x' {
+                        return Err(Error::Other("bad client".to_string()));
+                    }
+                    Ok(())
+                }),
+        );
+
+        for first in [b"x", b"o", b"x"] {
+            let mut client = TcpStream::connect(addr).await?;
+            client.write_all(first).await?;
+            // wait for the server to close the connection
+            let mut buf = [0u8; 1];
+            let _ = client.read(&mut buf).await;
+        }
+        // the observer runs after the connection has been closed
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(failures.load(Ordering::SeqCst), 2);
+
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connections_beyond_the_cap_wait_for_a_slot() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let state = (active.clone(), max_active.clone());
+        let server = tokio::spawn(
+            ServerBuilder::new(0)
+                .listener(listener)
+                .max_connections(2)
+                .run_with_state(state, |(active, max_active), socket, _| async move {
+                    let now_active = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_active.fetch_max(now_active, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    problem0::handle_client(socket).await
+                }),
+        );
+
+        let mut clients = Vec::new();
+        for _ in 0..4 {
+            let mut client = TcpStream::connect(addr).await?;
+            client.write_all(b"ping").await?;
+            client.shutdown().await?;
+            clients.push(client);
+        }
+        // every client is eventually served, never more than two at a time
+        for mut client in clients {
+            let mut echoed = Vec::new();
+            tokio::time::timeout(Duration::from_secs(2), client.read_to_end(&mut echoed))
+                .await
+                .expect("waiting client should be served")?;
+            assert_eq!(echoed, b"ping");
+        }
+        assert_eq!(max_active.load(Ordering::SeqCst), 2);
+
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn factory_builds_fresh_state_for_each_connection() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let connections = Arc::new(AtomicUsize::new(0));
+        // each connection replies with its own number, then how many connections the
+        // shared counter has seen so far
+        let server = tokio::spawn(ServerBuilder::new(0).listener(listener).run_with_factory(
+            connections.clone(),
+            |shared: &Arc<AtomicUsize>, _| shared.fetch_add(1, Ordering::SeqCst) as u8,
+            |shared, number, mut socket, _| async move {
+                let total = shared.load(Ordering::SeqCst) as u8;
+                socket.write_all(&[number, total]).await?;
+                Ok::<_, Error>(())
+            },
+        ));
+
+        let mut replies = Vec::new();
+        for _ in 0..3 {
+            let mut client = TcpStream::connect(addr).await?;
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await?;
+            replies.push(reply);
+        }
+        assert_eq!(replies, vec![[0, 1], [1, 2], [2, 3]]);
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_logs_a_summary_of_served_connections() -> Result<()> {
+        let (captured, _guard) = crate::testutil::capture_logs();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let token = CancellationToken::new();
+        let server = tokio::spawn(
+            ServerBuilder::new(0)
+                .listener(listener)
+                .shutdown(token.clone())
+                .run(problem0::handle_client),
+        );
+
+        for _ in 0..2 {
+            let mut client = TcpStream::connect(addr).await?;
+            client.write_all(b"ping").await?;
+            let mut buf = [0u8; 4];
+            client.read_exact(&mut buf).await?;
+        }
+        token.cancel();
+        tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("server should stop")
+            .unwrap()?;
+
+        let logs = captured.text();
+        let summary: Vec<&str> = logs
+            .lines()
+            .filter(|line| line.contains("Server stopped"))
+            .collect();
+        assert_eq!(summary.len(), 1, "{}", logs);
+        assert!(summary[0].contains("connections=2"), "{}", summary[0]);
+        assert!(summary[0].contains("refused=0"), "{}", summary[0]);
+        assert!(summary[0].contains("uptime="), "{}", summary[0]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_going_away_is_not_logged_as_an_error() -> Result<()> {
+        let (captured, _guard) = crate::testutil::capture_logs();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let token = CancellationToken::new();
+        let server = tokio::spawn(
+            ServerBuilder::new(0)
+                .listener(listener)
+                .shutdown(token.clone())
+                .run(|mut socket| async move {
+                    let mut message = [0u8; 4];
+                    socket.read_exact(&mut message).await?;
+                    Ok(())
+                }),
+        );
+
+        // half a message, then the client is gone
+        let mut client = TcpStream::connect(addr).await?;
+        client.write_all(b"pi").await?;
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        token.cancel();
+        server.await.unwrap()?;
+
+        let logs = captured.text();
+        assert!(
+            logs.contains("Client went away, connection 1 from"),
+            "{}",
+            logs
+        );
+        assert!(!logs.contains("ERROR"), "{}", logs);
+
+        Ok(())
+    }
+
+    /// Connect to the admin port, retrying until it listens, and parse its stats line.
+    async fn read_stats(port: u16) -> Result<HashMap<String, usize>> {
+        let mut admin = loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(admin) => break admin,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let mut line = String::new();
+        // the admin port closes the connection after the line
+        admin.read_to_string(&mut line).await?;
+        let stats = line
+            .trim_end()
+            .split(' ')
+            .map(|field| {
+                let (name, value) = field.split_once('=').unwrap();
+                (name.to_string(), value.parse().unwrap())
+            })
+            .collect();
+        Ok(stats)
+    }
+
+    #[tokio::test]
+    async fn admin_port_prints_the_connection_counters() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let admin_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let admin_port = admin_listener.local_addr()?.port();
+        let stats = Arc::new(ServerStats::default());
+        let server = tokio::spawn(
+            ServerBuilder::new(0)
+                .listener(listener)
+                .stats(stats.clone())
+                .admin_listener(admin_listener)
+                .run(|mut socket| async move {
+                    let mut byte = [0u8; 1];
+                    socket.read_exact(&mut byte).await?;
+                    if byte[0] == b'!' {
+                        return Err(Error::Other("client asked for an error".into()));
+                    }
+                    // serve until the client closes
+                    let _ = socket.read(&mut byte).await?;
+                    Ok(())
+                }),
+        );
+        assert_eq!(read_stats(admin_port).await?["handled"], 0);
+
+        let mut failing = TcpStream::connect(("127.0.0.1", port)).await?;
+        failing.write_all(b"!").await?;
+        let mut finished = TcpStream::connect(("127.0.0.1", port)).await?;
+        finished.write_all(b"a").await?;
+        drop(finished);
+        let mut open = TcpStream::connect(("127.0.0.1", port)).await?;
+        open.write_all(b"a").await?;
+
+        let expected = HashMap::from([
+            ("active".to_string(), 1),
+            ("handled".to_string(), 2),
+            ("errors".to_string(), 1),
+        ]);
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while read_stats(admin_port).await.unwrap() != expected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the stats should count the three connections");
+        assert_eq!(stats.active(), 1);
+
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_signal_stops_the_server_gracefully() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let token = CancellationToken::new();
+        let server = tokio::spawn(
+            ServerBuilder::new(0)
+                .listener(listener)
+                .shutdown(token.clone())
+                .run(problem0::handle_client),
+        );
+        let mut client = TcpStream::connect(addr).await?;
+        client.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await?;
+
+        let (signal, signals) = tokio::sync::mpsc::unbounded_channel();
+        let signal_handler = tokio::spawn(cancel_on(
+            token.clone(),
+            tokio_stream::wrappers::UnboundedReceiverStream::new(signals),
+        ));
+        signal.send("SIGTERM").unwrap();
+        tokio::time::timeout(Duration::from_secs(1), token.cancelled())
+            .await
+            .expect("the signal should cancel the token");
+
+        // the open connection is still served during the grace period
+        client.write_all(b"pong").await?;
+        client.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"pong");
+        drop(client);
+
+        let result = tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("server should stop once its connection is closed");
+        assert!(result.unwrap().is_ok());
+
+        // no second signal: the handler does not ask to exit
+        drop(signal);
+        assert!(!signal_handler.await.unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_second_signal_asks_to_exit_immediately() {
+        let token = CancellationToken::new();
+        let signals = futures::stream::iter(["SIGINT", "SIGINT"]);
+        assert!(cancel_on(token.clone(), signals).await);
+        assert!(token.is_cancelled());
     }
 }
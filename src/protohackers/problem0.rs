@@ -1,26 +1,37 @@
 use crate::Result;
 
-use super::HOST;
+use super::{ServerBuilder, read_message_with_timeout};
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tracing::info;
+use tokio::net::TcpStream;
 
-pub async fn run(port: u32) -> Result<()> {
-    let address = format!("{HOST}:{port}");
-    let listener = TcpListener::bind(address.clone()).await?;
+/// How long a client may stay silent before its connection is closed.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
 
-    info!("echo server listening on {address}");
-    loop {
-        let (socket, _addr) = listener.accept().await?;
+/// Serve the echo service with `server`, see `handle_client_with_idle_timeout`.
+pub async fn run(server: ServerBuilder, idle_timeout: Duration) -> Result<()> {
+    server
+        .run(move |socket| handle_client_with_idle_timeout(socket, idle_timeout))
+        .await
+}
 
-        tokio::spawn(handle_client(socket));
-    }
+#[allow(unused)]
+pub async fn handle_client(socket: TcpStream) -> Result<()> {
+    handle_client_with_idle_timeout(socket, DEFAULT_IDLE_TIMEOUT).await
 }
 
-pub async fn handle_client(mut socket: TcpStream) -> Result<()> {
+/// Echo everything back until EOF, or until the client sends nothing for `idle_timeout`.
+pub async fn handle_client_with_idle_timeout(
+    mut socket: TcpStream,
+    idle_timeout: Duration,
+) -> Result<()> {
     let mut buf = [0; 1024];
     loop {
-        match socket.read(&mut buf).await {
+        let Some(read) = read_message_with_timeout(idle_timeout, socket.read(&mut buf)).await
+        else {
+            return Ok(());
+        };
+        match read {
             Ok(0) => return Ok(()),
             Ok(n) => {
                 socket.write_all(&buf[..n]).await?;
@@ -34,6 +45,7 @@ pub async fn handle_client(mut socket: TcpStream) -> Result<()> {
 mod tests {
     use super::*;
     use std::net::SocketAddr;
+    use tokio::net::TcpListener;
 
     // --- Test Helper: Start server on random port ---
     async fn start_test_server() -> SocketAddr {
@@ -88,4 +100,31 @@ mod tests {
             );
         }
     }
+
+    #[tokio::test]
+    async fn silent_client_is_closed_after_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            handle_client_with_idle_timeout(socket, Duration::from_millis(200)).await
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        // activity resets the idle timer
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        stream.write_all(b"pong").await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // then staying silent gets the connection closed
+        let closed = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf))
+            .await
+            .expect("connection should be closed after the idle timeout");
+        assert_eq!(closed.unwrap(), 0);
+    }
 }
@@ -1,9 +1,15 @@
 // https://protohackers.com/problem/1
 // This problem exercise on how to read tcp stream line by line.
 
-use crate::Result;
+use crate::{Error, Result};
 
-use serde::{Deserialize, Serialize};
+use futures::StreamExt;
+use futures::future::BoxFuture;
+use futures::stream::FuturesOrdered;
+use num_bigint::BigUint;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
 use tracing::error;
@@ -12,7 +18,29 @@ use tracing::error;
 struct Request {
     #[allow(unused)]
     method: Method,
-    number: f64,
+    number: JsonNumber,
+}
+
+/// A JSON number kept as written, so integers beyond f64 precision are not rounded.
+#[derive(Debug)]
+struct JsonNumber(String);
+
+impl<'de> Deserialize<'de> for JsonNumber {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let raw = Box::<RawValue>::deserialize(deserializer)?;
+        // any other JSON value starts with a quote, a bracket or a letter
+        if raw
+            .get()
+            .starts_with(|c: char| c == '-' || c.is_ascii_digit())
+        {
+            Ok(JsonNumber(raw.get().to_string()))
+        } else {
+            Err(D::Error::custom(format!(
+                "expected a number, got {}",
+                raw.get()
+            )))
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -21,6 +49,8 @@ enum Method {
     IsPrime,
 }
 
+/// Serialized as exactly `{"method":"isPrime","prime":<bool>}`, fields in declaration order.
+/// Extra fields of the request are never echoed back.
 #[derive(Serialize, Debug)]
 struct Response {
     method: Method,
@@ -36,75 +66,279 @@ impl Response {
     }
 }
 
-pub async fn handle_client(mut socket: TcpStream) -> Result<()> {
+/// How the server decides whether an integer is prime.
+pub trait PrimalityTest: Send + Sync {
+    fn is_prime(&self, n: u64) -> bool;
+}
+
+/// Check odd divisors up to sqrt(n), simple but slow for large numbers.
+pub struct TrialDivision;
+
+impl PrimalityTest for TrialDivision {
+    fn is_prime(&self, n: u64) -> bool {
+        // Handle numbers less than 2
+        if n < 2 {
+            return false;
+        }
+
+        // Handle small primes
+        if n == 2 {
+            return true;
+        }
+
+        // Even numbers greater than 2 are not prime
+        if n.is_multiple_of(2) {
+            return false;
+        }
+
+        // Check odd divisors up to sqrt(n)
+        let sqrt_n = (n as f64).sqrt() as u64;
+        for i in (3..=sqrt_n).step_by(2) {
+            if n.is_multiple_of(i) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Deterministic Miller-Rabin, the bases below are enough for every u64.
+pub struct MillerRabin;
+
+const MILLER_RABIN_BASES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+impl PrimalityTest for MillerRabin {
+    fn is_prime(&self, n: u64) -> bool {
+        if n < 2 {
+            return false;
+        }
+        for p in MILLER_RABIN_BASES {
+            if n.is_multiple_of(p) {
+                return n == p;
+            }
+        }
+
+        // n - 1 = d * 2^s with d odd
+        let s = (n - 1).trailing_zeros();
+        let d = (n - 1) >> s;
+
+        'witness: for a in MILLER_RABIN_BASES {
+            let mut x = pow_mod(a, d, n);
+            if x == 1 || x == n - 1 {
+                continue;
+            }
+            for _ in 1..s {
+                x = mul_mod(x, x, n);
+                if x == n - 1 {
+                    continue 'witness;
+                }
+            }
+            return false;
+        }
+
+        true
+    }
+}
+
+fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    (a as u128 * b as u128 % m as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exp: u64, m: u64) -> u64 {
+    let mut result = 1;
+    base %= m;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_mod(result, base, m);
+        }
+        base = mul_mod(base, base, m);
+        exp >>= 1;
+    }
+    result
+}
+
+/// Miller-Rabin bases used beyond u64, deterministic below 3.3 * 10^24 and
+/// a strong probabilistic test above.
+const BIG_MILLER_RABIN_BASES: [u32; 20] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71,
+];
+
+/// Miller-Rabin for integers of any size.
+fn is_big_prime(n: &BigUint) -> bool {
+    let one = BigUint::from(1u32);
+    let two = BigUint::from(2u32);
+    if *n < two {
+        return false;
+    }
+    for p in BIG_MILLER_RABIN_BASES {
+        let p = BigUint::from(p);
+        if (n % &p) == BigUint::ZERO {
+            return *n == p;
+        }
+    }
+
+    // n - 1 = d * 2^s with d odd
+    let n_minus_one = n - &one;
+    let s = n_minus_one.trailing_zeros().unwrap_or(0);
+    let d = &n_minus_one >> s;
+
+    'witness: for a in BIG_MILLER_RABIN_BASES {
+        let mut x = BigUint::from(a).modpow(&d, n);
+        if x == one || x == n_minus_one {
+            continue;
+        }
+        for _ in 1..s {
+            x = x.modpow(&two, n);
+            if x == n_minus_one {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+
+    true
+}
+
+/// Selects the `PrimalityTest` used by the server.
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum PrimeStrategy {
+    #[default]
+    TrialDivision,
+    MillerRabin,
+}
+
+impl PrimeStrategy {
+    fn tester(self) -> &'static dyn PrimalityTest {
+        match self {
+            PrimeStrategy::TrialDivision => &TrialDivision,
+            PrimeStrategy::MillerRabin => &MillerRabin,
+        }
+    }
+}
+
+pub async fn handle_client(mut socket: TcpStream, strategy: PrimeStrategy) -> Result<()> {
     let (input_stream, output_stream) = socket.split();
-    let _ = handle_client_internal(input_stream, output_stream).await?;
+    handle_client_internal(input_stream, output_stream, strategy.tester()).await?;
 
     Ok(())
 }
 
+/// How many requests of one connection may be checked at the same time,
+/// the connection is not read further until the oldest one is answered.
+const MAX_PIPELINED_REQUESTS: usize = 64;
+
+/// The answer to one request line, in the order the lines were received.
+enum Reply {
+    Prime(bool),
+    Malformed,
+}
+
+/// Requests are checked concurrently on the blocking pool, so one slow check does not
+/// hold back the next ones, but responses are always written in request order.
 async fn handle_client_internal(
     input_stream: impl AsyncRead + Unpin,
     mut output_stream: impl AsyncWrite + Unpin,
+    tester: &'static dyn PrimalityTest,
 ) -> Result<()> {
     let input_stream = BufReader::new(input_stream);
     // review: read line from bytes stream
     let mut lines = input_stream.lines();
-    while let Some(line) = lines.next_line().await? {
-        // review: deserilize line into struct object
-        match serde_json::from_str::<Request>(&line) {
-            Ok(req) => {
-                let response = Response::new(is_prime(req.number));
-                // review: serialize struct into bytes
-                let mut bytes = serde_json::to_vec(&response)?;
-                bytes.push(b'\n');
-                output_stream.write_all(&bytes).await?;
-                output_stream.flush().await?;
-            }
-            Err(e) => {
-                error!("malformed request: {}", e);
-                output_stream.write_all(b"malformed\n").await?;
+    // re-sequences checks which complete out of order
+    let mut pending: FuturesOrdered<BoxFuture<'static, Result<Reply>>> = FuturesOrdered::new();
+    let mut input_done = false;
 
-                break;
+    loop {
+        tokio::select! {
+            line = lines.next_line(), if !input_done && pending.len() < MAX_PIPELINED_REQUESTS => {
+                let Some(line) = line? else {
+                    input_done = true;
+                    continue;
+                };
+                // review: deserilize line into struct object
+                match serde_json::from_str::<Request>(&line) {
+                    Ok(req) => {
+                        let check = tokio::task::spawn_blocking(move || {
+                            is_prime_number(&req.number, tester)
+                        });
+                        pending.push_back(Box::pin(async move {
+                            let prime = check.await.map_err(|e| Error::Other(e.to_string()))?;
+                            Ok(Reply::Prime(prime))
+                        }));
+                    }
+                    Err(e) => {
+                        error!("malformed request: {}", e);
+                        // answered once every earlier request is, then the connection ends
+                        pending.push_back(Box::pin(async { Ok(Reply::Malformed) }));
+                        input_done = true;
+                    }
+                }
             }
+            Some(reply) = pending.next() => match reply? {
+                Reply::Prime(prime) => {
+                    // review: serialize struct into bytes
+                    let mut bytes = serde_json::to_vec(&Response::new(prime))?;
+                    bytes.push(b'\n');
+                    output_stream.write_all(&bytes).await?;
+                    output_stream.flush().await?;
+                }
+                Reply::Malformed => {
+                    output_stream.write_all(b"malformed\n").await?;
+                    break;
+                }
+            },
+            else => break,
         }
     }
     Ok(())
 }
 
-fn is_prime(n: f64) -> bool {
-    // Handle non-integer values
-    if n.fract() != 0.0 {
-        return false;
-    }
+/// Above 2^53 a f64 can only hold even integers, so none of them is prime.
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
 
-    // Convert to integer (safe since we've checked it's a whole number)
-    let n_int = n as i64;
+#[cfg(test)]
+fn is_prime(n: f64) -> bool {
+    is_prime_with(n, &TrialDivision)
+}
 
-    // Handle numbers less than 2
-    if n_int < 2 {
-        return false;
+/// Integers are checked exactly whatever their size, floats go through `is_prime_with`.
+fn is_prime_number(n: &JsonNumber, tester: &dyn PrimalityTest) -> bool {
+    // an integer is written with digits only, a float has a fraction or an exponent
+    let (negative, digits) = match n.0.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, n.0.as_str()),
+    };
+    if digits.bytes().all(|b| b.is_ascii_digit()) {
+        // negative numbers are not prime
+        if negative {
+            return false;
+        }
+        return match digits.parse::<u64>() {
+            Ok(n) => tester.is_prime(n),
+            Err(_) => digits.parse::<BigUint>().is_ok_and(|n| is_big_prime(&n)),
+        };
     }
+    n.0.parse::<f64>().is_ok_and(|n| is_prime_with(n, tester))
+}
 
-    // Handle small primes
-    if n_int == 2 {
-        return true;
+fn is_prime_with(n: f64, tester: &dyn PrimalityTest) -> bool {
+    // Handle non-integer values (NaN and infinities included)
+    if n.fract() != 0.0 {
+        return false;
     }
 
-    // Even numbers greater than 2 are not prime
-    if n_int % 2 == 0 {
+    // Handle values too large to be cast into i64 safely
+    if n.abs() >= MAX_EXACT_INTEGER {
         return false;
     }
 
-    // Check odd divisors up to sqrt(n)
-    let sqrt_n = (n_int as f64).sqrt() as i64;
-    for i in (3..=sqrt_n).step_by(2) {
-        if n_int % i == 0 {
-            return false;
-        }
+    // Negative numbers are not prime
+    if n < 0.0 {
+        return false;
     }
 
-    true
+    // Convert to integer (safe since we've checked it's a whole number in range)
+    tester.is_prime(n as u64)
 }
 
 #[cfg(test)]
@@ -139,12 +373,168 @@ mod tests {
         assert_eq!(is_prime(1.0), false);
     }
 
+    #[test]
+    fn test_is_prime_out_of_i64_range() {
+        assert_eq!(is_prime(1e300), false);
+        assert_eq!(is_prime(-1e300), false);
+        // 2^63 and the next representable f64 above it
+        assert_eq!(is_prime(9_223_372_036_854_775_808.0), false);
+        assert_eq!(is_prime(9_223_372_036_854_777_856.0), false);
+        assert_eq!(is_prime(-9_223_372_036_854_777_856.0), false);
+        assert_eq!(is_prime(f64::INFINITY), false);
+        assert_eq!(is_prime(f64::NAN), false);
+    }
+
+    #[test]
+    fn strategies_agree() {
+        for n in 0..100_000 {
+            assert_eq!(
+                TrialDivision.is_prime(n),
+                MillerRabin.is_prime(n),
+                "strategies disagree on {}",
+                n
+            );
+        }
+
+        // large primes, composites and strong pseudoprimes to small bases
+        let large = [
+            (1_000_000_007, true),
+            (999_999_999_989, true),
+            (3 * 1_000_000_007, false),
+            (1_000_003 * 1_000_033, false),
+            (3_215_031_751, false),
+            (2_152_302_898_747, false),
+        ];
+        for (n, prime) in large {
+            assert_eq!(TrialDivision.is_prime(n), prime, "trial division on {}", n);
+            assert_eq!(MillerRabin.is_prime(n), prime, "miller-rabin on {}", n);
+        }
+    }
+
+    fn number(json: &str) -> JsonNumber {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn integers_beyond_f64_precision_are_checked_exactly() {
+        for tester in [&TrialDivision as &dyn PrimalityTest, &MillerRabin] {
+            // 10^39 + 3
+            let prime = number("1000000000000000000000000000000000000003");
+            assert!(is_prime_number(&prime, tester));
+            // 10000000000000000051 * 100000000000000000039
+            let composite = number("1000000000000000005490000000000000001989");
+            assert!(!is_prime_number(&composite, tester));
+            // strong pseudoprime to every prime base up to 37
+            let pseudoprime = number("318665857834031151167461");
+            assert!(!is_prime_number(&pseudoprime, tester));
+            assert!(!is_prime_number(
+                &number("100000000000000000000000"),
+                tester
+            ));
+            assert!(!is_prime_number(
+                &number("-1000000000000000000000000000000000000003"),
+                tester
+            ));
+
+            assert!(!is_prime_number(&number("3.5"), tester));
+            assert!(is_prime_number(&number("7"), tester));
+            assert!(is_prime_number(&number("7.0"), tester));
+            assert!(!is_prime_number(&number("-7"), tester));
+            assert!(!is_prime_number(&number("1e300"), tester));
+        }
+    }
+
+    #[tokio::test]
+    async fn big_integer_request_gets_an_exact_answer() {
+        let input = concat!(
+            r#"{"method":"isPrime","number":1000000000000000000000000000000000000003}"#,
+            "\n",
+            r#"{"method":"isPrime","number":3.5}"#,
+            "\n",
+            r#"{"method":"isPrime","number":"7"}"#,
+            "\n",
+        );
+        let mut output: Vec<u8> = vec![];
+
+        handle_client_internal(input.as_bytes(), &mut output, &TrialDivision)
+            .await
+            .expect("Failed to handle");
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                r#"{"method":"isPrime","prime":true}"#,
+                "\n",
+                r#"{"method":"isPrime","prime":false}"#,
+                "\n",
+                "malformed\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn pipelined_responses_keep_request_order() {
+        // 100000007 * 99999989 takes trial division a while, the others are instant
+        let input = concat!(
+            r#"{"method":"isPrime","number":9999999599999923}"#,
+            "\n",
+            r#"{"method":"isPrime","number":7}"#,
+            "\n",
+            r#"{"method":"isPrime","number":8}"#,
+            "\n",
+        );
+        let mut output: Vec<u8> = vec![];
+
+        handle_client_internal(input.as_bytes(), &mut output, &TrialDivision)
+            .await
+            .expect("Failed to handle");
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                r#"{"method":"isPrime","prime":false}"#,
+                "\n",
+                r#"{"method":"isPrime","prime":true}"#,
+                "\n",
+                r#"{"method":"isPrime","prime":false}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn response_serializes_exactly_method_then_prime() {
+        assert_eq!(
+            serde_json::to_vec(&Response::new(true)).unwrap(),
+            br#"{"method":"isPrime","prime":true}"#
+        );
+        assert_eq!(
+            serde_json::to_vec(&Response::new(false)).unwrap(),
+            br#"{"method":"isPrime","prime":false}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn extra_request_fields_are_ignored_and_not_echoed() {
+        let input = concat!(
+            r#"{"number":7,"id":1,"method":"isPrime","extra":{"nested":[1,2]}}"#,
+            "\n",
+        );
+        let mut output: Vec<u8> = vec![];
+
+        handle_client_internal(input.as_bytes(), &mut output, &TrialDivision)
+            .await
+            .expect("Failed to handle");
+
+        assert_eq!(output, b"{\"method\":\"isPrime\",\"prime\":true}\n");
+    }
+
     #[tokio::test]
     async fn prime_time_test_malformed() {
         let input = "{}\n";
         let mut output: Vec<u8> = vec![];
 
-        handle_client_internal(input.as_bytes(), &mut output)
+        handle_client_internal(input.as_bytes(), &mut output, &TrialDivision)
             .await
             .expect("Failed to handle");
 
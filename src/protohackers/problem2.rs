@@ -1,14 +1,21 @@
-use std::{collections::BTreeMap, io::ErrorKind, ops::RangeInclusive};
+use std::{collections::BTreeMap, io::ErrorKind, ops::RangeInclusive, time::Duration};
 
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::TcpStream,
 };
 
-use super::HOST;
+use super::{ServerBuilder, read_message_with_timeout};
 use crate::{Error, Result};
+use tracing::warn;
 
-struct Db(BTreeMap<i32, i32>);
+// A client which doesn't send a full message within this duration is disconnected.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Prices keyed by timestamp.
+// The spec leaves duplicate timestamps undefined, we keep every price inserted
+// at a timestamp so all of them count toward the mean.
+struct Db(BTreeMap<i32, Vec<i32>>);
 
 impl Db {
     fn new() -> Db {
@@ -16,7 +23,7 @@ impl Db {
     }
 
     fn insert(&mut self, timestamp: i32, price: i32) {
-        self.0.insert(timestamp, price);
+        self.0.entry(timestamp).or_default().push(price);
     }
 
     pub fn mean(&self, range: RangeInclusive<i32>) -> i32 {
@@ -26,26 +33,46 @@ impl Db {
         let (count, sum) = self
             .0
             .range(range)
-            .fold((0, 0_i64), |(count, sum), (_index, v)| {
-                (count + 1, sum + *v as i64)
-            });
+            .flat_map(|(_timestamp, prices)| prices)
+            .fold((0, 0_i64), |(count, sum), v| (count + 1, sum + *v as i64));
 
         if count > 0 { (sum / count) as i32 } else { 0 }
     }
-}
 
-pub async fn run(port: u32) -> Result<()> {
-    let address = format!("{HOST}:{port}");
-    let listener = TcpListener::bind(address.clone()).await?;
-    loop {
-        let (socket, _addr) = listener.accept().await?;
-        tokio::spawn(handle_client(socket));
+    /// Nearest-rank percentile (0 to 100) of the prices in `range`, 0 when there is none.
+    /// The prices of the range are collected and partially sorted, so a query costs
+    /// O(k) time and memory for k prices in the range.
+    pub fn percentile(&self, range: RangeInclusive<i32>, percentile: u8) -> i32 {
+        if range.is_empty() {
+            return 0;
+        };
+        let mut prices: Vec<i32> = self
+            .0
+            .range(range)
+            .flat_map(|(_timestamp, prices)| prices)
+            .copied()
+            .collect();
+        if prices.is_empty() {
+            return 0;
+        }
+
+        // smallest price with at least `percentile`% of the prices less than or equal to it
+        let rank = (percentile as usize * prices.len()).div_ceil(100).max(1);
+        *prices.select_nth_unstable(rank - 1).1
     }
 }
 
-async fn handle_client(mut socket: TcpStream) -> Result<()> {
+/// Serve the prices service. A message with an unknown op code closes the connection,
+/// unless `lenient` is set, in which case it is skipped and the next one is read.
+pub async fn run(server: ServerBuilder, lenient: bool) -> Result<()> {
+    server
+        .run(move |socket| handle_client(socket, lenient))
+        .await
+}
+
+async fn handle_client(mut socket: TcpStream, lenient: bool) -> Result<()> {
     let (input_stream, output_stream) = socket.split();
-    let _ = handle_client_internal(input_stream, output_stream).await?;
+    handle_client_internal(input_stream, output_stream, READ_TIMEOUT, lenient).await?;
 
     Ok(())
 }
@@ -53,35 +80,93 @@ async fn handle_client(mut socket: TcpStream) -> Result<()> {
 async fn handle_client_internal(
     mut input_stream: impl AsyncRead + Unpin,
     mut output_stream: impl AsyncWrite + Unpin,
+    read_timeout: Duration,
+    lenient: bool,
 ) -> Result<()> {
     let mut db = Db::new();
     let mut buffer = [0u8; 9];
 
-    loop {
-        // review: read from stream for exact n bytes
-        match input_stream.read_exact(&mut buffer).await {
-            Ok(_n) => {
-                let message = Message::parse(&buffer)?;
-                match message {
-                    Message::Insert { timestamp, price } => {
-                        db.insert(timestamp, price);
-                    }
-                    Message::Query { mintime, maxtime } => {
-                        let mean = db.mean(mintime..=maxtime);
-                        output_stream.write_all(&mean.to_be_bytes()).await?;
-                    }
+    // review: read from stream for exact n bytes
+    while read_with_timeout(&mut input_stream, &mut buffer, read_timeout).await? {
+        let message = if buffer[0] == b'P' {
+            // a percentile query carries one more byte, the percentile
+            let mut percentile = [0u8; 1];
+            if !read_with_timeout(&mut input_stream, &mut percentile, read_timeout).await? {
+                break;
+            }
+            Message::parse_percentile(&buffer, percentile[0])?
+        } else {
+            match Message::parse(&buffer) {
+                Err(Error::UnknownOpCode(op)) if lenient => {
+                    warn!("skip message with unknown op code {}", op);
+                    continue;
                 }
+                message => message?,
+            }
+        };
+        match message {
+            Message::Insert { timestamp, price } => {
+                db.insert(timestamp, price);
+            }
+            // an inverted range holds no price, answered without building the range
+            Message::Query { mintime, maxtime }
+            | Message::Percentile {
+                mintime, maxtime, ..
+            } if mintime > maxtime => {
+                output_stream.write_all(&0i32.to_be_bytes()).await?;
+            }
+            Message::Query { mintime, maxtime } => {
+                let mean = db.mean(mintime..=maxtime);
+                output_stream.write_all(&mean.to_be_bytes()).await?;
+            }
+            Message::Percentile {
+                mintime,
+                maxtime,
+                percentile,
+            } => {
+                let value = db.percentile(mintime..=maxtime, percentile);
+                output_stream.write_all(&value.to_be_bytes()).await?;
             }
-            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
-            Err(e) => return Err(Error::Io(e)),
         }
     }
+    Ok(())
+}
+
+/// Fill `buffer` from `input`, returns `false` when the client closed the connection
+/// or did not send the bytes within `read_timeout`.
+async fn read_with_timeout(
+    input: &mut (impl AsyncRead + Unpin),
+    buffer: &mut [u8],
+    read_timeout: Duration,
+) -> Result<bool> {
+    let Some(read_result) = read_message_with_timeout(read_timeout, input.read_exact(buffer)).await
+    else {
+        return Ok(false);
+    };
+
+    match read_result {
+        Ok(_n) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(Error::Io(e)),
+    }
 }
 
 #[derive(Debug, PartialEq)]
 enum Message {
-    Insert { price: i32, timestamp: i32 },
-    Query { mintime: i32, maxtime: i32 },
+    Insert {
+        price: i32,
+        timestamp: i32,
+    },
+    Query {
+        mintime: i32,
+        maxtime: i32,
+    },
+    /// Extension of the protocol: `P`, mintime, maxtime, then a single byte percentile.
+    Percentile {
+        mintime: i32,
+        maxtime: i32,
+        percentile: u8,
+    },
 }
 
 impl Message {
@@ -100,9 +185,30 @@ impl Message {
                 mintime: first,
                 maxtime: second,
             }),
-            _ => Err(Error::InvalidProtocol(format!("unexpected op code {}", op))),
+            _ => Err(Error::UnknownOpCode(op)),
         }
     }
+
+    /// Parse a percentile query, made of a regular 9 bytes message and the percentile.
+    pub fn parse_percentile(buffer: &[u8; 9], percentile: u8) -> Result<Self> {
+        if buffer[0] != b'P' {
+            return Err(Error::InvalidProtocol(format!(
+                "unexpected op code {}",
+                buffer[0]
+            )));
+        }
+        if percentile > 100 {
+            return Err(Error::InvalidProtocol(format!(
+                "percentile {} is above 100",
+                percentile
+            )));
+        }
+        Ok(Message::Percentile {
+            mintime: i32::from_be_bytes(buffer[1..5].try_into()?),
+            maxtime: i32::from_be_bytes(buffer[5..9].try_into()?),
+            percentile,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -156,6 +262,193 @@ mod tests {
         )
     }
 
+    /// Insert a price, an unknown `X` message, then query the price back.
+    async fn session_with_unknown_op_code() -> Vec<u8> {
+        vec![
+            create_message(b'I', 100, 42).await,
+            create_message(b'X', 1, 2).await,
+            create_message(b'Q', 0, 200).await,
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn unknown_op_code_is_a_typed_error() {
+        let buffer = create_message(b'X', 1, 2).await;
+        assert!(matches!(
+            Message::parse(&buffer),
+            Err(Error::UnknownOpCode(b'X'))
+        ));
+    }
+
+    #[tokio::test]
+    async fn strict_session_ends_at_unknown_op_code() {
+        let messages = session_with_unknown_op_code().await;
+
+        let mut output = vec![];
+        let result =
+            handle_client_internal(messages.as_slice(), &mut output, READ_TIMEOUT, false).await;
+
+        assert!(matches!(result, Err(Error::UnknownOpCode(b'X'))));
+        assert!(output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn lenient_session_skips_unknown_op_code() {
+        let messages = session_with_unknown_op_code().await;
+
+        let mut output = vec![];
+        handle_client_internal(messages.as_slice(), &mut output, READ_TIMEOUT, true)
+            .await
+            .unwrap();
+
+        assert_eq!(output, 42i32.to_be_bytes());
+    }
+
+    #[test]
+    fn mean_single_element_range() {
+        let mut db = Db::new();
+        db.insert(100, 42);
+        db.insert(101, 1000);
+
+        assert_eq!(42, db.mean(100..=100));
+    }
+
+    #[test]
+    fn mean_single_element_range_without_data() {
+        let mut db = Db::new();
+        db.insert(100, 42);
+
+        assert_eq!(0, db.mean(99..=99));
+    }
+
+    #[test]
+    fn mean_duplicate_timestamp_keeps_all_prices() {
+        let mut db = Db::new();
+        db.insert(100, 10);
+        db.insert(100, 30);
+
+        assert_eq!(20, db.mean(100..=100));
+    }
+
+    #[test]
+    fn mean_range_covering_duplicate_timestamp() {
+        let mut db = Db::new();
+        db.insert(99, 10);
+        db.insert(100, 20);
+        db.insert(100, 60);
+        db.insert(200, 1000);
+
+        assert_eq!(30, db.mean(0..=150));
+    }
+
+    /// Nearest-rank percentile over a fully sorted copy.
+    fn reference_percentile(prices: &[i32], percentile: u8) -> i32 {
+        let mut sorted = prices.to_vec();
+        sorted.sort();
+        let rank = (percentile as f64 / 100.0 * sorted.len() as f64).ceil() as usize;
+        sorted[rank.max(1) - 1]
+    }
+
+    #[test]
+    fn percentile_matches_reference() {
+        let mut db = Db::new();
+        // shuffled prices, a duplicate timestamp and data outside the range
+        let prices: Vec<i32> = (1..=40).map(|i| (i * 37) % 101 - 20).collect();
+        for (i, price) in prices.iter().enumerate() {
+            db.insert(i as i32 / 2, *price);
+        }
+        db.insert(1000, i32::MAX);
+
+        for percentile in [0, 50, 95, 100] {
+            assert_eq!(
+                db.percentile(0..=100, percentile),
+                reference_percentile(&prices, percentile),
+                "p{}",
+                percentile
+            );
+        }
+        assert_eq!(db.percentile(2000..=3000, 50), 0);
+    }
+
+    #[tokio::test]
+    async fn percentile_query_session() {
+        let mut messages = vec![];
+        for price in 1..=100 {
+            messages.extend(create_message(b'I', price, price).await);
+        }
+        messages.extend(create_message(b'P', 1, 100).await);
+        messages.push(50);
+        messages.extend(create_message(b'P', 1, 100).await);
+        messages.push(95);
+        messages.extend(create_message(b'Q', 1, 100).await);
+
+        let mut output = vec![];
+        handle_client_internal(messages.as_slice(), &mut output, READ_TIMEOUT, false)
+            .await
+            .unwrap();
+
+        let values: Vec<i32> = output
+            .chunks(4)
+            .map(|chunk| i32::from_be_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(values, vec![50, 95, 50]);
+    }
+
+    #[tokio::test]
+    async fn percentile_above_100_is_rejected() {
+        let buffer = create_message(b'P', 1, 100).await;
+        assert!(Message::parse_percentile(&buffer, 101).is_err());
+    }
+
+    #[tokio::test]
+    async fn duplicate_timestamp_session_counts_both_prices() {
+        let messages = vec![
+            create_message(b'I', 1000, 10).await,
+            create_message(b'I', 1000, 40).await,
+            create_message(b'I', 2000, 100).await,
+            create_message(b'Q', 0, 1500).await,
+            create_message(b'Q', 0, 2000).await,
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<u8>>();
+
+        let mut output = vec![];
+        handle_client_internal(messages.as_slice(), &mut output, READ_TIMEOUT, false)
+            .await
+            .unwrap();
+
+        assert_eq!(8, output.len());
+        assert_eq!(25, i32::from_be_bytes(output[..4].try_into().unwrap()));
+        assert_eq!(50, i32::from_be_bytes(output[4..].try_into().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn inverted_query_range_answers_zero() {
+        let mut messages = vec![];
+        messages.extend(create_message(b'I', 100, 10).await);
+        messages.extend(create_message(b'I', 200, 20).await);
+        messages.extend(create_message(b'Q', 200, 100).await);
+        messages.extend(create_message(b'Q', i32::MAX, i32::MIN).await);
+        messages.extend(create_message(b'P', 200, 100).await);
+        messages.push(50);
+        messages.extend(create_message(b'Q', 100, 200).await);
+
+        let mut output = vec![];
+        handle_client_internal(messages.as_slice(), &mut output, READ_TIMEOUT, false)
+            .await
+            .unwrap();
+
+        let values: Vec<i32> = output
+            .chunks(4)
+            .map(|chunk| i32::from_be_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(values, vec![0, 0, 0, 15]);
+    }
+
     #[tokio::test]
     async fn example_session_test() {
         let messages = vec![
@@ -171,11 +464,27 @@ mod tests {
 
         let mut output = vec![];
 
-        handle_client_internal(messages.as_slice(), &mut output)
+        handle_client_internal(messages.as_slice(), &mut output, READ_TIMEOUT, false)
             .await
             .unwrap();
 
         assert_eq!(4, output.len());
         assert_eq!(101, i32::from_be_bytes(output[..4].try_into().unwrap()));
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_client_is_disconnected_after_read_timeout() {
+        // keep the client half alive, but never write a full message
+        let (mut client, server) = tokio::io::duplex(64);
+        client.write_u8(b'I').await.unwrap();
+
+        let (input, output) = tokio::io::split(server);
+        let start = tokio::time::Instant::now();
+
+        handle_client_internal(input, output, Duration::from_secs(5), false)
+            .await
+            .unwrap();
+
+        assert!(start.elapsed() >= Duration::from_secs(5));
+    }
 }
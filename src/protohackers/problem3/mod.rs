@@ -5,4 +5,14 @@ mod server;
 mod user;
 
 pub use example_ex::*;
-pub use server::run;
+pub use protocol::{ChatEncoding, ChatFormat, ChatStyle};
+pub use room::{DEFAULT_ROOM_CAPACITY, RoomConfig};
+// the room on its own, e.g. for benches/chat_broadcast.rs
+#[allow(unused)]
+pub use protocol::{ChatText, ClientId, Username};
+// `Room` is the example's shared state
+#[allow(unused)]
+pub use room::Room as ChatRoom;
+pub use server::{ChatConfig, DEFAULT_MAX_MESSAGE_LENGTH, DEFAULT_MESSAGES_PER_SECOND, run};
+#[allow(unused)]
+pub use user::UserHandle;
@@ -1,6 +1,7 @@
 use crate::{Error, Result};
-use core::net::SocketAddr;
-use tokio_util::codec::{Decoder, Encoder, LinesCodec};
+use bytes::{Buf, BufMut, BytesMut};
+use core::net::{IpAddr, SocketAddr};
+use tokio_util::codec::{Decoder, Encoder};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ClientId {
@@ -11,6 +12,10 @@ impl ClientId {
     pub fn new(id: SocketAddr) -> Self {
         Self { id }
     }
+
+    pub fn ip(&self) -> IpAddr {
+        self.id.ip()
+    }
 }
 
 #[derive(derive_more::Display, Clone, Debug, PartialEq, Eq, Hash)]
@@ -35,31 +40,283 @@ impl Username {
     }
 }
 
+/// The text of a chat message, relayed to other users byte for byte.
+/// It is only guaranteed to be UTF-8 under `ChatEncoding::Utf8`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChatText(pub Vec<u8>);
+
+impl std::fmt::Display for ChatText {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl From<&str> for ChatText {
+    fn from(text: &str) -> Self {
+        ChatText(text.as_bytes().to_vec())
+    }
+}
+
 // represent all avaliable messages send to client
 #[derive(derive_more::Display, Clone, Debug, PartialEq)]
 pub enum OutgoingMessage {
     #[display("[{}] {}", from, text)]
-    Chat { from: Username, text: String },
+    Chat { from: Username, text: ChatText },
     #[display("* {} has entered the room", _0)]
     UserJoin(Username),
     #[display("* {} has left the room", _0)]
     UserLeave(Username),
     #[display("Welcome to budgetchat! What shall I call you?")]
     Welcome,
-    #[display("* The room contains: {}", "self.participants(_0)")]
+    #[display("* The room contains: {}", join_usernames(_0))]
     Participants(Vec<Username>),
     #[display("Invalid username {}", _0)]
     InvalidUsername(String),
+    #[display("* You are joining too often, try again later")]
+    JoinRateLimited,
+    #[display("* The room is full, try again later")]
+    RoomFull,
+    #[display("* This name is already taken")]
+    NameTaken,
+    #[display("* Message too long, at most {} bytes", _0)]
+    MessageTooLong(usize),
+    #[display("* You are sending messages too fast, message dropped")]
+    MessageRateLimited,
+    #[display("This room is password protected, what is the password?")]
+    PasswordPrompt,
+    #[display("Wrong password")]
+    WrongPassword,
+    /// Sent during quiet periods so the client can tell the connection is still alive.
+    #[display("* keepalive")]
+    Keepalive,
+}
+
+/// Newline delimited frames of arbitrary bytes, the `\n` is not part of the frame.
+/// Unlike `LinesCodec` it neither validates UTF-8 nor strips a trailing `\r`.
+/// A line over `max_length` bytes is an `Error::LineTooLong`, its rest is skipped.
+#[derive(Debug)]
+pub struct RawLinesCodec {
+    /// Where to look for the next `\n`, the bytes before it were already searched.
+    next_index: usize,
+    max_length: usize,
+    /// Skipping the rest of a too long line.
+    is_discarding: bool,
+}
+
+impl RawLinesCodec {
+    pub fn new(max_length: usize) -> Self {
+        Self {
+            next_index: 0,
+            max_length,
+            is_discarding: false,
+        }
+    }
+}
+
+impl Decoder for RawLinesCodec {
+    type Item = Vec<u8>;
+    type Error = crate::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        loop {
+            // no need to look further than one byte over the limit
+            let read_to = src.len().min(self.max_length.saturating_add(1));
+            let newline = src[self.next_index..read_to]
+                .iter()
+                .position(|b| *b == b'\n')
+                .map(|offset| self.next_index + offset);
+
+            match (self.is_discarding, newline) {
+                (true, Some(newline)) => {
+                    src.advance(newline + 1);
+                    self.is_discarding = false;
+                    self.next_index = 0;
+                }
+                (true, None) => {
+                    src.advance(read_to);
+                    self.next_index = 0;
+                    if src.is_empty() {
+                        return Ok(None);
+                    }
+                }
+                (false, Some(newline)) => {
+                    self.next_index = 0;
+                    let line = src.split_to(newline).to_vec();
+                    src.advance(1);
+                    return Ok(Some(line));
+                }
+                (false, None) if src.len() > self.max_length => {
+                    self.is_discarding = true;
+                    return Err(Error::LineTooLong(self.max_length));
+                }
+                (false, None) => {
+                    self.next_index = read_to;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        // a last line without newline is still a line
+        match self.decode(src)? {
+            Some(line) => Ok(Some(line)),
+            None if src.is_empty() => Ok(None),
+            None => {
+                self.next_index = 0;
+                Ok(Some(src.split().to_vec()))
+            }
+        }
+    }
+}
+
+impl Encoder<&[u8]> for RawLinesCodec {
+    type Error = crate::Error;
+
+    fn encode(&mut self, line: &[u8], dst: &mut BytesMut) -> Result<()> {
+        dst.reserve(line.len() + 1);
+        dst.put_slice(line);
+        dst.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+/// How the chat treats the bytes of a line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum ChatEncoding {
+    /// Lines must be UTF-8, a trailing `\r` is dropped like `LinesCodec` does.
+    #[default]
+    Utf8,
+    /// Lines are relayed as raw bytes, for clients using other encodings.
+    Raw,
+}
+
+/// The line formats which can be picked from the command line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum ChatStyle {
+    /// The lines the server has always sent, see `ChatFormat::default`.
+    #[default]
+    Legacy,
+    /// The protohackers lines, the participant line lists the names.
+    Protohackers,
+}
+
+/// Templates of the lines the room sends, with named placeholders:
+/// `{username}` and `{text}` for chat, `{username}` for join and leave,
+/// `{users}` (comma separated) for the participant list.
+/// The default sends the same lines as before the templates: the participant
+/// line never had the names filled in, `ChatFormat::protohackers` lists them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatFormat {
+    pub chat: String,
+    pub join: String,
+    pub leave: String,
+    pub participants: String,
+}
+
+impl Default for ChatFormat {
+    fn default() -> Self {
+        Self {
+            participants: "* The room contains: self.participants(_0)".into(),
+            ..Self::protohackers()
+        }
+    }
+}
+
+impl From<ChatStyle> for ChatFormat {
+    fn from(style: ChatStyle) -> Self {
+        match style {
+            ChatStyle::Legacy => Self::default(),
+            ChatStyle::Protohackers => Self::protohackers(),
+        }
+    }
+}
+
+impl ChatFormat {
+    pub fn protohackers() -> Self {
+        Self {
+            chat: "[{username}] {text}".into(),
+            join: "* {username} has entered the room".into(),
+            leave: "* {username} has left the room".into(),
+            participants: "* The room contains: {users}".into(),
+        }
+    }
+
+    /// The line for `message`, without newline. Lines without a template use `Display`.
+    pub fn render(&self, message: &OutgoingMessage) -> Vec<u8> {
+        match message {
+            // the text goes out exactly as it came in,
+            // it is spliced in last so a `{username}` typed in it stays as is
+            OutgoingMessage::Chat { from, text } => {
+                let mut parts = self.chat.split("{text}");
+                let from = from.to_string();
+                let mut line = parts
+                    .next()
+                    .unwrap_or_default()
+                    .replace("{username}", &from)
+                    .into_bytes();
+                for part in parts {
+                    line.extend_from_slice(&text.0);
+                    line.extend_from_slice(part.replace("{username}", &from).as_bytes());
+                }
+                line
+            }
+            OutgoingMessage::UserJoin(username) => self
+                .join
+                .replace("{username}", &username.to_string())
+                .into_bytes(),
+            OutgoingMessage::UserLeave(username) => self
+                .leave
+                .replace("{username}", &username.to_string())
+                .into_bytes(),
+            OutgoingMessage::Participants(users) => self
+                .participants
+                .replace("{users}", &join_usernames(users))
+                .into_bytes(),
+            other => other.to_string().into_bytes(),
+        }
+    }
+}
+
+fn join_usernames(users: &[Username]) -> String {
+    users
+        .iter()
+        .map(|user| user.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 pub struct ChatCodec {
-    lines: LinesCodec,
+    lines: RawLinesCodec,
+    encoding: ChatEncoding,
+    format: ChatFormat,
 }
 
 impl ChatCodec {
-    pub fn new() -> Self {
+    #[cfg(test)]
+    pub fn with_encoding(encoding: ChatEncoding) -> Self {
+        Self::new(encoding, ChatFormat::default(), usize::MAX)
+    }
+
+    /// Lines over `max_length` bytes are refused with `Error::LineTooLong`.
+    pub fn new(encoding: ChatEncoding, format: ChatFormat, max_length: usize) -> Self {
         Self {
-            lines: LinesCodec::new(),
+            lines: RawLinesCodec::new(max_length),
+            encoding,
+            format,
+        }
+    }
+
+    fn check_encoding(&self, mut line: Vec<u8>) -> Result<Vec<u8>> {
+        match self.encoding {
+            ChatEncoding::Utf8 => {
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                std::str::from_utf8(&line).map_err(|e| Error::Other(e.to_string()))?;
+                Ok(line)
+            }
+            ChatEncoding::Raw => Ok(line),
         }
     }
 }
@@ -67,20 +324,27 @@ impl ChatCodec {
 impl Encoder<OutgoingMessage> for ChatCodec {
     type Error = crate::Error;
 
-    fn encode(&mut self, item: OutgoingMessage, dst: &mut bytes::BytesMut) -> Result<()> {
-        self.lines
-            .encode(item.to_string(), dst)
-            .map_err(|e| Error::Other(e.to_string()))
+    fn encode(&mut self, item: OutgoingMessage, dst: &mut BytesMut) -> Result<()> {
+        let line = self.format.render(&item);
+        self.lines.encode(line.as_slice(), dst)
     }
 }
 
 impl Decoder for ChatCodec {
-    type Item = String;
+    type Item = Vec<u8>;
     type Error = crate::Error;
 
-    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>> {
-        self.lines
-            .decode(src)
-            .map_err(|e| Error::Other(e.to_string()))
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        match self.lines.decode(src)? {
+            Some(line) => self.check_encoding(line).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        match self.lines.decode_eof(src)? {
+            Some(line) => self.check_encoding(line).map(Some),
+            None => Ok(None),
+        }
     }
 }
@@ -3,25 +3,103 @@ use super::user::User;
 use super::user::UserHandle;
 use crate::{Error, Result};
 use std::collections::HashMap;
-use tokio::sync::mpsc;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::info;
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum RoomMessage {
-    Chat { from: ClientId, text: String },
-    UserJoin { client_id: ClientId, user: User },
-    UserLeave { client_id: ClientId },
+    Chat {
+        from: ClientId,
+        text: ChatText,
+    },
+    UserJoin {
+        client_id: ClientId,
+        user: User,
+    },
+    UserLeave {
+        client_id: ClientId,
+    },
+    /// Ask for the names of everyone in the room except `from`.
+    ListUsers {
+        from: ClientId,
+        reply: oneshot::Sender<Vec<Username>>,
+    },
+    /// Ask for a `guestN` name nobody in the room holds.
+    GuestName {
+        reply: oneshot::Sender<Username>,
+    },
 }
 
 #[derive(Debug, Clone)]
 pub struct Room {
     sender: mpsc::UnboundedSender<RoomMessage>,
+    /// The queue of a room created with `Room::detached`, which no task consumes.
+    #[cfg(test)]
+    pending: Option<std::sync::Arc<std::sync::Mutex<mpsc::UnboundedReceiver<RoomMessage>>>>,
+}
+
+/// How many users a room holds unless configured otherwise.
+pub const DEFAULT_ROOM_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct RoomConfig {
+    /// Minimum time between two joins from the same IP, a faster join is refused.
+    /// It stops a client from spamming join/leave broadcasts by reconnecting rapidly.
+    pub min_join_interval: Option<Duration>,
+    /// Maximum number of users in the room, a join into a full room is refused.
+    pub capacity: usize,
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        Self {
+            min_join_interval: None,
+            capacity: DEFAULT_ROOM_CAPACITY,
+        }
+    }
 }
 
 impl Room {
+    // a Default spawning the room task would be surprising
+    #[cfg(test)]
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Room {
+        Room::with_config(RoomConfig::default())
+    }
+
+    pub fn with_config(config: RoomConfig) -> Room {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_room(RoomHandle { receiver: rx }, config));
+        Room {
+            sender: tx,
+            #[cfg(test)]
+            pending: None,
+        }
+    }
+
+    /// A room whose messages are queued but never processed, see `drain_pending`.
+    #[cfg(test)]
+    pub fn detached() -> Room {
         let (tx, rx) = mpsc::unbounded_channel();
-        tokio::spawn(run_room(RoomHandle { receiver: rx }));
-        Room { sender: tx }
+        Room {
+            sender: tx,
+            pending: Some(std::sync::Arc::new(std::sync::Mutex::new(rx))),
+        }
+    }
+
+    /// Take every message queued so far, in order, so a test can assert exactly
+    /// what a client action enqueued. Only a `Room::detached` has a queue to drain.
+    #[cfg(test)]
+    pub fn drain_pending(&self) -> Vec<RoomMessage> {
+        let pending = self
+            .pending
+            .as_ref()
+            .expect("only a detached room can be drained");
+        let mut receiver = pending.lock().unwrap();
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
     }
 
     pub fn join(&self, client_id: ClientId, username: Username) -> Result<UserHandle> {
@@ -50,11 +128,33 @@ impl Room {
             .map_err(|_| Error::Other("Room channel closed".into()))
     }
 
-    pub fn send_chat(&self, from: ClientId, text: String) -> Result<()> {
+    pub fn send_chat(&self, from: ClientId, text: ChatText) -> Result<()> {
         self.sender
             .send(RoomMessage::Chat { from, text })
             .map_err(|_| Error::Other("Room channel closed".into()))
     }
+
+    /// The names of the other users currently in the room, as `from` would see them on joining.
+    pub async fn list_users(&self, from: ClientId) -> Result<Vec<Username>> {
+        let (reply, roster) = oneshot::channel();
+        self.sender
+            .send(RoomMessage::ListUsers { from, reply })
+            .map_err(|_| Error::Other("Room channel closed".into()))?;
+        roster
+            .await
+            .map_err(|_| Error::Other("Room channel closed".into()))
+    }
+
+    /// A name for a user who did not pick one. Each call gives a different name,
+    /// none held by a user in the room at the time.
+    pub async fn guest_name(&self) -> Result<Username> {
+        let (reply, name) = oneshot::channel();
+        self.sender
+            .send(RoomMessage::GuestName { reply })
+            .map_err(|_| Error::Other("Room channel closed".into()))?;
+        name.await
+            .map_err(|_| Error::Other("Room channel closed".into()))
+    }
 }
 
 struct RoomHandle {
@@ -69,20 +169,53 @@ impl RoomHandle {
 
 // a task which keep receiving ServerMessage and
 // broadcast Message to different client
-async fn run_room(mut room_handle: RoomHandle) -> Result<()> {
+async fn run_room(mut room_handle: RoomHandle, config: RoomConfig) -> Result<()> {
     // review: each client is represented by username with mpsc::UnboundedSender<Message>
     // which act like elixir's pid to allow you send message to it.
     let mut users: HashMap<ClientId, User> = HashMap::new();
+    // who is in `users`, oldest join first, so lists of participants are stable
+    let mut join_order: Vec<ClientId> = Vec::new();
+    let mut last_joins: HashMap<IpAddr, Instant> = HashMap::new();
+    // number of the next guest name handed out
+    let mut next_guest: usize = 1;
 
     while let Some(msg) = room_handle.recv().await {
         match msg {
             RoomMessage::UserJoin { client_id, user } => {
+                // 0. Refuse joins which come too fast from the same IP,
+                // dropping `user` closes its channel so the client handler stops.
+                if let Some(min_interval) = config.min_join_interval
+                    && joined_recently(&mut last_joins, client_id.ip(), min_interval)
+                {
+                    info!("refuse join of {:?}, joining too often", client_id);
+                    let _ = user.send(OutgoingMessage::JoinRateLimited);
+                    continue;
+                }
+
+                // names are identities, only one user may hold each
+                if users.values().any(|other| other.username == user.username) {
+                    info!(
+                        "refuse join of {:?}, {} is already taken",
+                        client_id, user.username
+                    );
+                    let _ = user.send(OutgoingMessage::NameTaken);
+                    continue;
+                }
+
+                // a refused user is neither listed nor announced
+                if users.len() >= config.capacity {
+                    info!("refuse join of {:?}, the room is full", client_id);
+                    let _ = user.send(OutgoingMessage::RoomFull);
+                    continue;
+                }
+
+                // only an accepted join counts, a client refused for its name may retry
+                if config.min_join_interval.is_some() {
+                    last_joins.insert(client_id.ip(), Instant::now());
+                }
+
                 // 1. Send presence list to the NEW user
-                let current_users: Vec<Username> = users
-                    .values()
-                    .into_iter()
-                    .map(|v| v.username.clone())
-                    .collect();
+                let current_users = usernames_in_join_order(&users, &join_order, &client_id);
                 let _ = user.send(OutgoingMessage::Participants(current_users));
 
                 // 2. Notify ALL OTHER users that this user joined
@@ -92,18 +225,25 @@ async fn run_room(mut room_handle: RoomHandle) -> Result<()> {
                 }
 
                 // 3. Register new user
+                join_order.push(client_id.clone());
                 users.insert(client_id, user);
             }
             RoomMessage::UserLeave { client_id } => {
-                let user = users.remove(&client_id);
-                let leave_msg = OutgoingMessage::UserLeave(user.unwrap().username);
+                // a refused user never made it into the room
+                let Some(user) = users.remove(&client_id) else {
+                    continue;
+                };
+                join_order.retain(|id| *id != client_id);
+                let leave_msg = OutgoingMessage::UserLeave(user.username);
 
                 for (_user, client_ref) in users.iter() {
                     let _ = client_ref.send(leave_msg.clone());
                 }
             }
             RoomMessage::Chat { from, text } => {
-                let user = users.get(&from).unwrap();
+                let Some(user) = users.get(&from) else {
+                    continue;
+                };
                 let chat_msg = OutgoingMessage::Chat {
                     from: user.username.clone(),
                     text,
@@ -114,7 +254,134 @@ async fn run_room(mut room_handle: RoomHandle) -> Result<()> {
                     }
                 }
             }
+            RoomMessage::ListUsers { from, reply } => {
+                let roster = usernames_in_join_order(&users, &join_order, &from);
+                let _ = reply.send(roster);
+            }
+            RoomMessage::GuestName { reply } => {
+                // skip the numbers of users who picked a guest name themselves
+                let name = loop {
+                    let name = Username::parse(&format!("guest{}", next_guest))?;
+                    next_guest += 1;
+                    if !users.values().any(|user| user.username == name) {
+                        break name;
+                    }
+                };
+                let _ = reply.send(name);
+            }
         }
     }
     Ok(())
 }
+
+/// Tell whether `ip` joined less than `min_interval` ago. Joins older than
+/// `min_interval` no longer matter and are forgotten, so the map only holds recent IPs.
+fn joined_recently(
+    last_joins: &mut HashMap<IpAddr, Instant>,
+    ip: IpAddr,
+    min_interval: Duration,
+) -> bool {
+    let now = Instant::now();
+    last_joins.retain(|_, last| now.duration_since(*last) < min_interval);
+    last_joins.contains_key(&ip)
+}
+
+/// The names of everyone in the room except `except`, in the order they joined.
+fn usernames_in_join_order(
+    users: &HashMap<ClientId, User>,
+    join_order: &[ClientId],
+    except: &ClientId,
+) -> Vec<Username> {
+    join_order
+        .iter()
+        .filter(|client_id| *client_id != except)
+        .filter_map(|client_id| users.get(client_id))
+        .map(|user| user.username.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_pending_returns_queued_messages_in_order() -> Result<()> {
+        let room = Room::detached();
+        let client_id = ClientId::new("127.0.0.1:10".parse().unwrap());
+
+        let user_handle = room.join(client_id.clone(), Username::parse("alice").unwrap())?;
+        user_handle.send_chat_message("hello".into(), &room).await?;
+        room.leave(client_id.clone())?;
+
+        let pending = room.drain_pending();
+        assert_eq!(pending.len(), 3, "{:?}", pending);
+        assert!(
+            matches!(&pending[0], RoomMessage::UserJoin { client_id: id, user } if *id == client_id && user.username.to_string() == "alice")
+        );
+        assert!(
+            matches!(&pending[1], RoomMessage::Chat { from, text } if *from == client_id && text.0 == b"hello")
+        );
+        assert!(
+            matches!(&pending[2], RoomMessage::UserLeave { client_id: id } if *id == client_id)
+        );
+
+        // the queue is empty once drained
+        assert!(room.drain_pending().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn join_throttle_forgets_joins_older_than_the_interval() {
+        let min_interval = Duration::from_secs(1);
+        let mut last_joins = HashMap::new();
+        for last_octet in 1..=100u8 {
+            let ip = IpAddr::from([10, 0, 0, last_octet]);
+            assert!(!joined_recently(&mut last_joins, ip, min_interval));
+            last_joins.insert(ip, Instant::now());
+        }
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        assert!(joined_recently(&mut last_joins, ip, min_interval));
+        assert_eq!(last_joins.len(), 100);
+
+        tokio::time::advance(min_interval).await;
+        assert!(!joined_recently(&mut last_joins, ip, min_interval));
+        assert!(last_joins.is_empty());
+    }
+
+    #[tokio::test]
+    async fn participants_are_listed_in_join_order() -> Result<()> {
+        let room = Room::new();
+        let mut handles = Vec::new();
+        // enough users that a HashMap would be unlikely to keep this order by chance
+        let names = [
+            "alice", "bob", "carol", "dave", "erin", "frank", "grace", "heidi",
+        ];
+        for (port, name) in names.iter().enumerate() {
+            let client_id = ClientId::new(format!("127.0.0.1:{}", port + 10).parse().unwrap());
+            let mut handle = room.join(client_id, Username::parse(name).unwrap())?;
+            // the participants sent to this user list the earlier joiners in order
+            assert_eq!(
+                handle.recv().await,
+                Some(OutgoingMessage::Participants(
+                    names[..port]
+                        .iter()
+                        .map(|name| Username::parse(name).unwrap())
+                        .collect()
+                ))
+            );
+            handles.push(handle);
+        }
+
+        // after a leave the others keep their order
+        room.leave(handles[1].client_id.clone())?;
+        let roster = room.list_users(handles[0].client_id.clone()).await?;
+        let expected: Vec<Username> = ["carol", "dave", "erin", "frank", "grace", "heidi"]
+            .iter()
+            .map(|name| Username::parse(name).unwrap())
+            .collect();
+        assert_eq!(roster, expected);
+
+        Ok(())
+    }
+}
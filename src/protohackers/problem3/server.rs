@@ -3,40 +3,108 @@
 
 use super::protocol::*;
 use super::room::*;
-use crate::{Error, Result};
+use crate::{Context, Error, Result};
 
-use crate::protohackers::HOST;
+use crate::protohackers::{HandlerOutcome, ServerBuilder, read_message_with_timeout};
 use futures::{Sink, SinkExt, Stream, StreamExt, TryStreamExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
 use tokio_util::codec::Framed;
-use tracing::error;
+use tracing::{error, info};
 
-pub async fn run(port: u32) -> Result<()> {
-    let address = format!("{HOST}:{port}");
-    let listener = TcpListener::bind(address.clone()).await?;
+/// Longest chat message, in bytes, broadcast to the room by default.
+pub const DEFAULT_MAX_MESSAGE_LENGTH: usize = 1000;
 
-    let room = Room::new();
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        let client_id = ClientId::new(addr);
-        // tokio::spawn(handle_client(socket, room.clone()));
-        tokio::spawn(handle_client(room.clone(), socket, client_id));
+/// How many chat messages a user may send per second by default.
+pub const DEFAULT_MESSAGES_PER_SECOND: u32 = 5;
+
+/// A first line asking for a guest name, when `ChatConfig::guest_names` is set.
+pub const ANON_KEYWORD: &str = "/anon";
+
+/// Settings which control how a client goes through the chat session.
+#[derive(Debug, Clone)]
+pub struct ChatConfig {
+    /// How long a newly connected client may take to send its username before being kicked.
+    pub username_timeout: Duration,
+    /// When set, the room is gated: after the username the client must send this password
+    /// within `username_timeout`, otherwise it is disconnected without joining.
+    pub password: Option<String>,
+    /// Limits of the room shared by every client.
+    pub room: RoomConfig,
+    /// Whether chat lines must be UTF-8 or are relayed as raw bytes.
+    /// Usernames must be printable ASCII either way.
+    pub encoding: ChatEncoding,
+    /// When set, a keepalive line is sent to each joined user at this interval,
+    /// a user whose connection can no longer be written to is disconnected.
+    pub keepalive: Option<Duration>,
+    /// Chat messages longer than this many bytes are refused instead of broadcast.
+    pub max_message_length: usize,
+    /// When set, each user may send this many chat messages per second, with bursts of
+    /// the same size. Messages beyond it are dropped and the sender is warned.
+    pub messages_per_second: Option<u32>,
+    /// How chat, join, leave and participant lines are rendered.
+    pub format: ChatFormat,
+    /// When set, a blank first line or `ANON_KEYWORD` joins under a generated
+    /// `guestN` name instead of being refused as a username.
+    pub guest_names: bool,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            username_timeout: Duration::from_secs(30),
+            password: None,
+            room: RoomConfig::default(),
+            encoding: ChatEncoding::Utf8,
+            keepalive: None,
+            max_message_length: DEFAULT_MAX_MESSAGE_LENGTH,
+            messages_per_second: Some(DEFAULT_MESSAGES_PER_SECOND),
+            format: ChatFormat::default(),
+            guest_names: false,
+        }
     }
 }
 
-async fn handle_client(room: Room, stream: TcpStream, client_id: ClientId) -> Result<()> {
-    let (input_stream, output_stream) = Framed::new(stream, ChatCodec::new()).split();
-    handle_client_internal(room, client_id, input_stream, output_stream).await
+/// Serve the chat with the port, limits and shutdown token of `server`.
+pub async fn run(server: ServerBuilder, config: ChatConfig) -> Result<()> {
+    let room = Room::with_config(config.room.clone());
+
+    server
+        .run_with_state((room, config), |(room, config), socket, addr| {
+            handle_client(room, config, socket, ClientId::new(addr))
+        })
+        .await
+}
+
+async fn handle_client(
+    room: Room,
+    config: ChatConfig,
+    stream: TcpStream,
+    client_id: ClientId,
+) -> HandlerOutcome {
+    // one more byte for the `\r` the Utf8 encoding drops
+    let codec = ChatCodec::new(
+        config.encoding,
+        config.format.clone(),
+        config.max_message_length.saturating_add(1),
+    );
+    let (input_stream, output_stream) = Framed::new(stream, codec).split();
+    handle_client_internal(room, config, client_id, input_stream, output_stream)
+        .await
+        .context("budget chat")
+        .into()
 }
 
 async fn handle_client_internal<I, O>(
     room: Room,
+    config: ChatConfig,
     client_id: ClientId,
     mut sink: O,
     mut stream: I,
-) -> Result<()>
+) -> Result<HandlerOutcome>
 where
-    I: Stream<Item = Result<String>> + Unpin,
+    I: Stream<Item = Result<Vec<u8>>> + Unpin,
     O: Sink<OutgoingMessage, Error = Error> + Unpin,
 {
     // review: use into_split to consumes the socket and returns owned
@@ -46,73 +114,210 @@ where
     // 1. send welcome to client
     let _ = sink.send(OutgoingMessage::Welcome).await?;
 
-    // 2. get username from the first line received from client
-    let username = stream
-        .try_next()
-        .await?
-        .ok_or_else(|| Error::Other("Error while waiting for the username".into()))?;
+    // 2. get username from the first line received from client,
+    // a client which never picks a name is kicked before joining the room
+    let Some(username) =
+        read_message_with_timeout(config.username_timeout, stream.try_next()).await
+    else {
+        info!(
+            "{:?} did not send a username in time, disconnect",
+            client_id
+        );
+        return Ok(HandlerOutcome::Completed);
+    };
+    let Some(username) = username? else {
+        return Ok(HandlerOutcome::ClientClosed);
+    };
 
-    let username = match Username::parse(&username) {
-        Ok(username) => username,
-        Err(e) => {
-            sink.send(OutgoingMessage::InvalidUsername(e.to_string()))
-                .await?;
-            return Ok(());
+    // non UTF-8 bytes become U+FFFD, which is rejected as not printable ASCII
+    let username = String::from_utf8_lossy(&username);
+    let username = if config.guest_names && (username.is_empty() || username == ANON_KEYWORD) {
+        room.guest_name().await?
+    } else {
+        match Username::parse(&username) {
+            Ok(username) => username,
+            Err(e) => {
+                sink.send(OutgoingMessage::InvalidUsername(e.to_string()))
+                    .await?;
+                return Ok(HandlerOutcome::Completed);
+            }
         }
     };
 
+    // 2b. a gated room asks for the password before joining
+    if let Some(expected) = &config.password {
+        sink.send(OutgoingMessage::PasswordPrompt).await?;
+        let Some(password) =
+            read_message_with_timeout(config.username_timeout, stream.try_next()).await
+        else {
+            info!(
+                "{:?} did not send a password in time, disconnect",
+                client_id
+            );
+            return Ok(HandlerOutcome::Completed);
+        };
+        let Some(password) = password? else {
+            return Ok(HandlerOutcome::ClientClosed);
+        };
+        if password != expected.as_bytes() {
+            sink.send(OutgoingMessage::WrongPassword).await?;
+            return Ok(HandlerOutcome::Completed);
+        }
+    }
+
     // let (client_tx, mut client_rx) = mpsc::unbounded_channel::<OutgoingMessage>();
 
     // 3. send to manager that user has joined
     let mut user_handle = room.join(client_id.clone(), username.clone())?;
+    let mut keepalive = config.keepalive.map(|period| {
+        let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+    let mut rate_limit = config.messages_per_second.map(TokenBucket::new);
 
-    loop {
+    let outcome = loop {
         tokio::select! {
             // 4a. Receive message from manager → send to client
-            Some(msg) = user_handle.recv() => {
-                if let Err(e) = sink.send(msg).await {
-                    error!("Error sending message {}",e);
-                    break;
+            msg = user_handle.recv() => match msg {
+                Some(msg) => {
+                    if let Err(e) = sink.send(msg).await {
+                        error!("Error sending message {}",e);
+                        break HandlerOutcome::Error(e);
+                    }
                 }
-            }
+                // the room dropped this user, e.g. the join was refused
+                None => {
+                    break HandlerOutcome::Completed;
+                }
+            },
 
              // 4b. send message for broadcast
              result = stream.next() => match result {
+                Some(Ok(msg)) if msg.len() > config.max_message_length => {
+                    info!("{:?} sent a message of {} bytes, not broadcast", client_id, msg.len());
+                    if let Err(e) = sink.send(OutgoingMessage::MessageTooLong(config.max_message_length)).await {
+                        break HandlerOutcome::Error(e);
+                    }
+                }
+                Some(Ok(msg)) if is_who_command(&msg) => {
+                    let roster = match room.list_users(client_id.clone()).await {
+                        Ok(roster) => roster,
+                        Err(e) => break HandlerOutcome::Error(e),
+                    };
+                    if let Err(e) = sink.send(OutgoingMessage::Participants(roster)).await {
+                        break HandlerOutcome::Error(e);
+                    }
+                }
+                Some(Ok(_)) if rate_limit.as_mut().is_some_and(|bucket| !bucket.try_take()) => {
+                    info!("{:?} sends messages too fast, message dropped", client_id);
+                    if let Err(e) = sink.send(OutgoingMessage::MessageRateLimited).await {
+                        break HandlerOutcome::Error(e);
+                    }
+                }
                 Some(Ok(msg)) => {
-                    let _ = user_handle.send_chat_message(msg, &room).await;
+                    let _ = user_handle.send_chat_message(ChatText(msg), &room).await;
+                }
+                // the line is not buffered whole, and the stream ends after a codec error
+                Some(Err(Error::LineTooLong(_))) => {
+                    info!("{:?} sent a line over the message limit, disconnect", client_id);
+                    let _ = sink.send(OutgoingMessage::MessageTooLong(config.max_message_length)).await;
+                    break HandlerOutcome::Completed;
                 }
                 Some(Err(e)) => {
                     error!("Error reading message {}", e);
-                    break;
+                    break HandlerOutcome::Error(e);
                 }
                 None => {
-                    break;
+                    break HandlerOutcome::ClientClosed;
+                }
+             },
+
+            // 4c. show the client that the connection is still alive
+            _ = next_keepalive(&mut keepalive) => {
+                if let Err(e) = sink.send(OutgoingMessage::Keepalive).await {
+                    info!("{:?} can no longer be written to, disconnect: {}", client_id, e);
+                    break HandlerOutcome::ClientClosed;
                 }
-             }
+            }
         }
-    }
+    };
 
     // 5. One EOF, notify manager user leave
     let _ = room.leave(client_id.clone());
 
-    Ok(())
+    Ok(outcome)
+}
+
+/// Allows `rate` messages per second on average, refilled continuously,
+/// and a burst of up to `rate` messages after a quiet period.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token if one is left, false means the message must be dropped.
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// `/who` asks for the current participants, it is answered to the sender only.
+fn is_who_command(line: &[u8]) -> bool {
+    line.split(|b| b.is_ascii_whitespace()).next() == Some(b"/who")
+}
+
+/// Wait for the next keepalive tick, forever when keepalives are disabled.
+async fn next_keepalive(keepalive: &mut Option<Interval>) {
+    match keepalive {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testutil::recv_timeout;
+    use bytes::BytesMut;
     use tokio::sync::mpsc;
     use tokio::sync::mpsc::{Receiver, Sender};
     use tokio::task::JoinHandle;
+    use tokio_util::codec::{Decoder, Encoder};
     use tokio_util::sync::PollSender;
 
     struct UserTest {
         sink_receiver: Receiver<OutgoingMessage>,
-        stream_sender: Option<Sender<Result<String>>>,
-        handle: JoinHandle<Result<()>>,
+        stream_sender: Option<Sender<Result<Vec<u8>>>>,
+        handle: JoinHandle<Result<HandlerOutcome>>,
     }
 
     async fn connect(room: Room, client_id: ClientId) -> UserTest {
+        connect_with_config(room, ChatConfig::default(), client_id).await
+    }
+
+    async fn connect_with_config(room: Room, config: ChatConfig, client_id: ClientId) -> UserTest {
         let (sink_tx, sink_rx) = mpsc::channel(100);
 
         let (stream_tx, mut stream_rx) = mpsc::channel(100);
@@ -127,7 +332,7 @@ mod tests {
         let sink = PollSender::new(sink_tx).sink_map_err(|e| Error::Other(e.to_string()));
 
         let handle = tokio::spawn(async move {
-            handle_client_internal(room, client_id, sink, Box::pin(stream)).await
+            handle_client_internal(room, config, client_id, sink, Box::pin(stream)).await
         });
 
         UserTest {
@@ -139,10 +344,23 @@ mod tests {
 
     impl UserTest {
         async fn send(&mut self, message: &str) {
+            self.send_bytes(message.as_bytes()).await;
+        }
+
+        async fn send_bytes(&mut self, message: &[u8]) {
             self.stream_sender
                 .as_ref()
                 .unwrap()
-                .send(Ok(message.to_string()))
+                .send(Ok(message.to_vec()))
+                .await
+                .unwrap();
+        }
+
+        async fn send_error(&mut self, error: Error) {
+            self.stream_sender
+                .as_ref()
+                .unwrap()
+                .send(Err(error))
                 .await
                 .unwrap();
         }
@@ -151,11 +369,15 @@ mod tests {
             let stream = self.stream_sender.take();
             drop(stream);
 
-            self.handle.await.unwrap().unwrap()
+            let outcome = self.handle.await.unwrap().unwrap();
+            assert!(matches!(outcome, HandlerOutcome::ClientClosed));
         }
 
         async fn check_message(&mut self, msg: OutgoingMessage) {
-            assert_eq!(self.sink_receiver.recv().await.unwrap(), msg);
+            let received = recv_timeout(&mut self.sink_receiver, Duration::from_secs(1))
+                .await
+                .unwrap_or_else(|e| panic!("expected {:?}: {}", msg, e));
+            assert_eq!(received, msg);
         }
     }
 
@@ -198,7 +420,7 @@ mod tests {
 
         // bob gets alice's message
         bob.check_message(OutgoingMessage::Chat {
-            text: "Hi bob!".to_string(),
+            text: "Hi bob!".into(),
             from: alice_username.clone(),
         })
         .await;
@@ -209,7 +431,7 @@ mod tests {
         // alice gets bob's message
         alice
             .check_message(OutgoingMessage::Chat {
-                text: "Hi alice!".to_string(),
+                text: "Hi alice!".into(),
                 from: bob_username.clone(),
             })
             .await;
@@ -224,4 +446,704 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_without_username_is_kicked_after_timeout() -> Result<()> {
+        let room = Room::new();
+        let config = ChatConfig {
+            username_timeout: Duration::from_secs(5),
+            ..ChatConfig::default()
+        };
+        let client_id = ClientId::new("127.0.0.1:10".parse().unwrap());
+
+        let mut user = connect_with_config(room, config, client_id).await;
+        user.check_message(OutgoingMessage::Welcome).await;
+
+        // the stream stays open, but no username is ever sent
+        let start = tokio::time::Instant::now();
+        let outcome = user.handle.await.unwrap()?;
+        assert!(matches!(outcome, HandlerOutcome::Completed));
+        assert!(start.elapsed() >= Duration::from_secs(5));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clean_eof_yields_client_closed() -> Result<()> {
+        let room = Room::new();
+        let client_id = ClientId::new("127.0.0.1:10".parse().unwrap());
+
+        let mut alice = connect(room, client_id).await;
+        alice.check_message(OutgoingMessage::Welcome).await;
+        alice.send("alice").await;
+        alice
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+
+        // `leave` asserts the outcome is `ClientClosed`
+        alice.leave().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn protocol_error_yields_error() -> Result<()> {
+        let room = Room::new();
+        let client_id = ClientId::new("127.0.0.1:10".parse().unwrap());
+
+        let mut alice = connect(room, client_id).await;
+        alice.check_message(OutgoingMessage::Welcome).await;
+        alice.send("alice").await;
+        alice
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+
+        alice
+            .send_error(Error::InvalidProtocol("line too long".into()))
+            .await;
+
+        let outcome = alice.handle.await.unwrap()?;
+        assert!(matches!(
+            outcome,
+            HandlerOutcome::Error(Error::InvalidProtocol(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rapid_rejoins_from_same_ip_are_rate_limited() -> Result<()> {
+        let room = Room::with_config(RoomConfig {
+            min_join_interval: Some(Duration::from_secs(10)),
+            ..RoomConfig::default()
+        });
+        let alice_username = Username::parse("alice").unwrap();
+        let bob_username = Username::parse("bob").unwrap();
+
+        let mut alice = connect(room.clone(), ClientId::new("127.0.0.1:10".parse().unwrap())).await;
+        alice.check_message(OutgoingMessage::Welcome).await;
+        alice.send("alice").await;
+        alice
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+
+        let mut bob = connect(room.clone(), ClientId::new("127.0.0.2:20".parse().unwrap())).await;
+        bob.check_message(OutgoingMessage::Welcome).await;
+        bob.send("bob").await;
+        bob.check_message(OutgoingMessage::Participants(vec![alice_username.clone()]))
+            .await;
+        alice
+            .check_message(OutgoingMessage::UserJoin(bob_username.clone()))
+            .await;
+        bob.leave().await;
+        alice
+            .check_message(OutgoingMessage::UserLeave(bob_username.clone()))
+            .await;
+
+        // bob reconnects right away from the same IP: refused, no join broadcast
+        let mut bob = connect(room.clone(), ClientId::new("127.0.0.2:21".parse().unwrap())).await;
+        bob.check_message(OutgoingMessage::Welcome).await;
+        bob.send("bob").await;
+        bob.check_message(OutgoingMessage::JoinRateLimited).await;
+        let outcome = bob.handle.await.unwrap()?;
+        assert!(matches!(outcome, HandlerOutcome::Completed));
+
+        // after the interval bob can join again
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let mut bob = connect(room.clone(), ClientId::new("127.0.0.2:22".parse().unwrap())).await;
+        bob.check_message(OutgoingMessage::Welcome).await;
+        bob.send("bob").await;
+        bob.check_message(OutgoingMessage::Participants(vec![alice_username.clone()]))
+            .await;
+
+        // the next thing alice sees is the successful join, not the refused one
+        alice
+            .check_message(OutgoingMessage::UserJoin(bob_username.clone()))
+            .await;
+
+        Ok(())
+    }
+
+    fn gated_config() -> ChatConfig {
+        ChatConfig {
+            password: Some("secret".into()),
+            ..ChatConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn gated_room_accepts_correct_password() -> Result<()> {
+        let room = Room::new();
+        let client_id = ClientId::new("127.0.0.1:10".parse().unwrap());
+
+        let mut alice = connect_with_config(room, gated_config(), client_id).await;
+        alice.check_message(OutgoingMessage::Welcome).await;
+        alice.send("alice").await;
+        alice.check_message(OutgoingMessage::PasswordPrompt).await;
+        alice.send("secret").await;
+        alice
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+        alice.leave().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gated_room_rejects_wrong_password() -> Result<()> {
+        let room = Room::new();
+        let alice_client = ClientId::new("127.0.0.1:10".parse().unwrap());
+        let bob_client = ClientId::new("127.0.0.1:11".parse().unwrap());
+
+        let mut bob = connect_with_config(room.clone(), gated_config(), bob_client).await;
+        bob.check_message(OutgoingMessage::Welcome).await;
+        bob.send("bob").await;
+        bob.check_message(OutgoingMessage::PasswordPrompt).await;
+        bob.send("guess").await;
+        bob.check_message(OutgoingMessage::WrongPassword).await;
+        let outcome = bob.handle.await.unwrap()?;
+        assert!(matches!(outcome, HandlerOutcome::Completed));
+
+        // bob never joined, so the room is still empty
+        let mut alice = connect_with_config(room, gated_config(), alice_client).await;
+        alice.check_message(OutgoingMessage::Welcome).await;
+        alice.send("alice").await;
+        alice.check_message(OutgoingMessage::PasswordPrompt).await;
+        alice.send("secret").await;
+        alice
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn raw_encoding_relays_non_utf8_bytes_exactly() -> Result<()> {
+        let room = Room::new();
+        let config = ChatConfig {
+            encoding: ChatEncoding::Raw,
+            ..ChatConfig::default()
+        };
+        let alice_client = ClientId::new("127.0.0.1:10".parse().unwrap());
+        let bob_client = ClientId::new("127.0.0.1:11".parse().unwrap());
+        let raw: &[u8] = b"caf\xe9 \xff\xfe\r";
+
+        // the codec lets the raw bytes through, the default one refuses them
+        let mut wire = BytesMut::from(&b"caf\xe9 \xff\xfe\r\n"[..]);
+        assert!(
+            ChatCodec::with_encoding(ChatEncoding::Utf8)
+                .decode(&mut wire.clone())
+                .is_err()
+        );
+        let line = ChatCodec::with_encoding(ChatEncoding::Raw).decode(&mut wire)?;
+        assert_eq!(line.as_deref(), Some(raw));
+
+        let mut alice = connect_with_config(room.clone(), config.clone(), alice_client).await;
+        alice.check_message(OutgoingMessage::Welcome).await;
+        alice.send("alice").await;
+        alice
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+
+        let mut bob = connect_with_config(room.clone(), config, bob_client).await;
+        bob.check_message(OutgoingMessage::Welcome).await;
+        bob.send("bob").await;
+        bob.check_message(OutgoingMessage::Participants(vec![
+            Username::parse("alice").unwrap(),
+        ]))
+        .await;
+        alice
+            .check_message(OutgoingMessage::UserJoin(Username::parse("bob").unwrap()))
+            .await;
+
+        alice.send_bytes(raw).await;
+        let relayed = OutgoingMessage::Chat {
+            from: Username::parse("alice").unwrap(),
+            text: ChatText(raw.to_vec()),
+        };
+        bob.check_message(relayed.clone()).await;
+
+        let mut out = BytesMut::new();
+        ChatCodec::with_encoding(ChatEncoding::Raw).encode(relayed, &mut out)?;
+        assert_eq!(&out[..], b"[alice] caf\xe9 \xff\xfe\r\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn too_long_line_is_refused_and_the_next_one_decoded() -> Result<()> {
+        let mut codec = RawLinesCodec::new(5);
+        let mut wire = BytesMut::from(&b"abc"[..]);
+        assert_eq!(codec.decode(&mut wire)?, None);
+
+        // the limit is hit before the newline arrives
+        wire.extend_from_slice(b"defgh");
+        assert!(matches!(
+            codec.decode(&mut wire),
+            Err(Error::LineTooLong(5))
+        ));
+        wire.extend_from_slice(b"ijk\nhello\n");
+        assert_eq!(codec.decode(&mut wire)?, Some(b"hello".to_vec()));
+        assert_eq!(codec.decode(&mut wire)?, None);
+        assert!(wire.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn line_is_found_when_it_arrives_in_pieces() -> Result<()> {
+        let mut codec = RawLinesCodec::new(usize::MAX);
+        let mut wire = BytesMut::new();
+        for piece in [&b"hel"[..], b"lo", b" world"] {
+            wire.extend_from_slice(piece);
+            assert_eq!(codec.decode(&mut wire)?, None);
+        }
+        wire.extend_from_slice(b"\nnext");
+        assert_eq!(codec.decode(&mut wire)?, Some(b"hello world".to_vec()));
+        assert_eq!(codec.decode_eof(&mut wire)?, Some(b"next".to_vec()));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalives_are_sent_and_dead_clients_pruned() -> Result<()> {
+        let room = Room::new();
+        let config = ChatConfig {
+            keepalive: Some(Duration::from_millis(500)),
+            ..ChatConfig::default()
+        };
+        let alice_client = ClientId::new("127.0.0.1:10".parse().unwrap());
+        let bob_client = ClientId::new("127.0.0.1:11".parse().unwrap());
+
+        let mut alice = connect_with_config(room.clone(), config.clone(), alice_client).await;
+        alice.check_message(OutgoingMessage::Welcome).await;
+        alice.send("alice").await;
+        alice
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+
+        let mut bob = connect_with_config(room.clone(), config, bob_client).await;
+        bob.check_message(OutgoingMessage::Welcome).await;
+        bob.send("bob").await;
+        bob.check_message(OutgoingMessage::Participants(vec![
+            Username::parse("alice").unwrap(),
+        ]))
+        .await;
+        alice
+            .check_message(OutgoingMessage::UserJoin(Username::parse("bob").unwrap()))
+            .await;
+
+        // a quiet room still gets keepalives
+        alice.check_message(OutgoingMessage::Keepalive).await;
+        bob.check_message(OutgoingMessage::Keepalive).await;
+
+        // bob's connection can no longer be written to
+        drop(bob.sink_receiver);
+        let outcome = bob.handle.await.unwrap()?;
+        assert!(matches!(outcome, HandlerOutcome::ClientClosed));
+
+        alice.check_message(OutgoingMessage::Keepalive).await;
+        alice
+            .check_message(OutgoingMessage::UserLeave(Username::parse("bob").unwrap()))
+            .await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn joining_a_full_room_is_refused() -> Result<()> {
+        let room = Room::with_config(RoomConfig {
+            capacity: 2,
+            ..RoomConfig::default()
+        });
+        let alice_username = Username::parse("alice").unwrap();
+        let bob_username = Username::parse("bob").unwrap();
+
+        let mut alice = connect(room.clone(), ClientId::new("127.0.0.1:10".parse().unwrap())).await;
+        alice.check_message(OutgoingMessage::Welcome).await;
+        alice.send("alice").await;
+        alice
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+
+        let mut bob = connect(room.clone(), ClientId::new("127.0.0.1:11".parse().unwrap())).await;
+        bob.check_message(OutgoingMessage::Welcome).await;
+        bob.send("bob").await;
+        bob.check_message(OutgoingMessage::Participants(vec![alice_username.clone()]))
+            .await;
+        alice
+            .check_message(OutgoingMessage::UserJoin(bob_username.clone()))
+            .await;
+
+        let mut carol = connect(room.clone(), ClientId::new("127.0.0.1:12".parse().unwrap())).await;
+        carol.check_message(OutgoingMessage::Welcome).await;
+        carol.send("carol").await;
+        carol.check_message(OutgoingMessage::RoomFull).await;
+        let outcome = carol.handle.await.unwrap()?;
+        assert!(matches!(outcome, HandlerOutcome::Completed));
+
+        // carol was never announced: the next thing alice sees is bob's chat
+        bob.send("hi").await;
+        alice
+            .check_message(OutgoingMessage::Chat {
+                from: bob_username,
+                text: "hi".into(),
+            })
+            .await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_after_a_taken_name_is_not_rate_limited() -> Result<()> {
+        let room = Room::with_config(RoomConfig {
+            min_join_interval: Some(Duration::from_secs(10)),
+            ..RoomConfig::default()
+        });
+        let mut alice = connect(room.clone(), ClientId::new("127.0.0.1:10".parse().unwrap())).await;
+        alice.check_message(OutgoingMessage::Welcome).await;
+        alice.send("alice").await;
+        alice
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+
+        let mut bob = connect(room.clone(), ClientId::new("127.0.0.2:20".parse().unwrap())).await;
+        bob.check_message(OutgoingMessage::Welcome).await;
+        bob.send("alice").await;
+        bob.check_message(OutgoingMessage::NameTaken).await;
+
+        // the refused join is not counted, bob retries right away with another name
+        let mut bob = connect(room.clone(), ClientId::new("127.0.0.2:21".parse().unwrap())).await;
+        bob.check_message(OutgoingMessage::Welcome).await;
+        bob.send("bob").await;
+        bob.check_message(OutgoingMessage::Participants(vec![
+            Username::parse("alice").unwrap(),
+        ]))
+        .await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn duplicate_username_is_refused() -> Result<()> {
+        let room = Room::new();
+        let alice_username = Username::parse("alice").unwrap();
+        let bob_username = Username::parse("bob").unwrap();
+
+        let mut alice = connect(room.clone(), ClientId::new("127.0.0.1:10".parse().unwrap())).await;
+        alice.check_message(OutgoingMessage::Welcome).await;
+        alice.send("alice").await;
+        alice
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+
+        let mut impostor =
+            connect(room.clone(), ClientId::new("127.0.0.1:11".parse().unwrap())).await;
+        impostor.check_message(OutgoingMessage::Welcome).await;
+        impostor.send("alice").await;
+        impostor.check_message(OutgoingMessage::NameTaken).await;
+        let outcome = impostor.handle.await.unwrap()?;
+        assert!(matches!(outcome, HandlerOutcome::Completed));
+
+        // the original alice is still in the room, and the only one
+        let mut bob = connect(room.clone(), ClientId::new("127.0.0.1:12".parse().unwrap())).await;
+        bob.check_message(OutgoingMessage::Welcome).await;
+        bob.send("bob").await;
+        bob.check_message(OutgoingMessage::Participants(vec![alice_username.clone()]))
+            .await;
+        alice
+            .check_message(OutgoingMessage::UserJoin(bob_username.clone()))
+            .await;
+        alice.send("still here").await;
+        bob.check_message(OutgoingMessage::Chat {
+            from: alice_username,
+            text: "still here".into(),
+        })
+        .await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn anonymous_users_get_distinct_guest_names() -> Result<()> {
+        let room = Room::new();
+        let config = ChatConfig {
+            guest_names: true,
+            ..ChatConfig::default()
+        };
+        let guest = |name: &str| Username::parse(name).unwrap();
+
+        // someone already picked the first guest name
+        let mut early = connect(room.clone(), ClientId::new("127.0.0.1:10".parse().unwrap())).await;
+        early.check_message(OutgoingMessage::Welcome).await;
+        early.send("guest1").await;
+        early
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+
+        let mut first = connect_with_config(
+            room.clone(),
+            config.clone(),
+            ClientId::new("127.0.0.1:11".parse().unwrap()),
+        )
+        .await;
+        first.check_message(OutgoingMessage::Welcome).await;
+        first.send(ANON_KEYWORD).await;
+        first
+            .check_message(OutgoingMessage::Participants(vec![guest("guest1")]))
+            .await;
+        early
+            .check_message(OutgoingMessage::UserJoin(guest("guest2")))
+            .await;
+
+        // a blank line asks for a guest name too
+        let mut second = connect_with_config(
+            room.clone(),
+            config,
+            ClientId::new("127.0.0.1:12".parse().unwrap()),
+        )
+        .await;
+        second.check_message(OutgoingMessage::Welcome).await;
+        second.send("").await;
+        second
+            .check_message(OutgoingMessage::Participants(vec![
+                guest("guest1"),
+                guest("guest2"),
+            ]))
+            .await;
+        first
+            .check_message(OutgoingMessage::UserJoin(guest("guest3")))
+            .await;
+
+        second.send("hello").await;
+        first
+            .check_message(OutgoingMessage::Chat {
+                from: guest("guest3"),
+                text: "hello".into(),
+            })
+            .await;
+
+        // without guest names the keyword is an ordinary name
+        let mut plain = connect(room.clone(), ClientId::new("127.0.0.1:13".parse().unwrap())).await;
+        plain.check_message(OutgoingMessage::Welcome).await;
+        plain.send(ANON_KEYWORD).await;
+        first
+            .check_message(OutgoingMessage::UserJoin(guest(ANON_KEYWORD)))
+            .await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn over_long_message_is_refused_and_not_broadcast() -> Result<()> {
+        let room = Room::new();
+        let config = ChatConfig {
+            max_message_length: 10,
+            ..ChatConfig::default()
+        };
+        let alice_username = Username::parse("alice").unwrap();
+
+        let mut alice = connect_with_config(
+            room.clone(),
+            config.clone(),
+            ClientId::new("127.0.0.1:10".parse().unwrap()),
+        )
+        .await;
+        alice.check_message(OutgoingMessage::Welcome).await;
+        alice.send("alice").await;
+        alice
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+
+        let mut bob =
+            connect_with_config(room, config, ClientId::new("127.0.0.1:11".parse().unwrap())).await;
+        bob.check_message(OutgoingMessage::Welcome).await;
+        bob.send("bob").await;
+        bob.check_message(OutgoingMessage::Participants(vec![alice_username.clone()]))
+            .await;
+        alice
+            .check_message(OutgoingMessage::UserJoin(Username::parse("bob").unwrap()))
+            .await;
+
+        alice.send("this is far too long").await;
+        alice
+            .check_message(OutgoingMessage::MessageTooLong(10))
+            .await;
+
+        // a message right at the limit still goes through, and is the first thing bob sees
+        alice.send("ten bytes!").await;
+        bob.check_message(OutgoingMessage::Chat {
+            from: alice_username,
+            text: "ten bytes!".into(),
+        })
+        .await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn who_command_lists_the_other_participants() -> Result<()> {
+        let room = Room::new();
+        let alice_username = Username::parse("alice").unwrap();
+        let bob_username = Username::parse("bob").unwrap();
+        let carol_username = Username::parse("carol").unwrap();
+
+        let mut alice = connect(room.clone(), ClientId::new("127.0.0.1:10".parse().unwrap())).await;
+        alice.check_message(OutgoingMessage::Welcome).await;
+        alice.send("alice").await;
+        alice
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+
+        let mut bob = connect(room.clone(), ClientId::new("127.0.0.1:11".parse().unwrap())).await;
+        bob.check_message(OutgoingMessage::Welcome).await;
+        bob.send("bob").await;
+        bob.check_message(OutgoingMessage::Participants(vec![alice_username.clone()]))
+            .await;
+        alice
+            .check_message(OutgoingMessage::UserJoin(bob_username.clone()))
+            .await;
+
+        let mut carol = connect(room.clone(), ClientId::new("127.0.0.1:12".parse().unwrap())).await;
+        carol.check_message(OutgoingMessage::Welcome).await;
+        carol.send("carol").await;
+        // carol's own roster, in no particular order either
+        let _ = recv_timeout(&mut carol.sink_receiver, Duration::from_secs(1)).await;
+        alice
+            .check_message(OutgoingMessage::UserJoin(carol_username.clone()))
+            .await;
+        bob.check_message(OutgoingMessage::UserJoin(carol_username))
+            .await;
+
+        // the roster comes in no particular order
+        alice.send("/who").await;
+        let roster = recv_timeout(&mut alice.sink_receiver, Duration::from_secs(1)).await;
+        let Ok(OutgoingMessage::Participants(mut roster)) = roster else {
+            panic!("expected the participants, got {:?}", roster);
+        };
+        roster.sort_by_key(|name| name.to_string());
+        assert_eq!(
+            roster,
+            vec![bob_username.clone(), Username::parse("carol").unwrap()]
+        );
+
+        // the command is not broadcast, other lines still are
+        alice.send("/whom it may concern").await;
+        bob.check_message(OutgoingMessage::Chat {
+            from: alice_username,
+            text: "/whom it may concern".into(),
+        })
+        .await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flooding_messages_are_dropped_beyond_the_rate() -> Result<()> {
+        let room = Room::new();
+        let config = ChatConfig {
+            messages_per_second: Some(5),
+            ..ChatConfig::default()
+        };
+        let alice_username = Username::parse("alice").unwrap();
+
+        let mut alice = connect_with_config(
+            room.clone(),
+            config.clone(),
+            ClientId::new("127.0.0.1:10".parse().unwrap()),
+        )
+        .await;
+        alice.check_message(OutgoingMessage::Welcome).await;
+        alice.send("alice").await;
+        alice
+            .check_message(OutgoingMessage::Participants(vec![]))
+            .await;
+
+        let mut bob =
+            connect_with_config(room, config, ClientId::new("127.0.0.1:11".parse().unwrap())).await;
+        bob.check_message(OutgoingMessage::Welcome).await;
+        bob.send("bob").await;
+        bob.check_message(OutgoingMessage::Participants(vec![alice_username.clone()]))
+            .await;
+        alice
+            .check_message(OutgoingMessage::UserJoin(Username::parse("bob").unwrap()))
+            .await;
+
+        for i in 0..10 {
+            alice.send(&format!("spam {}", i)).await;
+        }
+        for _ in 0..5 {
+            alice
+                .check_message(OutgoingMessage::MessageRateLimited)
+                .await;
+        }
+        for i in 0..5 {
+            bob.check_message(OutgoingMessage::Chat {
+                from: alice_username.clone(),
+                text: format!("spam {}", i).as_str().into(),
+            })
+            .await;
+        }
+
+        // the bucket refills over time, the next thing bob sees is the later message
+        tokio::time::advance(Duration::from_secs(1)).await;
+        alice.send("calm").await;
+        bob.check_message(OutgoingMessage::Chat {
+            from: alice_username,
+            text: "calm".into(),
+        })
+        .await;
+
+        Ok(())
+    }
+
+    #[test]
+    fn chat_format_templates_render_lines() -> Result<()> {
+        let alice = Username::parse("alice").unwrap();
+        let bob = Username::parse("bob").unwrap();
+        let messages = [
+            OutgoingMessage::Chat {
+                from: alice.clone(),
+                text: "hi {username}".into(),
+            },
+            OutgoingMessage::UserJoin(bob.clone()),
+            OutgoingMessage::UserLeave(bob.clone()),
+            OutgoingMessage::Participants(vec![alice.clone(), bob.clone()]),
+            OutgoingMessage::Welcome,
+        ];
+        let encode = |format: ChatFormat| -> Result<BytesMut> {
+            let mut codec = ChatCodec::new(ChatEncoding::Utf8, format, usize::MAX);
+            let mut out = BytesMut::new();
+            for message in messages.clone() {
+                codec.encode(message, &mut out)?;
+            }
+            Ok(out)
+        };
+
+        // unconfigured, the lines are the ones sent before the templates
+        assert_eq!(
+            &encode(ChatFormat::default())?[..],
+            b"[alice] hi {username}\n\
+              * bob has entered the room\n\
+              * bob has left the room\n\
+              * The room contains: self.participants(_0)\n\
+              Welcome to budgetchat! What shall I call you?\n"
+        );
+        assert_eq!(ChatFormat::from(ChatStyle::Legacy), ChatFormat::default());
+
+        let custom = ChatFormat {
+            chat: "<{username}> {text}".into(),
+            ..ChatFormat::from(ChatStyle::Protohackers)
+        };
+        assert_eq!(
+            &encode(custom)?[..],
+            b"<alice> hi {username}\n\
+              * bob has entered the room\n\
+              * bob has left the room\n\
+              * The room contains: alice, bob\n\
+              Welcome to budgetchat! What shall I call you?\n"
+        );
+
+        Ok(())
+    }
 }
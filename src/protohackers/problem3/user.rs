@@ -23,7 +23,7 @@ pub struct UserHandle {
 }
 
 impl UserHandle {
-    pub async fn send_chat_message(&self, msg: String, room: &Room) -> Result<()> {
+    pub async fn send_chat_message(&self, msg: ChatText, room: &Room) -> Result<()> {
         room.send_chat(self.client_id.clone(), msg)
     }
 
@@ -1,4 +1,4 @@
 mod protocol;
 mod server;
 
-pub use server::run;
+pub use server::{DbConfig, run};
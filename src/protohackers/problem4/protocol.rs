@@ -1,11 +1,29 @@
 // Unlike TCP stream, UDP is message-oriented which means we don't need Decoder/Encoder codecs like in TCP
 // There is no stream to frame.
 
+use std::time::Duration;
+
 // Parse raw UDP datagram → Request
 #[derive(Debug, PartialEq)]
 pub enum Request {
-    Insert { key: String, value: String },
-    Retrieve { key: String },
+    Insert {
+        key: String,
+        value: String,
+    },
+    /// `key@SECONDS=value`, the key expires after that many seconds.
+    /// Only understood by `parse_extended`.
+    InsertWithTtl {
+        key: String,
+        value: String,
+        ttl: Duration,
+    },
+    Retrieve {
+        key: String,
+    },
+    /// `key~`, only understood by `parse_extended`.
+    Delete {
+        key: String,
+    },
 }
 
 impl Request {
@@ -25,6 +43,36 @@ impl Request {
             Some(Request::Retrieve { key: s.to_string() })
         }
     }
+
+    /// Like `parse`, and also accepts `key@SECONDS=value` and `key~` (delete) which are
+    /// not in the spec: for `parse` they insert and retrieve keys with those suffixes.
+    pub fn parse_extended(payload: &[u8]) -> Option<Request> {
+        let s = std::str::from_utf8(payload).ok()?;
+
+        let timed = s.split_once('=').and_then(|(key, value)| {
+            let (key, secs) = key.rsplit_once('@')?;
+            if secs.is_empty() || !secs.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            Some((key, value, secs.parse().ok()?))
+        });
+        match timed {
+            Some(("version", _, _)) => None, // ignore inserts to "version"
+            Some((key, value, secs)) => Some(Request::InsertWithTtl {
+                key: key.to_string(),
+                value: value.to_string(),
+                ttl: Duration::from_secs(secs),
+            }),
+            None if s.contains('=') => Self::parse(payload),
+            None => match s.strip_suffix('~') {
+                Some("version") => None, // ignore deletes of "version"
+                Some(key) => Some(Request::Delete {
+                    key: key.to_string(),
+                }),
+                None => Self::parse(payload),
+            },
+        }
+    }
 }
 
 // Format response for a given key + value
@@ -1,64 +1,234 @@
 use super::protocol::*;
 use crate::Result;
 
-use crate::protohackers::HOST;
+use crate::protohackers::{HOST, bind_udp};
 use std::collections::HashMap;
-use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+/// Requests and responses must be shorter than this, longer requests are ignored.
+const MAX_PACKET_BYTES: usize = 1000;
+
+/// Options of the Unusual Database server.
+#[derive(Debug, Clone)]
+pub struct DbConfig {
+    /// How long a key inserted with `key=value` lives, forever when `None`.
+    pub key_ttl: Option<Duration>,
+    /// How often expired keys are swept from the store.
+    pub sweep_interval: Duration,
+    /// Inserts of longer values are ignored, so repeated inserts can't grow memory unbounded.
+    pub max_value_bytes: usize,
+    /// Accept requests beyond the spec, see `Request::parse_extended`: `key@SECONDS=value`
+    /// sets the lifetime of a single key and `key~` deletes it. Off by default, such keys
+    /// are plain keys otherwise.
+    pub extended_syntax: bool,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self {
+            key_ttl: None,
+            sweep_interval: Duration::from_secs(60),
+            max_value_bytes: MAX_PACKET_BYTES,
+            extended_syntax: false,
+        }
+    }
+}
+
+struct Entry {
+    value: String,
+    expires_at: Option<Instant>,
+}
 
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+#[derive(Clone)]
 struct Db {
-    store: Arc<Mutex<HashMap<String, String>>>,
+    store: Arc<Mutex<HashMap<String, Entry>>>,
+    key_ttl: Option<Duration>,
+    max_value_bytes: usize,
+    extended_syntax: bool,
 }
 
 impl Db {
+    #[cfg(test)]
     fn new() -> Self {
+        Self::with_config(&DbConfig::default())
+    }
+
+    #[cfg(test)]
+    fn extended() -> Self {
+        Self::with_config(&DbConfig {
+            extended_syntax: true,
+            ..DbConfig::default()
+        })
+    }
+
+    #[cfg(test)]
+    fn with_ttl(key_ttl: Option<Duration>) -> Self {
+        Self::with_config(&DbConfig {
+            key_ttl,
+            ..DbConfig::default()
+        })
+    }
+
+    fn with_config(config: &DbConfig) -> Self {
         Self {
             store: Arc::new(Mutex::new(HashMap::new())),
+            key_ttl: config.key_ttl,
+            max_value_bytes: config.max_value_bytes,
+            extended_syntax: config.extended_syntax,
         }
     }
 
     fn insert(&mut self, k: String, v: String) -> Option<String> {
+        self.insert_with_ttl(k, v, self.key_ttl)
+    }
+
+    /// A value longer than `max_value_bytes` is not stored, the key keeps its old value.
+    fn insert_with_ttl(&mut self, k: String, v: String, ttl: Option<Duration>) -> Option<String> {
+        if v.len() > self.max_value_bytes {
+            debug!("Ignore insert of {} bytes into {:?}", v.len(), k);
+            return None;
+        }
+        // a deadline too far to represent is never reached, the key never expires
+        let expires_at = ttl.and_then(|ttl| Instant::now().checked_add(ttl));
+        self.insert_entry(k, v, expires_at)
+    }
+
+    /// Insert a key which never expires, whatever the ttl of the `Db`.
+    fn insert_permanent(&mut self, k: String, v: String) -> Option<String> {
+        self.insert_entry(k, v, None)
+    }
+
+    fn insert_entry(
+        &mut self,
+        k: String,
+        v: String,
+        expires_at: Option<Instant>,
+    ) -> Option<String> {
         let mut s = self.store.lock().unwrap();
-        s.insert(k, v)
+        s.insert(
+            k,
+            Entry {
+                value: v,
+                expires_at,
+            },
+        )
+        .map(|entry| entry.value)
     }
 
     fn retrieve(&self, k: &str) -> Option<String> {
         let s = self.store.lock().unwrap();
-        s.get(k).cloned()
+        s.get(k)
+            .filter(|entry| !entry.is_expired(Instant::now()))
+            .map(|entry| entry.value.clone())
+    }
+
+    fn delete(&mut self, k: &str) -> Option<String> {
+        let mut s = self.store.lock().unwrap();
+        s.remove(k).map(|entry| entry.value)
+    }
+
+    /// Remove every expired key, returns how many were removed.
+    fn sweep_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut s = self.store.lock().unwrap();
+        let before = s.len();
+        s.retain(|_, entry| !entry.is_expired(now));
+        before - s.len()
     }
 }
 
-pub async fn run(port: u32) -> Result<()> {
-    let addr: SocketAddr = format!("{HOST}:{port}").parse().unwrap();
-    let socket = UdpSocket::bind(addr).await?;
+/// Periodically reclaim expired keys, even those which are never retrieved again.
+fn spawn_sweeper(db: Db, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // the first tick completes immediately
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let removed = db.sweep_expired();
+            if removed > 0 {
+                debug!("Swept {} expired keys", removed);
+            }
+        }
+    })
+}
+
+/// Serve the database until `shutdown` is cancelled.
+pub async fn run(port: u32, config: DbConfig, shutdown: CancellationToken) -> Result<()> {
+    let socket = bind_udp(&format!("{HOST}:{port}")).await?;
 
-    let mut db = Db::new();
-    let _ = db.insert(
+    let mut db = Db::with_config(&config);
+    let _ = db.insert_permanent(
         "version".to_string(),
         "Ken's Key-Value Store 1.0".to_string(),
     );
 
+    // keys expire through `key_ttl` or, with the extended syntax, `key@SECONDS=value`
+    let sweeper = spawn_sweeper(db.clone(), config.sweep_interval);
+    let result = serve(&socket, &mut db, &shutdown).await;
+    sweeper.abort();
+    result
+}
+
+async fn serve(socket: &UdpSocket, db: &mut Db, shutdown: &CancellationToken) -> Result<()> {
+    let started = Instant::now();
+    let mut requests = 0usize;
     let mut buf = vec![0u8; 65536];
-    loop {
-        let (len, src_addr) = socket.recv_from(&mut buf).await?;
+    let result = loop {
+        let (len, src_addr) = tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(e) => break Err(e.into()),
+            },
+            _ = shutdown.cancelled() => break Ok(()),
+        };
+        requests += 1;
         let payload = &buf[..len];
 
-        if let Some(resonse) = handle_message(&mut db, payload) {
-            socket.send_to(&resonse, src_addr).await?;
+        if let Some(resonse) = handle_message(db, payload)
+            && let Err(e) = socket.send_to(&resonse, src_addr).await
+        {
+            break Err(e.into());
         }
-    }
+    };
+    info!(requests, uptime = ?started.elapsed(), "Server stopped");
+    result
 }
 
 fn handle_message(db: &mut Db, payload: &[u8]) -> Option<Vec<u8>> {
+    if payload.len() >= MAX_PACKET_BYTES {
+        return None;
+    }
     // Parse request
-    if let Some(req) = Request::parse(payload) {
+    let request = if db.extended_syntax {
+        Request::parse_extended(payload)
+    } else {
+        Request::parse(payload)
+    };
+    if let Some(req) = request {
         match req {
             Request::Insert { key, value } => {
                 // Update store
                 db.insert(key, value);
                 return None;
             }
+            Request::InsertWithTtl { key, value, ttl } => {
+                db.insert_with_ttl(key, value, Some(ttl));
+                None
+            }
             Request::Retrieve { key } => {
                 if let Some(value) = db.retrieve(&key) {
                     let response = format_response(&key, &value);
@@ -70,6 +240,11 @@ fn handle_message(db: &mut Db, payload: &[u8]) -> Option<Vec<u8>> {
                     return Some(response);
                 }
             }
+            Request::Delete { key } => {
+                // like an insert, a delete is not answered
+                db.delete(&key);
+                None
+            }
         }
     } else {
         // If parse fails (e.g., invalid UTF-8), ignore silently (UDP best-effort)
@@ -277,4 +452,239 @@ mod tests {
         let resp = handle_message(&mut db, b"key").unwrap();
         assert_eq!(resp, b"key=new");
     }
+
+    #[test]
+    fn test_parse_delete() {
+        let req = Request::parse_extended(b"foo~").unwrap();
+        assert_eq!(
+            req,
+            Request::Delete {
+                key: "foo".to_string()
+            }
+        );
+        assert!(Request::parse_extended(b"version~").is_none());
+    }
+
+    #[test]
+    fn test_handle_delete_existing_key() {
+        let mut db = Db::extended();
+        handle_message(&mut db, b"foo=bar");
+
+        let resp = handle_message(&mut db, b"foo~");
+        assert!(resp.is_none());
+
+        let resp = handle_message(&mut db, b"foo").unwrap();
+        assert_eq!(resp, b"foo=");
+    }
+
+    #[test]
+    fn test_handle_delete_missing_key() {
+        let mut db = Db::extended();
+        handle_message(&mut db, b"foo=bar");
+
+        let resp = handle_message(&mut db, b"missing~");
+        assert!(resp.is_none());
+
+        let resp = handle_message(&mut db, b"foo").unwrap();
+        assert_eq!(resp, b"foo=bar");
+    }
+
+    #[test]
+    fn tilde_is_a_plain_retrieve_by_default() {
+        let mut db = Db::new();
+        handle_message(&mut db, b"foo=bar");
+        handle_message(&mut db, b"foo~=tilde");
+
+        assert_eq!(handle_message(&mut db, b"foo~").unwrap(), b"foo~=tilde");
+        assert_eq!(handle_message(&mut db, b"foo").unwrap(), b"foo=bar");
+    }
+
+    #[test]
+    fn test_handle_version_delete_ignored() {
+        let mut db = Db::extended();
+        db.insert_permanent(
+            "version".to_string(),
+            "Ken's Key-Value Store 1.0".to_string(),
+        );
+
+        let resp = handle_message(&mut db, b"version~");
+        assert!(resp.is_none());
+
+        let resp = handle_message(&mut db, b"version").unwrap();
+        assert_eq!(resp, b"version=Ken's Key-Value Store 1.0");
+    }
+
+    #[test]
+    fn test_handle_oversized_value_not_stored() {
+        let mut db = Db::with_config(&DbConfig {
+            max_value_bytes: 5,
+            ..DbConfig::default()
+        });
+
+        handle_message(&mut db, b"old=short");
+        handle_message(&mut db, b"old=far too long");
+        handle_message(&mut db, b"new=far too long");
+
+        let resp = handle_message(&mut db, b"old").unwrap();
+        assert_eq!(resp, b"old=short");
+        let resp = handle_message(&mut db, b"new").unwrap();
+        assert_eq!(resp, b"new=");
+    }
+
+    #[test]
+    fn test_handle_oversized_packet_ignored() {
+        let mut db = Db::new();
+
+        let mut insert = b"key=".to_vec();
+        insert.resize(MAX_PACKET_BYTES, b'x');
+        assert!(handle_message(&mut db, &insert).is_none());
+        let resp = handle_message(&mut db, b"key").unwrap();
+        assert_eq!(resp, b"key=");
+
+        // not even answered
+        let retrieve = vec![b'k'; MAX_PACKET_BYTES];
+        assert!(handle_message(&mut db, &retrieve).is_none());
+
+        // just below the limit is fine
+        insert.truncate(MAX_PACKET_BYTES - 1);
+        handle_message(&mut db, &insert);
+        let resp = handle_message(&mut db, b"key").unwrap();
+        assert_eq!(resp, insert);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sweeper_removes_expired_keys() {
+        let mut db = Db::with_ttl(Some(Duration::from_secs(5)));
+        db.insert_permanent("version".to_string(), "1.0".to_string());
+        db.insert("a".to_string(), "1".to_string());
+        db.insert("b".to_string(), "2".to_string());
+        let sweeper = spawn_sweeper(db.clone(), Duration::from_secs(1));
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(db.store.lock().unwrap().len(), 3);
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let store = db.store.lock().unwrap();
+        assert_eq!(store.len(), 1);
+        assert!(store.contains_key("version"));
+        drop(store);
+
+        sweeper.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_key_is_not_retrieved_before_sweep() {
+        let mut db = Db::with_ttl(Some(Duration::from_secs(5)));
+        handle_message(&mut db, b"foo=bar");
+        assert_eq!(handle_message(&mut db, b"foo").unwrap(), b"foo=bar");
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(handle_message(&mut db, b"foo").unwrap(), b"foo=");
+    }
+
+    #[test]
+    fn test_parse_insert_with_ttl() {
+        let req = Request::parse_extended(b"foo@30=bar").unwrap();
+        assert_eq!(
+            req,
+            Request::InsertWithTtl {
+                key: "foo".to_string(),
+                value: "bar".to_string(),
+                ttl: Duration::from_secs(30),
+            }
+        );
+        // no seconds after '@', a plain key
+        let req = Request::parse_extended(b"me@home=bar").unwrap();
+        assert_eq!(
+            req,
+            Request::Insert {
+                key: "me@home".to_string(),
+                value: "bar".to_string()
+            }
+        );
+        assert!(Request::parse_extended(b"version@30=hacked").is_none());
+    }
+
+    #[test]
+    fn timed_insert_syntax_is_off_by_default() {
+        let mut db = Db::new();
+        handle_message(&mut db, b"foo@30=bar");
+        assert_eq!(handle_message(&mut db, b"foo@30").unwrap(), b"foo@30=bar");
+        assert_eq!(handle_message(&mut db, b"foo").unwrap(), b"foo=");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timed_insert_expires_while_plain_insert_stays() {
+        let mut db = Db::extended();
+        db.insert_permanent("version".to_string(), "1.0".to_string());
+        handle_message(&mut db, b"session@5=abc");
+        handle_message(&mut db, b"user=alice");
+
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert_eq!(handle_message(&mut db, b"session").unwrap(), b"session=abc");
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(handle_message(&mut db, b"session").unwrap(), b"session=");
+        assert_eq!(handle_message(&mut db, b"user").unwrap(), b"user=alice");
+        assert_eq!(handle_message(&mut db, b"version").unwrap(), b"version=1.0");
+
+        // and the sweep reclaims it
+        assert_eq!(db.sweep_expired(), 1);
+    }
+
+    #[tokio::test]
+    async fn huge_ttl_never_expires_and_the_server_keeps_answering() -> Result<()> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let server_addr = socket.local_addr()?;
+        let server = tokio::spawn(async move {
+            serve(&socket, &mut Db::extended(), &CancellationToken::new()).await
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        client
+            .send_to(b"k@18446744073709551615=v", server_addr)
+            .await?;
+        client.send_to(b"k", server_addr).await?;
+
+        let mut buf = [0u8; 64];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+            .await
+            .expect("the server should still answer")?;
+        assert_eq!(&buf[..len], b"k=v");
+        assert!(!server.is_finished());
+
+        server.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_logs_the_request_count() -> Result<()> {
+        let (captured, _guard) = crate::testutil::capture_logs();
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let server_addr = socket.local_addr()?;
+        let shutdown = CancellationToken::new();
+        let token = shutdown.clone();
+        let server = tokio::spawn(async move { serve(&socket, &mut Db::new(), &token).await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        client.send_to(b"k=v", server_addr).await?;
+        client.send_to(b"k", server_addr).await?;
+        let mut buf = [0u8; 64];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+            .await
+            .expect("the server should answer")?;
+        assert_eq!(&buf[..len], b"k=v");
+
+        shutdown.cancel();
+        server.await.unwrap()?;
+
+        let logs = captured.text();
+        let stopped: Vec<_> = logs
+            .lines()
+            .filter(|line| line.contains("Server stopped"))
+            .collect();
+        assert_eq!(stopped.len(), 1);
+        assert!(stopped[0].contains("requests=2"));
+        Ok(())
+    }
 }
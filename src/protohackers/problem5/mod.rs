@@ -1,3 +1,3 @@
 mod server;
 
-pub use server::run;
+pub use server::{DEFAULT_PROXY_IDLE_TIMEOUT, ProxyConfig, TONY_ACCOUNT, UPSTREAM_ADDR, run};
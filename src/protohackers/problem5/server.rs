@@ -1,9 +1,13 @@
-use crate::protohackers::HOST;
+use crate::protohackers::ServerBuilder;
 use crate::{Error, Result};
 use futures::{Sink, SinkExt, Stream, StreamExt};
-use tokio::net::{TcpListener, TcpStream};
+use regex::{Captures, Regex};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
 use tokio_util::codec::{Decoder, Encoder, Framed, LinesCodec};
-use tracing::error;
+use tracing::{error, info};
 
 pub struct MessageCodec {
     inner: LinesCodec,
@@ -44,48 +48,83 @@ impl Decoder for MessageCodec {
     }
 }
 
-pub async fn run(port: u32) -> Result<()> {
-    let address = format!("{HOST}:{port}");
-    let listener = TcpListener::bind(address.clone()).await?;
-    loop {
-        let (socket, _addr) = listener.accept().await?;
-        tokio::spawn(handle_client(socket));
+pub const TONY_ACCOUNT: &str = "7YWHMfk9JZe0LM0g1ZauHuiSxhI";
+pub const UPSTREAM_ADDR: &str = "chat.protohackers.com:16963";
+/// How long a proxied connection may relay nothing, either way, before both sides are closed.
+pub const DEFAULT_PROXY_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Where the proxy relays to and whose account it slips into messages.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// `host:port` of the budget chat server.
+    pub upstream_addr: String,
+    /// Every Boguscoin address in a relayed message is replaced with this one.
+    pub target_account: String,
+    /// Both sides are closed once no message went either way for this long,
+    /// so half-open connections do not pile up.
+    pub idle_timeout: Duration,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            upstream_addr: UPSTREAM_ADDR.to_string(),
+            target_account: TONY_ACCOUNT.to_string(),
+            idle_timeout: DEFAULT_PROXY_IDLE_TIMEOUT,
+        }
     }
 }
 
-async fn handle_client(socket: TcpStream) -> Result<()> {
+/// Relay every client connecting to `server` to its own upstream connection.
+pub async fn run(server: ServerBuilder, config: ProxyConfig) -> Result<()> {
+    server
+        .run_with_state(config, |config, socket, _| handle_client(socket, config))
+        .await
+}
+
+async fn handle_client(socket: TcpStream, config: ProxyConfig) -> Result<()> {
     let (sink, stream) = Framed::new(socket, MessageCodec::new()).split();
-    let _ = handle_client_internal(sink, stream).await;
+    let _ = handle_client_internal(&config, sink, stream).await;
     Ok(())
 }
 
-const TONY_ACCOUNT: &str = "7YWHMfk9JZe0LM0g1ZauHuiSxhI";
-const UPSTREAM_HOST: &str = "chat.protohackers.com";
-const UPSTREAM_PORT: &str = "16963";
-
 // https://protohackers.com/problem/5
 // 1. every message I received from client_socket, I need to send it via budget_chat_socket
 // 2. every message I received from budget_chat_socket, I need to send it to client_socket
 // 3. inspect message, find the account and replace it with @tony_account.
 // 4. do 1, and 2 in parallel
 // 5 if connection in 1 or 2 has problem, close the both connection
-async fn handle_client_internal<I, O>(mut client_sink: O, mut client_stream: I) -> Result<()>
+// Whoever speaks first is relayed first, e.g. the upstream's welcome reaches the
+// client before it sends anything.
+async fn handle_client_internal<I, O>(
+    config: &ProxyConfig,
+    mut client_sink: O,
+    mut client_stream: I,
+) -> Result<()>
 where
     I: Stream<Item = Result<String>> + Unpin,
     O: Sink<Message, Error = Error> + Unpin,
 {
-    let upstream = TcpStream::connect(format!("{}:{}", UPSTREAM_HOST, UPSTREAM_PORT)).await?;
+    let upstream = TcpStream::connect(&config.upstream_addr).await?;
 
     let (mut upstream_sink, mut upstream_stream) =
         Framed::new(upstream, MessageCodec::new()).split();
+    // moved forward by every relayed message
+    let idle = tokio::time::sleep(config.idle_timeout);
+    tokio::pin!(idle);
 
     loop {
         tokio::select! {
+            _ = &mut idle => {
+                info!("nothing relayed for {:?}, close both sides", config.idle_timeout);
+                break;
+            }
             // Message from CLIENT -> rewrite -> send to UPSTREAM
             client_msg = client_stream.next() => {
                 match client_msg {
                     Some(Ok(msg)) => {
-                        let rewritten = rewritten_account(&msg);
+                        idle.as_mut().reset(Instant::now() + config.idle_timeout);
+                        let rewritten = rewritten_account(&msg, &config.target_account);
                         if let Err(e) = upstream_sink.send(Message::General(rewritten)).await {
                             error!("failed to send to upstream: {}", e);
                             break;
@@ -104,7 +143,8 @@ where
             upstream_msg = upstream_stream.next() => {
                 match upstream_msg {
                     Some(Ok(msg)) => {
-                        let rewritten = rewritten_account(&msg);
+                        idle.as_mut().reset(Instant::now() + config.idle_timeout);
+                        let rewritten = rewritten_account(&msg, &config.target_account);
                         if let Err(e) = client_sink.send(Message::General(rewritten)).await {
                             error!("failed to send to the client: {}", e);
                             break;
@@ -113,6 +153,7 @@ where
                     }
                     Some(Err(e)) => {
                         error!("upstream_stream error: {}", e);
+                        break;
                     }
                     None => {
                         break;
@@ -122,12 +163,235 @@ where
         }
     }
 
+    // once either side is done, flush and shut down both, so neither is left hanging
+    if let Err(e) = client_sink.close().await {
+        error!("failed to close the client: {}", e);
+    }
+    if let Err(e) = upstream_sink.close().await {
+        error!("failed to close upstream: {}", e);
+    }
+
     Ok(())
 }
 
-fn rewritten_account(msg: &str) -> String {
-    // This pattern captures: (prefix)(address)(suffix)
-    // where prefix is start or space, suffix is space or end
-    let re = regex::Regex::new(r"(^| )7[0-9A-Za-z]{25,34}($| )").unwrap();
-    re.replace_all(msg, TONY_ACCOUNT).into_owned()
+/// Runs of characters which may make up an address. `-` is included so that a
+/// product ID like `7Pv5Z6fmjKRyyXzXjEXk8eFAS5JpG6Oo-1234` is one token, not an address.
+static TOKEN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[0-9A-Za-z-]+").unwrap());
+/// A Boguscoin address: a 7 followed by 25 to 34 alphanumeric characters.
+static ADDRESS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^7[0-9A-Za-z]{25,34}$").unwrap());
+
+/// Replace every Boguscoin address in `msg` with `target_account`. An address is delimited
+/// by the ends of the message or by any character which can't be part of it, e.g. spaces,
+/// tabs or punctuation, and those delimiters are kept as they are.
+fn rewritten_account(msg: &str, target_account: &str) -> String {
+    TOKEN
+        .replace_all(msg, |token: &Captures| {
+            if ADDRESS.is_match(&token[0]) {
+                target_account.to_string()
+            } else {
+                token[0].to_string()
+            }
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    const SAMPLE_ADDRESS: &str = "7F1u3wSD5RbOHQmupo9nx4TnhQ";
+
+    fn rewrite(msg: &str) -> String {
+        rewritten_account(msg, TONY_ACCOUNT).replace(TONY_ACCOUNT, "<tony>")
+    }
+
+    #[test]
+    fn address_is_rewritten_wherever_it_stands_alone() {
+        assert_eq!(
+            rewrite(&format!("{SAMPLE_ADDRESS} is mine")),
+            "<tony> is mine"
+        );
+        assert_eq!(
+            rewrite(&format!("send to {SAMPLE_ADDRESS}")),
+            "send to <tony>"
+        );
+        assert_eq!(rewrite(&format!("a  {SAMPLE_ADDRESS}  b")), "a  <tony>  b");
+        assert_eq!(rewrite(&format!("a\t{SAMPLE_ADDRESS}\tb")), "a\t<tony>\tb");
+        assert_eq!(rewrite(&format!("pay {SAMPLE_ADDRESS}!")), "pay <tony>!");
+        assert_eq!(
+            rewrite(&format!("{SAMPLE_ADDRESS} {SAMPLE_ADDRESS}")),
+            "<tony> <tony>"
+        );
+    }
+
+    #[test]
+    fn partial_and_too_long_tokens_are_left_untouched() {
+        let untouched = [
+            // too short and too long
+            "7F1u3wSD5RbOHQmupo9nx4Tnh".to_string(),
+            "7F1u3wSD5RbOHQmupo9nx4TnhQ0123456789".to_string(),
+            // part of a longer token
+            format!("x{SAMPLE_ADDRESS}"),
+            format!("{SAMPLE_ADDRESS}-1234"),
+            // not starting with 7
+            "8F1u3wSD5RbOHQmupo9nx4TnhQ".to_string(),
+        ];
+        for token in untouched {
+            let msg = format!("hi {token} there");
+            assert_eq!(rewrite(&msg), msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn upstream_welcome_reaches_client_before_it_speaks() -> Result<()> {
+        // the fake budget chat greets as soon as it accepts
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let config = ProxyConfig {
+            upstream_addr: upstream.local_addr()?.to_string(),
+            ..ProxyConfig::default()
+        };
+        let fake_chat = tokio::spawn(async move {
+            let (mut socket, _) = upstream.accept().await?;
+            socket
+                .write_all(b"Welcome to budgetchat! What shall I call you?\n")
+                .await?;
+            // stay connected until the proxy goes away
+            let mut rest = Vec::new();
+            let _ = socket.read_to_end(&mut rest).await;
+            Ok::<_, Error>(())
+        });
+
+        // the client half stays open but silent
+        let (client, proxy_side) = tokio::io::duplex(1024);
+        let (sink, stream) = Framed::new(proxy_side, MessageCodec::new()).split();
+        let proxy =
+            tokio::spawn(async move { handle_client_internal(&config, sink, stream).await });
+
+        let mut client = BufReader::new(client);
+        let mut line = String::new();
+        timeout(Duration::from_secs(1), client.read_line(&mut line))
+            .await
+            .map_err(|_| Error::Other("no welcome relayed".into()))??;
+        assert_eq!(line, "Welcome to budgetchat! What shall I call you?\n");
+
+        proxy.abort();
+        fake_chat.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn boguscoin_address_is_rewritten_before_reaching_upstream() -> Result<()> {
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let config = ProxyConfig {
+            upstream_addr: upstream.local_addr()?.to_string(),
+            target_account: "7LOrwbDlS8NujgjddyogWgIM93MV5N2VR".to_string(),
+            ..ProxyConfig::default()
+        };
+        let fake_chat = tokio::spawn(async move {
+            let (socket, _) = upstream.accept().await?;
+            let mut line = String::new();
+            BufReader::new(socket).read_line(&mut line).await?;
+            Ok::<_, Error>(line)
+        });
+
+        let (client, proxy_side) = tokio::io::duplex(1024);
+        let (sink, stream) = Framed::new(proxy_side, MessageCodec::new()).split();
+        let proxy =
+            tokio::spawn(async move { handle_client_internal(&config, sink, stream).await });
+
+        let (_client_read, mut client_write) = tokio::io::split(client);
+        client_write
+            .write_all(b"7F1u3wSD5RbOHQmupo9nx4TnhQ\n")
+            .await?;
+
+        let line = timeout(Duration::from_secs(1), fake_chat)
+            .await
+            .map_err(|_| Error::Other("nothing relayed upstream".into()))?
+            .unwrap()?;
+        assert_eq!(line, "7LOrwbDlS8NujgjddyogWgIM93MV5N2VR\n");
+
+        proxy.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_is_closed_when_upstream_drops() -> Result<()> {
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let config = ProxyConfig {
+            upstream_addr: upstream.local_addr()?.to_string(),
+            ..ProxyConfig::default()
+        };
+        // the fake budget chat hangs up right away
+        let fake_chat = tokio::spawn(async move {
+            let (socket, _) = upstream.accept().await?;
+            drop(socket);
+            Ok::<_, Error>(())
+        });
+
+        let (client, proxy_side) = tokio::io::duplex(1024);
+        let (sink, stream) = Framed::new(proxy_side, MessageCodec::new()).split();
+        let proxy =
+            tokio::spawn(async move { handle_client_internal(&config, sink, stream).await });
+
+        // the client keeps its side open, still it sees the end of the connection
+        let (mut client_read, _client_write) = tokio::io::split(client);
+        let mut rest = Vec::new();
+        timeout(Duration::from_secs(1), client_read.read_to_end(&mut rest))
+            .await
+            .map_err(|_| Error::Other("client connection left open".into()))??;
+        assert!(rest.is_empty());
+        timeout(Duration::from_secs(1), proxy)
+            .await
+            .unwrap()
+            .unwrap()?;
+
+        fake_chat.await.unwrap()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn silent_connections_are_closed_after_the_idle_timeout() -> Result<()> {
+        let upstream = TcpListener::bind("127.0.0.1:0").await?;
+        let config = ProxyConfig {
+            upstream_addr: upstream.local_addr()?.to_string(),
+            idle_timeout: Duration::from_millis(300),
+            ..ProxyConfig::default()
+        };
+        // the fake budget chat never says anything, it waits for the proxy to hang up
+        let fake_chat = tokio::spawn(async move {
+            let (mut socket, _) = upstream.accept().await?;
+            let mut rest = Vec::new();
+            socket.read_to_end(&mut rest).await?;
+            Ok::<_, Error>(rest)
+        });
+
+        let started = Instant::now();
+        let (client, proxy_side) = tokio::io::duplex(1024);
+        let (sink, stream) = Framed::new(proxy_side, MessageCodec::new()).split();
+        let proxy =
+            tokio::spawn(async move { handle_client_internal(&config, sink, stream).await });
+
+        // neither side sends anything, yet both see the end of the connection
+        let (mut client_read, _client_write) = tokio::io::split(client);
+        let mut rest = Vec::new();
+        timeout(Duration::from_secs(2), client_read.read_to_end(&mut rest))
+            .await
+            .map_err(|_| Error::Other("client connection left open".into()))??;
+        assert!(rest.is_empty());
+        let relayed = timeout(Duration::from_secs(2), fake_chat)
+            .await
+            .map_err(|_| Error::Other("upstream connection left open".into()))?
+            .unwrap()?;
+        assert!(relayed.is_empty());
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        timeout(Duration::from_secs(1), proxy)
+            .await
+            .unwrap()
+            .unwrap()?;
+        Ok(())
+    }
 }
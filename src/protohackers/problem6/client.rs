@@ -1,9 +1,13 @@
 use super::protocol::*;
 use super::state::*;
-use crate::{Error, Result};
+use crate::protohackers::idle_timeout::IdleTimeout;
+use crate::protohackers::transcript::TranscriptStream;
+use crate::{Context, Error, Result};
 use core::net::SocketAddr;
 use futures::stream::SplitSink;
 use futures::{SinkExt, StreamExt};
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
@@ -11,6 +15,10 @@ use tokio::time::{Duration, interval};
 use tokio_util::codec::Framed;
 use tracing::info;
 
+// Dispatchers legitimately stay quiet after identifying themselves,
+// so only disconnect a client which has been silent for a long time.
+const CLIENT_READ_TIMEOUT: Duration = Duration::from_secs(600);
+
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub struct ClientId {
     id: SocketAddr,
@@ -20,7 +28,7 @@ pub struct ClientId {
 pub struct Client {
     pub client_id: ClientId,
     pub role: ClientRole,
-    pub sender: mpsc::UnboundedSender<Message>,
+    pub sender: mpsc::Sender<Message>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -39,8 +47,11 @@ impl PartialEq for Client {
 
 #[derive(Debug)]
 pub struct ClientChannel {
-    pub receiver: mpsc::UnboundedReceiver<Message>,
-    pub sender: mpsc::UnboundedSender<Message>,
+    pub receiver: mpsc::Receiver<Message>,
+    pub sender: mpsc::Sender<Message>,
+    /// Reserved at join, so the error sent before a disconnect is queued even when
+    /// tickets and heartbeats filled the channel.
+    pub error_slot: Option<mpsc::OwnedPermit<Message>>,
 }
 
 impl ClientId {
@@ -52,8 +63,8 @@ impl ClientId {
 impl Client {
     pub fn send(&self, msg: Message) -> Result<()> {
         self.sender
-            .send(msg)
-            .map_err(|_| Error::Other("Client disconnected".into()))
+            .try_send(msg)
+            .map_err(|e| Error::Other(format!("Failed to send to client: {}", e)))
     }
 }
 
@@ -63,9 +74,14 @@ impl ClientChannel {
     }
 
     pub fn send(&mut self, msg: Message) -> Result<()> {
+        if let (Message::Error { .. }, Some(slot)) = (&msg, self.error_slot.take()) {
+            slot.send(msg);
+            return Ok(());
+        }
+        // never wait here: this channel is drained by the same task which calls `send`
         let _ = self
             .sender
-            .send(msg)
+            .try_send(msg)
             .map_err(|e| Error::Other(e.to_string()))?;
         Ok(())
     }
@@ -80,55 +96,110 @@ enum HeartbeatStatus {
     Disabled,
 }
 
-struct ClientState {
+/// What a connection knows about its client, built fresh for each connection.
+pub struct ClientState {
     id: ClientId,
     role: ClientRole,
     heartbeat: HeartbeatStatus,
 }
 
+impl ClientState {
+    pub fn new(id: ClientId) -> Self {
+        Self {
+            id,
+            role: ClientRole::Undefined,
+            heartbeat: HeartbeatStatus::NotStarted,
+        }
+    }
+}
+
 pub async fn handle_client(
-    client_id: ClientId,
     state_tx: StateTx,
+    client_state: ClientState,
     socket: TcpStream,
+    transcript_dir: Option<PathBuf>,
 ) -> Result<()> {
+    let socket = TranscriptStream::new(socket, transcript_dir.as_deref(), client_state.id.id)
+        .await
+        .context("speed daemon transcript")?;
+    handle_client_internal(client_state, state_tx, socket, CLIENT_READ_TIMEOUT)
+        .await
+        .context("speed daemon")
+}
+
+async fn handle_client_internal<T>(
+    mut client_state: ClientState,
+    state_tx: StateTx,
+    socket: T,
+    read_timeout: Duration,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let client_id = client_state.id.clone();
     info!("handle_client: {:?}", client_id);
-    let (mut sink, mut stream) = Framed::new(socket, MessageCodec::new()).split();
+    let (mut sink, stream) = Framed::new(socket, MessageCodec::new()).split();
+    let mut stream = IdleTimeout::new(stream, read_timeout);
 
     let mut client_channel = state_tx.join(client_id.clone())?;
-    let mut client_state = ClientState {
-        id: client_id.clone(),
-        role: ClientRole::Undefined,
-        heartbeat: HeartbeatStatus::NotStarted,
-    };
 
-    loop {
+    let result = loop {
         tokio::select! {
             msg = stream.next() => {
-                 let _ = handle_client_socket_message(&mut client_state, &mut client_channel, &state_tx, msg).await?;
+                // the client closed the connection, or was silent for `read_timeout`
+                let Some(msg) = msg else {
+                    break Ok(());
+                };
+                if let Err(e) = handle_client_socket_message(&mut client_state, &mut client_channel, &state_tx, msg).await {
+                    // the error telling the client why it is disconnected is still queued
+                    let flushed = flush_client_channel(&mut client_channel, &mut sink).await;
+                    break flushed.and(sink.close().await).and(Err(e));
+                }
             }
             Some(msg) = client_channel.recv() => {
-                let _ = handle_message_from_client_channel(&state_tx, msg, &mut sink).await?;
+                if let Err(e) = handle_message_from_client_channel(&state_tx, msg, &mut sink).await {
+                    break Err(e);
+                }
             }
         }
-    }
+    };
 
-    #[allow(unreachable_code)]
     let _ = state_tx.leave(client_id.clone())?;
     info!("client_id: {client_id:?} disconnect");
 
+    result
+}
+
+/// Send everything queued for the client, without waiting for more.
+async fn flush_client_channel<T>(
+    client_channel: &mut ClientChannel,
+    sink: &mut ClientSink<T>,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    while let Ok(msg) = client_channel.receiver.try_recv() {
+        sink.send(msg).await?;
+    }
     Ok(())
 }
 
-type ClientSink = SplitSink<Framed<TcpStream, MessageCodec>, Message>;
+type ClientSink<T> = SplitSink<Framed<T, MessageCodec>, Message>;
 
-async fn handle_message_from_client_channel(
+async fn handle_message_from_client_channel<T>(
     _state: &StateTx,
     msg: Message,
-    sink: &mut ClientSink,
-) -> Result<()> {
+    sink: &mut ClientSink<T>,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
     match msg {
         Message::Error { msg } => {
             let _ = sink.send(Message::Error { msg }).await?;
+            // flush the frame and shut down our side, so the client reads the error then EOF
+            // whatever happens to the socket afterwards
+            sink.close().await?;
             return Err(Error::Other(
                 "disconnect after sending error message to client".into(),
             ));
@@ -171,16 +242,18 @@ async fn handle_client_socket_message(
     client_state: &mut ClientState,
     client_channel: &mut ClientChannel,
     state: &StateTx,
-    msg: Option<Result<Message>>,
+    msg: Result<Message>,
 ) -> Result<()> {
     match msg {
-        None => return Err(Error::Other("client disconnected".into())),
-        Some(Err(_e)) => {
+        Err(e) => {
+            // the stream is unusable after a decoding error, the client must be told
+            // before it is disconnected
             let _ = client_channel.send(Message::Error {
                 msg: "bad message".into(),
             });
+            return Err(Error::InvalidProtocol(format!("bad message: {}", e)));
         }
-        Some(Ok(msg)) => {
+        Ok(msg) => {
             match msg {
                 Message::IAmCamera { road, mile, limit } => match client_state.role {
                     ClientRole::Undefined => {
@@ -192,9 +265,13 @@ async fn handle_client_socket_message(
                     }
 
                     _ => {
-                        let _ = client_channel.send(Message::Error {
+                        client_channel.send(Message::Error {
                             msg: "role validation failed".into(),
-                        });
+                        })?;
+                        return Err(Error::InvalidProtocol(format!(
+                            "client {:?} identified twice",
+                            client_state.id
+                        )));
                     }
                 },
                 Message::IAmDispatcher { numroads: _, roads } => match client_state.role {
@@ -215,6 +292,10 @@ async fn handle_client_socket_message(
                         let _ = client_channel.send(Message::Error {
                             msg: "role validation failed".into(),
                         })?;
+                        return Err(Error::InvalidProtocol(format!(
+                            "client {:?} identified twice",
+                            client_state.id
+                        )));
                     }
                 },
                 Message::Plate { plate, timestamp } => match client_state.role {
@@ -235,12 +316,16 @@ async fn handle_client_socket_message(
                     }
                 },
                 Message::WantHeartbeat { interval } => {
-                    // Enforce: only once (or allow reconfigure?)
+                    // Per spec: multiple WantHeartbeat = error → close connection,
+                    // a disabled heartbeat (interval 0) counts as one too
                     if !matches!(client_state.heartbeat, HeartbeatStatus::NotStarted) {
-                        // Per spec: multiple WantHeartbeat = error → close connection
                         let () = client_channel.send(Message::Error {
                             msg: "Duplicate WantHeartbeat".into(),
                         })?;
+                        return Err(Error::InvalidProtocol(format!(
+                            "client {:?} asked for heartbeats twice",
+                            client_state.id
+                        )));
                     }
 
                     if interval == 0 {
@@ -278,7 +363,7 @@ async fn start_heartbeat_task(
             tokio::select! {
                 // Send heartbeat on tick
                 _ = interval.tick() => {
-                    if client_sender.send(Message::Heartbeat).is_err() {
+                    if client_sender.send(Message::Heartbeat).await.is_err() {
                         break; // client gone
                     }
                 }
@@ -290,3 +375,253 @@ async fn start_heartbeat_task(
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_client_is_disconnected_after_read_timeout() {
+        let state_tx = StateTx::new();
+        let client_id = ClientId::new("127.0.0.1:10".parse().unwrap());
+
+        // keep the client half alive, but never send anything
+        let (_client, server) = tokio::io::duplex(64);
+        let start = tokio::time::Instant::now();
+
+        handle_client_internal(
+            ClientState::new(client_id),
+            state_tx,
+            server,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+
+        assert!(start.elapsed() >= Duration::from_secs(5));
+    }
+
+    /// Connect a client to `state_tx` through an in-memory pipe,
+    /// returns the remote end framed with the protocol codec.
+    fn connect(state_tx: &StateTx, port: u16) -> Framed<tokio::io::DuplexStream, MessageCodec> {
+        let client_id = ClientId::new(SocketAddr::from(([127, 0, 0, 1], port)));
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(handle_client_internal(
+            ClientState::new(client_id),
+            state_tx.clone(),
+            server,
+            CLIENT_READ_TIMEOUT,
+        ));
+        Framed::new(client, MessageCodec::new())
+    }
+
+    #[tokio::test]
+    async fn second_identification_gets_an_error_then_disconnects() -> Result<()> {
+        let state_tx = StateTx::new();
+        let camera = Message::IAmCamera {
+            road: 42,
+            mile: 8,
+            limit: 60,
+        };
+
+        let mut client = connect(&state_tx, 10);
+        client.send(camera.clone()).await?;
+        client.send(camera).await?;
+        assert_eq!(
+            client.next().await.unwrap()?,
+            Message::Error {
+                msg: "role validation failed".to_string().into()
+            }
+        );
+        assert!(client.next().await.is_none());
+
+        let mut client = connect(&state_tx, 11);
+        client
+            .send(Message::IAmDispatcher {
+                numroads: 1,
+                roads: vec![42],
+            })
+            .await?;
+        client
+            .send(Message::IAmCamera {
+                road: 42,
+                mile: 8,
+                limit: 60,
+            })
+            .await?;
+        assert!(matches!(
+            client.next().await.unwrap()?,
+            Message::Error { .. }
+        ));
+        assert!(client.next().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn illegal_message_gets_the_error_frame_before_eof() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let state_tx = StateTx::new();
+        let client_id = ClientId::new("127.0.0.1:10".parse().unwrap());
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handle = tokio::spawn(handle_client_internal(
+            ClientState::new(client_id),
+            state_tx,
+            server,
+            CLIENT_READ_TIMEOUT,
+        ));
+
+        // 0xff is no message type
+        client.write_all(&[0xff]).await?;
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(1), client.read_to_end(&mut received))
+            .await
+            .expect("the server closes the connection")?;
+
+        // Error{msg: "bad message"}, nothing after it
+        let mut expected = vec![0x10, 11];
+        expected.extend_from_slice(b"bad message");
+        assert_eq!(received, expected);
+        assert!(matches!(
+            handle.await.unwrap(),
+            Err(Error::InvalidProtocol(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn duplicate_want_heartbeat_gets_an_error_then_disconnects() -> Result<()> {
+        let state_tx = StateTx::new();
+        for interval in [10, 0] {
+            let mut client = connect(&state_tx, 10);
+            client.send(Message::WantHeartbeat { interval }).await?;
+            client.send(Message::WantHeartbeat { interval }).await?;
+
+            // heartbeats already queued may come first
+            let error = loop {
+                match client.next().await.unwrap()? {
+                    Message::Heartbeat if interval > 0 => continue,
+                    other => break other,
+                }
+            };
+            assert_eq!(
+                error,
+                Message::Error {
+                    msg: "Duplicate WantHeartbeat".to_string().into()
+                }
+            );
+            assert!(client.next().await.is_none(), "interval {}", interval);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_heartbeat_interval_sends_no_heartbeat() -> Result<()> {
+        let state_tx = StateTx::new();
+        let mut client = connect(&state_tx, 10);
+        client.send(Message::WantHeartbeat { interval: 0 }).await?;
+
+        let silence = tokio::time::timeout(Duration::from_secs(60), client.next()).await;
+        assert!(silence.is_err(), "unexpected {:?}", silence);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dispatcher_gets_heartbeats_and_tickets_interleaved() -> Result<()> {
+        let state_tx = StateTx::new();
+
+        let mut dispatcher = connect(&state_tx, 10);
+        dispatcher
+            .send(Message::IAmDispatcher {
+                numroads: 1,
+                roads: vec![42],
+            })
+            .await?;
+        dispatcher
+            .send(Message::WantHeartbeat { interval: 10 })
+            .await?;
+        // the first heartbeat goes out right away
+        assert_eq!(dispatcher.next().await.unwrap()?, Message::Heartbeat);
+
+        // 1 mile in 45 seconds is 80 mph on a 60 mph road
+        for (port, mile, timestamp) in [(11, 8, 0), (12, 9, 45)] {
+            let mut camera = connect(&state_tx, port);
+            camera
+                .send(Message::IAmCamera {
+                    road: 42,
+                    mile,
+                    limit: 60,
+                })
+                .await?;
+            camera
+                .send(Message::Plate {
+                    plate: "UN1X".to_string().into(),
+                    timestamp,
+                })
+                .await?;
+        }
+
+        // every frame decodes cleanly, and heartbeats keep coming after the ticket
+        let ticket = Message::Ticket {
+            plate: "UN1X".to_string().into(),
+            road: 42,
+            mile1: 8,
+            timestamp1: 0,
+            mile2: 9,
+            timestamp2: 45,
+            speed: 8000,
+        };
+        let mut frames = Vec::new();
+        while frames
+            .iter()
+            .filter(|msg| **msg == Message::Heartbeat)
+            .count()
+            < 2
+            || !frames.contains(&ticket)
+        {
+            frames.push(dispatcher.next().await.unwrap()?);
+        }
+        let ticket_at = frames.iter().position(|msg| *msg == ticket).unwrap();
+        assert!(
+            frames
+                .iter()
+                .all(|msg| *msg == ticket || *msg == Message::Heartbeat),
+            "{:?}",
+            frames
+        );
+        assert_eq!(frames.iter().filter(|msg| **msg == ticket).count(), 1);
+        assert_eq!(frames.last(), Some(&Message::Heartbeat));
+        assert!(ticket_at < frames.len() - 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn error_is_queued_when_the_channel_is_full() -> Result<()> {
+        let state_tx = StateTx::new();
+        let mut channel = state_tx.join(ClientId::new("127.0.0.1:10".parse().unwrap()))?;
+        let mut queued = 0;
+        while channel.sender.try_send(Message::Heartbeat).is_ok() {
+            queued += 1;
+        }
+
+        channel.send(Message::Error {
+            msg: "bad message".into(),
+        })?;
+
+        for _ in 0..queued {
+            assert_eq!(channel.recv().await, Some(Message::Heartbeat));
+        }
+        assert_eq!(
+            channel.recv().await,
+            Some(Message::Error {
+                msg: "bad message".into()
+            })
+        );
+        Ok(())
+    }
+}
@@ -3,3 +3,4 @@ mod protocol;
 mod server;
 mod state;
 pub use server::run;
+pub use state::{DEFAULT_SPEED_TOLERANCE_100X, TicketConfig};
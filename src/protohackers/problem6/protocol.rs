@@ -166,7 +166,7 @@ const I_AM_CAMERA_SIZE: usize = U16_SIZE + // road
     U16_SIZE + // mile
     U16_SIZE; // limit
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Message {
     // region:      --- Message for socket
 
@@ -598,107 +598,6 @@ mod encode_tests {
             ]
         );
     }
-
-    // === 0x40: WantHeartbeat ===
-    #[test]
-    fn encode_want_heartbeat_10() {
-        let mut codec = MessageCodec::new();
-        let mut buf = BytesMut::new();
-        codec
-            .encode(Message::WantHeartbeat { interval: 10 }, &mut buf)
-            .unwrap();
-        assert_eq!(buf.as_ref(), &[0x40, 0x00, 0x00, 0x00, 0x0a]);
-    }
-
-    #[test]
-    fn encode_want_heartbeat_1243() {
-        let mut codec = MessageCodec::new();
-        let mut buf = BytesMut::new();
-        codec
-            .encode(Message::WantHeartbeat { interval: 1243 }, &mut buf)
-            .unwrap();
-        assert_eq!(buf.as_ref(), &[0x40, 0x00, 0x00, 0x04, 0xdb]);
-    }
-
-    // === 0x41: Heartbeat ===
-    #[test]
-    fn encode_heartbeat() {
-        let mut codec = MessageCodec::new();
-        let mut buf = BytesMut::new();
-        codec.encode(Message::Heartbeat, &mut buf).unwrap();
-        assert_eq!(buf.as_ref(), &[0x41]);
-    }
-
-    // === 0x80: IAmCamera ===
-    #[test]
-    fn encode_i_am_camera_66() {
-        let mut codec = MessageCodec::new();
-        let mut buf = BytesMut::new();
-        codec
-            .encode(
-                Message::IAmCamera {
-                    road: 66,
-                    mile: 100,
-                    limit: 60,
-                },
-                &mut buf,
-            )
-            .unwrap();
-        assert_eq!(buf.as_ref(), &[0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c]);
-    }
-
-    #[test]
-    fn encode_i_am_camera_368() {
-        let mut codec = MessageCodec::new();
-        let mut buf = BytesMut::new();
-        codec
-            .encode(
-                Message::IAmCamera {
-                    road: 368,
-                    mile: 1234,
-                    limit: 40,
-                },
-                &mut buf,
-            )
-            .unwrap();
-        assert_eq!(buf.as_ref(), &[0x80, 0x01, 0x70, 0x04, 0xd2, 0x00, 0x28]);
-    }
-
-    // === 0x81: IAmDispatcher ===
-    #[test]
-    fn encode_i_am_dispatcher_single() {
-        let mut codec = MessageCodec::new();
-        let mut buf = BytesMut::new();
-        codec
-            .encode(
-                Message::IAmDispatcher {
-                    numroads: 1,
-                    roads: vec![66],
-                },
-                &mut buf,
-            )
-            .unwrap();
-        assert_eq!(buf.as_ref(), &[0x81, 0x01, 0x00, 0x42]);
-    }
-
-    #[test]
-    fn encode_i_am_dispatcher_multi() {
-        let mut codec = MessageCodec::new();
-        let mut buf = BytesMut::new();
-        codec
-            .encode(
-                Message::IAmDispatcher {
-                    numroads: 3,
-                    roads: vec![66, 368, 5000],
-                },
-                &mut buf,
-            )
-            .unwrap();
-        assert_eq!(
-            buf.as_ref(),
-            &[0x81, 0x03, 0x00, 0x42, 0x01, 0x70, 0x13, 0x88]
-        );
-    }
 }
 
 #[cfg(test)]
@@ -807,99 +706,111 @@ mod decode_tests {
         );
     }
 
-    // === 0x40: WantHeartbeat ===
-    #[test]
-    fn decode_want_heartbeat_10() {
-        let msg = decode_single(MessageCodec::new(), &[0x40, 0x00, 0x00, 0x00, 0x0a]);
-        assert_eq!(msg, Message::WantHeartbeat { interval: 10 });
-    }
-
-    #[test]
-    fn decode_want_heartbeat_1243() {
-        let msg = decode_single(MessageCodec::new(), &[0x40, 0x00, 0x00, 0x04, 0xdb]);
-        assert_eq!(msg, Message::WantHeartbeat { interval: 1243 });
-    }
-
-    // === 0x41: Heartbeat ===
-    #[test]
-    fn decode_heartbeat() {
-        let msg = decode_single(MessageCodec::new(), &[0x41]);
-        assert_eq!(msg, Message::Heartbeat);
-    }
-
-    // === 0x80: IAmCamera ===
+    // === Partial / Streaming Decoding (Optional but Recommended) ===
     #[test]
-    fn decode_i_am_camera_66() {
-        let msg = decode_single(
-            MessageCodec::new(),
-            &[0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c],
-        );
-        assert_eq!(
-            msg,
-            Message::IAmCamera {
-                road: 66,
-                mile: 100,
-                limit: 60,
-            }
-        );
+    fn decode_partial_heartbeat() {
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::from(&[0x41][..1]); // only 1 byte
+        assert!(codec.decode(&mut buf).unwrap().is_some()); // should decode immediately
     }
 
     #[test]
-    fn decode_i_am_camera_368() {
-        let msg = decode_single(
-            MessageCodec::new(),
-            &[0x80, 0x01, 0x70, 0x04, 0xd2, 0x00, 0x28],
-        );
-        assert_eq!(
-            msg,
-            Message::IAmCamera {
-                road: 368,
-                mile: 1234,
-                limit: 40,
-            }
-        );
+    fn decode_partial_plate_needs_more() {
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::from(&[0x20, 0x04][..]); // has tag + len, but no string yet
+        assert!(codec.decode(&mut buf).unwrap().is_none()); // not enough for "UN1X"
     }
 
-    // === 0x81: IAmDispatcher ===
     #[test]
-    fn decode_i_am_dispatcher_single() {
-        let msg = decode_single(MessageCodec::new(), &[0x81, 0x01, 0x00, 0x42]);
+    fn decode_i_am_dispatcher_split_mid_roads() {
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::from(&[0x81, 0x03, 0x00, 0x42][..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
         assert_eq!(
-            msg,
-            Message::IAmDispatcher {
-                numroads: 1,
-                roads: vec![66],
-            }
+            buf.len(),
+            4,
+            "Nothing should be consumed before the message completes"
         );
-    }
 
-    #[test]
-    fn decode_i_am_dispatcher_multi() {
-        let msg = decode_single(
-            MessageCodec::new(),
-            &[0x81, 0x03, 0x00, 0x42, 0x01, 0x70, 0x13, 0x88],
-        );
+        // the rest of the roads arrive one byte at a time
+        let rest = [0x01, 0x70, 0x13, 0x88];
+        for byte in &rest[..rest.len() - 1] {
+            buf.extend_from_slice(&[*byte]);
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+        buf.extend_from_slice(&rest[rest.len() - 1..]);
         assert_eq!(
-            msg,
-            Message::IAmDispatcher {
+            codec.decode(&mut buf).unwrap(),
+            Some(Message::IAmDispatcher {
                 numroads: 3,
                 roads: vec![66, 368, 5000],
-            }
+            })
         );
+        assert!(buf.is_empty());
     }
+}
 
-    // === Partial / Streaming Decoding (Optional but Recommended) ===
-    #[test]
-    fn decode_partial_heartbeat() {
-        let mut codec = MessageCodec::new();
-        let mut buf = BytesMut::from(&[0x41][..1]); // only 1 byte
-        assert!(codec.decode(&mut buf).unwrap().is_some()); // should decode immediately
-    }
+#[cfg(test)]
+mod roundtrip_tests {
+    use super::*;
+    use crate::testutil::roundtrip_codec;
 
-    #[test]
-    fn decode_partial_plate_needs_more() {
-        let mut codec = MessageCodec::new();
-        let mut buf = BytesMut::from(&[0x20, 0x04][..]); // has tag + len, but no string yet
-        assert!(codec.decode(&mut buf).unwrap().is_none()); // not enough for "UN1X"
-    }
+    // === 0x40: WantHeartbeat ===
+    roundtrip_codec!(
+        want_heartbeat_10,
+        MessageCodec::new(),
+        Message::WantHeartbeat { interval: 10 },
+        &[0x40, 0x00, 0x00, 0x00, 0x0a],
+    );
+    roundtrip_codec!(
+        want_heartbeat_1243,
+        MessageCodec::new(),
+        Message::WantHeartbeat { interval: 1243 },
+        &[0x40, 0x00, 0x00, 0x04, 0xdb],
+    );
+
+    // === 0x41: Heartbeat ===
+    roundtrip_codec!(heartbeat, MessageCodec::new(), Message::Heartbeat, &[0x41]);
+
+    // === 0x80: IAmCamera ===
+    roundtrip_codec!(
+        i_am_camera_66,
+        MessageCodec::new(),
+        Message::IAmCamera {
+            road: 66,
+            mile: 100,
+            limit: 60,
+        },
+        &[0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c],
+    );
+    roundtrip_codec!(
+        i_am_camera_368,
+        MessageCodec::new(),
+        Message::IAmCamera {
+            road: 368,
+            mile: 1234,
+            limit: 40,
+        },
+        &[0x80, 0x01, 0x70, 0x04, 0xd2, 0x00, 0x28],
+    );
+
+    // === 0x81: IAmDispatcher ===
+    roundtrip_codec!(
+        i_am_dispatcher_single,
+        MessageCodec::new(),
+        Message::IAmDispatcher {
+            numroads: 1,
+            roads: vec![66],
+        },
+        &[0x81, 0x01, 0x00, 0x42],
+    );
+    roundtrip_codec!(
+        i_am_dispatcher_multi,
+        MessageCodec::new(),
+        Message::IAmDispatcher {
+            numroads: 3,
+            roads: vec![66, 368, 5000],
+        },
+        &[0x81, 0x03, 0x00, 0x42, 0x01, 0x70, 0x13, 0x88],
+    );
 }
@@ -3,19 +3,25 @@
 use super::client::*;
 use super::state::*;
 use crate::Result;
-use crate::protohackers::HOST;
-use tokio::net::TcpListener;
-use tracing::info;
+use crate::protohackers::ServerBuilder;
+use std::path::PathBuf;
 
-pub async fn run(port: u32) -> Result<()> {
-    let address = format!("{HOST}:{port}");
-    let listener = TcpListener::bind(address.clone()).await?;
-    info!("problem6 listen on: {}", address);
-    let state_tx = StateTx::new();
+/// Serve the speed daemon with the port, limits and shutdown token of `server`,
+/// recording each connection into `transcript_dir` when set.
+pub async fn run(
+    server: ServerBuilder,
+    transcript_dir: Option<PathBuf>,
+    config: TicketConfig,
+) -> Result<()> {
+    let state_tx = StateTx::with_config(config);
 
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        let client_id = ClientId::new(addr);
-        tokio::spawn(handle_client(client_id, state_tx.clone(), socket));
-    }
+    server
+        .run_with_factory(
+            state_tx,
+            |_, addr| ClientState::new(ClientId::new(addr)),
+            move |state_tx, client_state, socket, _| {
+                handle_client(state_tx, client_state, socket, transcript_dir.clone())
+            },
+        )
+        .await
 }
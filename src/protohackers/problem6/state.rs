@@ -7,6 +7,12 @@ use std::collections::HashSet;
 use tokio::sync::mpsc;
 use tracing::{error, info};
 
+// How many messages (tickets, heartbeats, errors) can queue up for a single client
+const CLIENT_CHANNEL_CAPACITY: usize = 64;
+
+/// The spec tickets cars going 0.5 mph or more over the limit, in hundredths of mph
+pub const DEFAULT_SPEED_TOLERANCE_100X: u16 = 50;
+
 #[derive(Debug, Clone)]
 pub struct StateTx {
     sender: mpsc::UnboundedSender<Message>,
@@ -24,19 +30,53 @@ impl StateChannel {
     }
 }
 
+/// Policies applied when turning plate observations into tickets.
+#[derive(Debug, Clone)]
+pub struct TicketConfig {
+    /// Ignore an observation whose timestamp is older than the newest one seen for the same
+    /// plate on the same road by more than this many seconds, so replayed packets can not
+    /// trigger or suppress tickets. Observations delivered out of order within it are kept.
+    pub replay_window: Option<u32>,
+    /// How far above the limit, in hundredths of mph, a car may go before it is ticketed.
+    /// A car at or below the limit is never ticketed, even with no tolerance.
+    pub speed_tolerance_100x: u16,
+}
+
+impl Default for TicketConfig {
+    fn default() -> Self {
+        Self {
+            replay_window: None,
+            speed_tolerance_100x: DEFAULT_SPEED_TOLERANCE_100X,
+        }
+    }
+}
+
 impl StateTx {
+    #[cfg(test)]
     pub fn new() -> Self {
+        Self::with_config(TicketConfig::default())
+    }
+
+    pub fn with_config(config: TicketConfig) -> Self {
         let (tx, rx) = mpsc::unbounded_channel();
-        tokio::spawn(run_state(StateChannel {
-            receiver: rx,
-            sender: tx.clone(),
-        }));
+        tokio::spawn(run_state(
+            StateChannel {
+                receiver: rx,
+                sender: tx.clone(),
+            },
+            config,
+        ));
         StateTx { sender: tx }
     }
 
     // A client will Join the State and return a ClientHandle
     pub fn join(&self, client_id: ClientId) -> Result<ClientChannel> {
-        let (client_tx, client_rx) = mpsc::unbounded_channel::<Message>();
+        // one more slot, kept for the error sent to the client before it is disconnected
+        let (client_tx, client_rx) = mpsc::channel::<Message>(CLIENT_CHANNEL_CAPACITY + 1);
+        let error_slot = client_tx
+            .clone()
+            .try_reserve_owned()
+            .map_err(|e| Error::Other(e.to_string()))?;
 
         let _ = self
             .sender
@@ -52,6 +92,7 @@ impl StateTx {
         return Ok(ClientChannel {
             sender: client_tx,
             receiver: client_rx,
+            error_slot: Some(error_slot),
         });
     }
 
@@ -95,13 +136,14 @@ impl PlateTracker {
 }
 
 struct TicketManager {
+    config: TicketConfig,
     roads: HashMap<RoadInfo, PlateTracker>,
     ticketed: HashSet<(Plate, u32)>,
     pending_tickets: Vec<Ticket>,
     dispatcher_registry: HashMap<u16, HashSet<ClientId>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 struct Ticket {
     plate: String,
     road: u16,
@@ -113,8 +155,14 @@ struct Ticket {
 }
 
 impl TicketManager {
+    #[cfg(test)]
     fn new() -> Self {
+        Self::with_config(TicketConfig::default())
+    }
+
+    fn with_config(config: TicketConfig) -> Self {
         TicketManager {
+            config,
             roads: HashMap::new(),
             ticketed: HashSet::new(),
             pending_tickets: vec![],
@@ -159,24 +207,21 @@ impl TicketManager {
 
         let mut tickets_to_keep = Vec::new();
 
-        // Dispatch tickets (move ticket, no clone)
+        // Dispatch tickets, a ticket which can't be delivered right now
+        // (e.g. the dispatcher's channel is full) is kept and retried on the next flush.
         for ticket in tickets {
             if let Some(dispatcher_ids) = self.dispatcher_registry.get(&ticket.road) {
                 if let Some(dispatcher_id) = dispatcher_ids.iter().next() {
-                    let dispatcher = clients.get(dispatcher_id).unwrap();
+                    let Some(dispatcher) = clients.get(dispatcher_id) else {
+                        tickets_to_keep.push(ticket);
+                        continue;
+                    };
 
                     info!("Sending ticket to dispatcher: {:?}", dispatcher_id);
-                    let _ = dispatcher
-                        .send(Message::Ticket {
-                            plate: ticket.plate.into(),
-                            road: ticket.road,
-                            mile1: ticket.mile1,
-                            timestamp1: ticket.timestamp1,
-                            mile2: ticket.mile2,
-                            timestamp2: ticket.timestamp2,
-                            speed: ticket.speed,
-                        })
-                        .map_err(|e| Error::Other(e.to_string()))?;
+                    if let Err(e) = dispatcher.send(ticket.clone().into()) {
+                        info!("keep ticket {:?} for retry: {}", ticket, e);
+                        tickets_to_keep.push(ticket);
+                    }
                 } else {
                     info!("Dispatcher set is empty for road {}", ticket.road);
                     tickets_to_keep.push(ticket);
@@ -220,6 +265,18 @@ impl TicketManager {
             .entry(plate_key.clone())
             .or_insert_with(|| BTreeMap::new());
 
+        // Ignore replayed observations, far older than what we already know
+        if let (Some(window), Some((newest, _))) =
+            (self.config.replay_window, plate_events.last_key_value())
+            && newest.0.saturating_sub(timestamp) > window
+        {
+            info!(
+                "ignore replayed observation of {plate} at {timestamp}, newest is {}",
+                newest.0
+            );
+            return None;
+        }
+
         // Add new event
         plate_events.insert(ts_val, mile_val);
 
@@ -238,16 +295,19 @@ impl TicketManager {
 
             let delta_mile = m2.0.abs_diff(m1.0);
             let speed_100x = compute_speed_100x(delta_mile, delta_time);
-            let threshold = road_info.limit * 100 + 50;
+            // in u32, a limit above 655 mph would overflow u16
+            let threshold =
+                road_info.limit as u32 * 100 + self.config.speed_tolerance_100x.max(1) as u32;
 
-            if speed_100x < threshold {
+            if (speed_100x as u32) < threshold {
                 continue;
             }
 
             let day1 = day_from_timestamp(ts1.0);
             let day2 = day_from_timestamp(ts2.0);
 
-            // Check if already ticketed on any day in [day1, day2]
+            // Per the spec a ticket over 2 days counts for both of them, so the car must not
+            // get another ticket on either. Check if already ticketed on any day in [day1, day2]
             let violates_limit =
                 (day1..=day2).any(|day| self.ticketed.contains(&(plate_key.clone(), day)));
 
@@ -260,6 +320,13 @@ impl TicketManager {
                 self.ticketed.insert((plate_key.clone(), day));
             }
 
+            // a ticket must never be issued for a car at or below the limit
+            debug_assert!(
+                speed_100x as u32 > road_info.limit as u32 * 100,
+                "spurious ticket: speed {} on road limited to {}",
+                speed_100x,
+                road_info.limit
+            );
             return Some(Ticket {
                 plate: plate.to_string(),
                 road: road_info.road,
@@ -275,10 +342,24 @@ impl TicketManager {
     }
 }
 
-async fn run_state(mut state_channel: StateChannel) -> Result<()> {
+impl From<Ticket> for Message {
+    fn from(ticket: Ticket) -> Self {
+        Message::Ticket {
+            plate: ticket.plate.into(),
+            road: ticket.road,
+            mile1: ticket.mile1,
+            timestamp1: ticket.timestamp1,
+            mile2: ticket.mile2,
+            timestamp2: ticket.timestamp2,
+            speed: ticket.speed,
+        }
+    }
+}
+
+async fn run_state(mut state_channel: StateChannel, config: TicketConfig) -> Result<()> {
     // initalize state
     let mut clients: HashMap<ClientId, Client> = HashMap::new();
-    let mut ticket_manager = TicketManager::new();
+    let mut ticket_manager = TicketManager::with_config(config);
     // let mut pending_tickets: Vec<Ticket> = Vec::new();
 
     // loop receive message from handle
@@ -298,7 +379,13 @@ async fn run_state(mut state_channel: StateChannel) -> Result<()> {
                 }
             }
             Message::DispatcherObservation { client_id, roads } => {
-                let client = clients.get_mut(&client_id).unwrap();
+                let Some(client) = clients.get_mut(&client_id) else {
+                    error!(
+                        "dispatcher {:?} has not joined, ignore its roads",
+                        client_id
+                    );
+                    continue;
+                };
                 client.role = ClientRole::Dispatcher {
                     roads: roads.clone(),
                 };
@@ -343,9 +430,293 @@ fn day_from_timestamp(ts: u32) -> u32 {
     ts / 86400
 }
 
-// Integer-only speed calculation: returns speed * 100
+// Integer-only speed calculation: returns speed * 100.
+// Saturates at u16::MAX (655.35 mph), the largest speed a ticket can carry,
+// instead of wrapping around for short time deltas.
 fn compute_speed_100x(delta_mile: u16, delta_time: u32) -> u16 {
     let dm = delta_mile as u64;
     let dt = delta_time as u64;
-    ((dm * 360_000) / dt) as u16 // 3600 sec/hour * 100
+    let speed_100x = (dm * 360_000) / dt; // 3600 sec/hour * 100
+    speed_100x.min(u16::MAX as u64) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ticket(plate: &str, road: u16) -> Ticket {
+        Ticket {
+            plate: plate.to_string(),
+            road,
+            mile1: 8,
+            timestamp1: 0,
+            mile2: 9,
+            timestamp2: 45,
+            speed: 8000,
+        }
+    }
+
+    fn dispatcher(
+        port: u16,
+        roads: Vec<u16>,
+        capacity: usize,
+    ) -> (Client, mpsc::Receiver<Message>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let client = Client {
+            client_id: ClientId::new(format!("127.0.0.1:{port}").parse().unwrap()),
+            role: ClientRole::Dispatcher { roads },
+            sender: tx,
+        };
+        (client, rx)
+    }
+
+    #[test]
+    fn tickets_are_retained_while_dispatcher_channel_is_full() -> Result<()> {
+        let mut manager = TicketManager::new();
+        let (client, mut rx) = dispatcher(10, vec![66], 1);
+        manager.register_dispatcher(client.client_id.clone(), vec![66]);
+        let clients = HashMap::from([(client.client_id.clone(), client)]);
+
+        manager.add_ticket(ticket("UN1X", 66));
+        manager.add_ticket(ticket("RE05BKG", 66));
+
+        // only one slot in the channel, the second ticket stays pending
+        manager.flush_pending_tickets(&clients)?;
+        assert_eq!(manager.pending_tickets, vec![ticket("RE05BKG", 66)]);
+        assert_eq!(rx.try_recv().unwrap(), ticket("UN1X", 66).into());
+
+        // space is freed, the retained ticket is delivered on the next flush
+        manager.flush_pending_tickets(&clients)?;
+        assert!(manager.pending_tickets.is_empty());
+        assert_eq!(rx.try_recv().unwrap(), ticket("RE05BKG", 66).into());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pending_ticket_is_delivered_when_a_dispatcher_connects() -> Result<()> {
+        let state_tx = StateTx::new();
+        let camera = ClientId::new("127.0.0.1:11".parse().unwrap());
+        state_tx.join(camera.clone())?;
+        for (mile, timestamp) in [(8, 0), (9, 45)] {
+            state_tx.send(Message::PlateObservation {
+                client_id: camera.clone(),
+                road: 66,
+                mile,
+                limit: 60,
+                plate: "UN1X".to_string(),
+                timestamp,
+            })?;
+        }
+
+        // the ticket is waiting, no dispatcher was there for road 66
+        let dispatcher = ClientId::new("127.0.0.1:10".parse().unwrap());
+        let mut channel = state_tx.join(dispatcher.clone())?;
+        state_tx.send(Message::DispatcherObservation {
+            client_id: dispatcher,
+            roads: vec![66],
+        })?;
+
+        let delivered = tokio::time::timeout(Duration::from_secs(1), channel.receiver.recv())
+            .await
+            .map_err(|_| Error::Other("pending ticket was not delivered".into()))?;
+        assert_eq!(delivered, Some(ticket("UN1X", 66).into()));
+
+        Ok(())
+    }
+
+    /// Observe "UN1X" at mile 0 then at `miles` after `seconds` on road 66 limited to 60 mph.
+    fn observe_trip(miles: u16, seconds: u32) -> Option<Ticket> {
+        let mut manager = TicketManager::new();
+        assert!(
+            manager
+                .add_plate_observation(66, 0, 60, "UN1X", 0)
+                .is_none()
+        );
+        manager.add_plate_observation(66, miles, 60, "UN1X", seconds)
+    }
+
+    #[test]
+    fn no_ticket_at_exactly_the_limit() {
+        // 120 miles in 2 hours is 60 mph
+        assert_eq!(observe_trip(120, 7200), None);
+    }
+
+    #[test]
+    fn no_ticket_half_a_mile_under_the_limit() {
+        // 119 miles in 2 hours is 59.5 mph
+        assert_eq!(observe_trip(119, 7200), None);
+    }
+
+    #[test]
+    fn ticket_half_a_mile_over_the_limit() {
+        // 121 miles in 2 hours is 60.5 mph, the first speed which is ticketed
+        let ticket = observe_trip(121, 7200).expect("60.5 mph should be ticketed");
+        assert_eq!(ticket.speed, 6050);
+
+        // just below 60.5 mph is still tolerated
+        assert_eq!(observe_trip(121, 7201), None);
+    }
+
+    /// Like `observe_trip`, with `tolerance` hundredths of mph allowed over the limit.
+    fn observe_trip_with_tolerance(tolerance: u16, miles: u16, seconds: u32) -> Option<Ticket> {
+        let mut manager = TicketManager::with_config(TicketConfig {
+            speed_tolerance_100x: tolerance,
+            ..TicketConfig::default()
+        });
+        assert!(
+            manager
+                .add_plate_observation(66, 0, 60, "UN1X", 0)
+                .is_none()
+        );
+        manager.add_plate_observation(66, miles, 60, "UN1X", seconds)
+    }
+
+    #[test]
+    fn ticket_boundary_follows_the_configured_tolerance() {
+        // 2 mph: 124 miles in 2 hours is 62 mph, the first speed which is ticketed
+        let ticket = observe_trip_with_tolerance(200, 124, 7200).expect("62 mph is ticketed");
+        assert_eq!(ticket.speed, 6200);
+        assert_eq!(observe_trip_with_tolerance(200, 124, 7201), None);
+        // 60.5 mph would be ticketed by default
+        assert_eq!(observe_trip_with_tolerance(200, 121, 7200), None);
+
+        // no tolerance: anything above the limit, but never the limit itself
+        assert_eq!(observe_trip_with_tolerance(0, 120, 7200), None);
+        // 121 miles in 7258 seconds is 60.01 mph
+        let ticket = observe_trip_with_tolerance(0, 121, 7258).expect("above 60 mph is ticketed");
+        assert_eq!(ticket.speed, 6001);
+    }
+
+    /// "UN1X" drives 1 mile in 45 seconds (80 mph) on `road` limited to 60 mph, from `start`.
+    fn speeding(manager: &mut TicketManager, road: u16, start: u32) -> Option<Ticket> {
+        assert!(
+            manager
+                .add_plate_observation(road, 0, 60, "UN1X", start)
+                .is_none()
+        );
+        manager.add_plate_observation(road, 1, 60, "UN1X", start + 45)
+    }
+
+    #[test]
+    fn one_ticket_per_day_within_a_single_day() {
+        let mut manager = TicketManager::new();
+        assert!(speeding(&mut manager, 1, 1_000).is_some());
+        // same day, on another road
+        assert_eq!(speeding(&mut manager, 2, 50_000), None);
+        // the next day
+        assert!(speeding(&mut manager, 3, 86_400 + 1_000).is_some());
+    }
+
+    #[test]
+    fn ticket_spanning_midnight_counts_for_both_days() {
+        let mut manager = TicketManager::new();
+        // from day 0 to day 1
+        let ticket = speeding(&mut manager, 1, 86_380).expect("first violation is ticketed");
+        assert_eq!((ticket.timestamp1, ticket.timestamp2), (86_380, 86_425));
+
+        // day 0 and day 1 are both taken
+        assert_eq!(speeding(&mut manager, 2, 1_000), None);
+        assert_eq!(speeding(&mut manager, 3, 86_400 + 1_000), None);
+        // day 2 is not
+        assert!(speeding(&mut manager, 4, 2 * 86_400 + 1_000).is_some());
+    }
+
+    #[test]
+    fn violation_spanning_midnight_is_suppressed_by_either_day() {
+        // only day 1 was ticketed, a violation over days 0 and 1 is suppressed
+        let mut manager = TicketManager::new();
+        assert!(speeding(&mut manager, 1, 86_400 + 1_000).is_some());
+        assert_eq!(speeding(&mut manager, 2, 86_380), None);
+
+        // with neither day ticketed it is issued
+        let mut manager = TicketManager::new();
+        assert!(speeding(&mut manager, 1, 86_380).is_some());
+    }
+
+    #[test]
+    fn speed_saturates_instead_of_wrapping() {
+        // 1 mile in 1 second is 3600 mph
+        assert_eq!(compute_speed_100x(1, 1), u16::MAX);
+        assert_eq!(compute_speed_100x(5, 1), u16::MAX);
+        // 0.18 mile per second is 648 mph, the last speeds below the cap are exact
+        assert_eq!(compute_speed_100x(18, 100), 64_800);
+    }
+
+    #[test]
+    fn fast_car_is_ticketed_at_the_capped_speed() {
+        // 5 miles in 1 second used to wrap around to a speed below the limit
+        let mut manager = TicketManager::new();
+        assert!(
+            manager
+                .add_plate_observation(66, 0, 60, "UN1X", 1000)
+                .is_none()
+        );
+        let ticket = manager
+            .add_plate_observation(66, 5, 60, "UN1X", 1001)
+            .expect("18000 mph is ticketed");
+        assert_eq!(ticket.speed, u16::MAX);
+    }
+
+    #[test]
+    fn high_limit_does_not_overflow_the_threshold() {
+        // 655 * 100 + 50 does not fit in a u16
+        let mut manager = TicketManager::new();
+        assert!(
+            manager
+                .add_plate_observation(66, 0, 655, "UN1X", 1000)
+                .is_none()
+        );
+        assert_eq!(
+            manager.add_plate_observation(66, 1, 655, "UN1X", 1010),
+            None
+        );
+    }
+
+    #[test]
+    fn replayed_old_observation_is_ignored() {
+        let replay_policy = TicketConfig {
+            replay_window: Some(60),
+            ..TicketConfig::default()
+        };
+
+        // 50 miles in 1000 seconds is 180 mph, but the first observation is a replay
+        let mut manager = TicketManager::with_config(replay_policy.clone());
+        assert!(
+            manager
+                .add_plate_observation(66, 50, 60, "UN1X", 10_000)
+                .is_none()
+        );
+        assert!(
+            manager
+                .add_plate_observation(66, 0, 60, "UN1X", 9_000)
+                .is_none()
+        );
+
+        // without the policy the same observations give a ticket
+        let mut manager = TicketManager::new();
+        assert!(
+            manager
+                .add_plate_observation(66, 50, 60, "UN1X", 10_000)
+                .is_none()
+        );
+        assert!(
+            manager
+                .add_plate_observation(66, 0, 60, "UN1X", 9_000)
+                .is_some()
+        );
+
+        // out of order delivery within the window is still legitimate
+        let mut manager = TicketManager::with_config(replay_policy);
+        assert!(
+            manager
+                .add_plate_observation(66, 50, 60, "UN1X", 10_000)
+                .is_none()
+        );
+        let ticket = manager
+            .add_plate_observation(66, 49, 60, "UN1X", 9_970)
+            .expect("1 mile in 30 seconds should be ticketed");
+        assert_eq!((ticket.timestamp1, ticket.timestamp2), (9_970, 10_000));
+    }
 }
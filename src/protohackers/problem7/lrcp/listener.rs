@@ -1,10 +1,10 @@
 use std::collections::HashMap;
-use tokio::net::UdpSocket;
 use tokio::sync::mpsc;
 
 use super::protocol::*;
 use super::session::*;
 use super::stream::*;
+use crate::protohackers::bind_udp;
 use crate::{Error, Result};
 use std::net::SocketAddr;
 use tracing::debug;
@@ -16,6 +16,7 @@ pub struct LrcpListener {
     // pub udp_tx: mpsc::UnboundedSender<UdpPacket>,
     // pub accept_tx: mpsc::UnboundedSender<(LrcpStream, SocketAddr)>,
     pub accept_rx: mpsc::UnboundedReceiver<LrcpStreamPair>,
+    local_addr: SocketAddr,
 }
 
 impl LrcpListener {
@@ -28,8 +29,21 @@ impl LrcpListener {
         Ok((lrcp_accept_result.stream, lrcp_accept_result.addr))
     }
 
+    /// The address the listener is bound to, e.g. to find the port picked for port 0.
+    #[allow(unused)]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Each listener owns its socket, sessions and tasks, so several of them can
+    /// run in one process, each on its own port.
     pub async fn bind(addr: &str) -> Result<Self> {
-        let socket = UdpSocket::bind(addr).await?;
+        Self::bind_with_config(addr, LrcpConfig::default()).await
+    }
+
+    pub async fn bind_with_config(addr: &str, config: LrcpConfig) -> Result<Self> {
+        let socket = bind_udp(addr).await?;
+        let local_addr = socket.local_addr()?;
         let (udp_message_tx, mut udp_message_rx) = mpsc::unbounded_channel::<UdpMessage>();
         let (lrcp_message_tx, mut lrcp_message_rx) =
             mpsc::unbounded_channel::<(LrcpMessage, SocketAddr)>();
@@ -79,6 +93,7 @@ impl LrcpListener {
                     &lrcp_stream_tx_clone,
                     lrcp_message,
                     &lrcp_message_tx_clone,
+                    &config,
                 )
                 .await;
             }
@@ -86,6 +101,7 @@ impl LrcpListener {
 
         Ok(Self {
             accept_rx: lrcp_stream_rx,
+            local_addr,
         })
     }
 
@@ -97,43 +113,47 @@ impl LrcpListener {
         lrcp_stream_tx: &mpsc::UnboundedSender<LrcpStreamPair>,
         lrcp_message: LrcpMessage,
         lrcp_message_tx: &mpsc::UnboundedSender<(LrcpMessage, SocketAddr)>,
+        config: &LrcpConfig,
     ) {
         match lrcp_message {
             LrcpMessage::Connect { session_id } => {
-                // Always ACK, even for duplicates
-                let ack = format!("/ack/{}/0/", session_id);
-                let _ = udp_messge_tx.send(UdpMessage::new(addr, ack));
-
                 match sessions.get(&session_id) {
+                    // Always ACK, even for duplicates: the session acks what it has received
                     Some(session) => {
                         let _ = session.send(SessionEvent::RepeatedConnect);
                     }
                     None => {
+                        let ack = LrcpMessage::Ack {
+                            session_id,
+                            length: 0,
+                        };
+                        let _ = udp_messge_tx.send(UdpMessage::new(addr, ack));
+
                         // Create channels
                         let (session_cmd_tx, session_cmd_rx) = mpsc::unbounded_channel();
                         let (session_event_tx, session_event_rx) = mpsc::unbounded_channel();
                         let (bytes_tx, bytes_rx) = mpsc::unbounded_channel();
 
                         // Create stream for application
-                        let lrcp_stream = LrcpStream::new(session_cmd_tx, bytes_rx);
+                        let send_window = SendWindow::new(config.max_pending_bytes);
+                        let lrcp_stream =
+                            LrcpStream::new(session_cmd_tx, bytes_rx, send_window.clone());
 
                         // Spawn session actor
-                        let udp_packet_paire_tx_clone = udp_messge_tx.clone();
-                        let session_event_tx_clone = session_event_tx.clone();
-                        let lrcp_message_tx_clone = lrcp_message_tx.clone();
+                        let channels = SessionChannels {
+                            udp_packet_pair_tx: udp_messge_tx.clone(),
+                            session_cmd_rx,
+                            session_event_tx: session_event_tx.clone(),
+                            session_event_rx,
+                            bytes_tx,
+                            lrcp_message_tx: lrcp_message_tx.clone(),
+                        };
+                        let config = config.clone();
 
                         tokio::spawn(async move {
-                            if let Err(e) = Session::spawn(
-                                session_id,
-                                addr,
-                                udp_packet_paire_tx_clone,
-                                session_cmd_rx,
-                                session_event_tx_clone,
-                                session_event_rx,
-                                bytes_tx,
-                                lrcp_message_tx_clone,
-                            )
-                            .await
+                            if let Err(e) =
+                                Session::spawn(session_id, addr, channels, send_window, config)
+                                    .await
                             {
                                 error!("== session {} error: {}", session_id, e);
                             }
@@ -156,7 +176,7 @@ impl LrcpListener {
                     let _ = session_event_tx.send(SessionEvent::Data { pos, escaped_data });
                 } else {
                     // If the session is not open: send /close/SESSION/ and stop.
-                    let close = format!("/close/{}/", session_id);
+                    let close = LrcpMessage::Close { session_id };
                     let _ = udp_messge_tx.send(UdpMessage::new(addr, close));
                 }
             }
@@ -165,13 +185,13 @@ impl LrcpListener {
                     let _ = session_event_tx.send(SessionEvent::Ack { length });
                 }
             }
-            LrcpMessage::ClientClose { session_id } => {
+            LrcpMessage::Close { session_id } => {
                 if let Some(session_event_tx) = sessions.get(&session_id) {
                     let _ = session_event_tx.send(SessionEvent::Close {
                         reason: "client close connection".to_string(),
                     });
                 } else {
-                    let close = format!("/close/{}/", session_id);
+                    let close = LrcpMessage::Close { session_id };
                     let _ = udp_messge_tx.send(UdpMessage::new(addr, close));
                 }
             }
@@ -182,3 +202,106 @@ impl LrcpListener {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+    use tokio::time::{Instant, timeout};
+
+    async fn recv(socket: &UdpSocket) -> String {
+        let mut buf = [0u8; 1024];
+        let (len, _) = timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .expect("the server answers")
+            .unwrap();
+        String::from_utf8_lossy(&buf[..len]).to_string()
+    }
+
+    #[tokio::test]
+    async fn silent_session_is_closed_after_the_configured_idle_timeout() -> Result<()> {
+        let config = LrcpConfig {
+            idle_timeout: Duration::from_secs(1),
+            ..LrcpConfig::default()
+        };
+        let listener = LrcpListener::bind_with_config("127.0.0.1:0", config).await?;
+        let server_addr = listener.local_addr();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        client.send_to(b"/connect/7/", server_addr).await?;
+        assert_eq!(recv(&client).await, "/ack/7/0/");
+
+        let connected = Instant::now();
+        assert_eq!(recv(&client).await, "/close/7/");
+        let idle = connected.elapsed();
+        assert!(idle >= Duration::from_millis(900), "{:?}", idle);
+        assert!(idle < Duration::from_secs(3), "{:?}", idle);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listeners_on_different_ports_keep_their_sessions_apart() -> Result<()> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let mut first = LrcpListener::bind("127.0.0.1:0").await?;
+        let mut second = LrcpListener::bind("127.0.0.1:0").await?;
+        assert_ne!(first.local_addr(), second.local_addr());
+
+        // the same session id on both listeners names two unrelated sessions
+        let mut clients = Vec::new();
+        for listener in [&first, &second] {
+            let client = UdpSocket::bind("127.0.0.1:0").await?;
+            client.connect(listener.local_addr()).await?;
+            client.send(b"/connect/7/").await?;
+            assert_eq!(recv(&client).await, "/ack/7/0/");
+            clients.push(client);
+        }
+        clients[0].send(b"/data/7/0/first\n/").await?;
+        clients[1].send(b"/data/7/0/second one\n/").await?;
+        assert_eq!(recv(&clients[0]).await, "/ack/7/6/");
+        assert_eq!(recv(&clients[1]).await, "/ack/7/11/");
+
+        // each listener serves its session concurrently, echoing what it got
+        let serve = |stream: LrcpStream| async move {
+            let (read_half, mut write_half) = stream.split();
+            let mut line = String::new();
+            BufReader::new(read_half).read_line(&mut line).await?;
+            write_half.write_all(line.as_bytes()).await?;
+            write_half.flush().await?;
+            Ok::<_, Error>(line)
+        };
+        let (first_stream, _) = first.accept().await?;
+        let (second_stream, _) = second.accept().await?;
+        let (first_line, second_line) =
+            tokio::try_join!(serve(first_stream), serve(second_stream))?;
+        assert_eq!(first_line, "first\n");
+        assert_eq!(second_line, "second one\n");
+
+        assert_eq!(recv(&clients[0]).await, "/data/7/0/first\n/");
+        assert_eq!(recv(&clients[1]).await, "/data/7/0/second one\n/");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn repeated_connect_acks_the_received_position() -> Result<()> {
+        let listener = LrcpListener::bind("127.0.0.1:0").await?;
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        client.connect(listener.local_addr()).await?;
+
+        client.send(b"/connect/7/").await?;
+        assert_eq!(recv(&client).await, "/ack/7/0/");
+        client.send(b"/data/7/0/hello\n/").await?;
+        assert_eq!(recv(&client).await, "/ack/7/6/");
+
+        // the session keeps its position, and says so
+        client.send(b"/connect/7/").await?;
+        assert_eq!(recv(&client).await, "/ack/7/6/");
+        client.send(b"/data/7/6/again\n/").await?;
+        assert_eq!(recv(&client).await, "/ack/7/12/");
+
+        Ok(())
+    }
+}
@@ -1,8 +1,10 @@
 mod listener;
 mod protocol;
+mod rtt;
 mod session;
 mod stream;
 
 pub use listener::*;
+pub use protocol::parse_packet;
 pub use session::RETRANSMIT_MILLIS;
 pub use stream::*;
@@ -1,4 +1,6 @@
 use crate::{Error, Result};
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
 
 /// Represent possible udp packet received from udp socket.
 #[derive(Debug, Clone, PartialEq)]
@@ -6,9 +8,11 @@ pub enum LrcpMessage {
     Connect {
         session_id: u64,
     },
-    ClientClose {
+    /// Sent by either side.
+    Close {
         session_id: u64,
     },
+    /// Internal, from a session to the listener, never on the wire.
     SessionTerminate {
         session_id: u64,
     },
@@ -25,7 +29,9 @@ pub enum LrcpMessage {
     },
 }
 
-const MAX_INT: u64 = 2_147_483_648; // 2^31
+pub const MAX_INT: u64 = 2_147_483_648; // 2^31
+/// LRCP messages must be smaller than this, in bytes.
+pub const MAX_PACKET_SIZE: usize = 1000;
 
 fn parse_int(s: &str) -> Result<u64> {
     s.parse::<u64>()
@@ -45,14 +51,15 @@ fn tokenize(s: &str) -> Result<Vec<String>> {
     let mut tokens = Vec::new();
     let mut acc = String::new();
     let mut chars = s.char_indices().peekable();
-    let last_index = s.len() - 1;
+    // byte index of the last char, `s` is empty for the packet "/"
+    let last_index = s.char_indices().last().map(|(i, _)| i);
     while let Some((_index, ch)) = chars.next() {
         match ch {
             '\\' => {
                 // Look ahead to next char
                 if let Some((i, next)) = chars.peek() {
                     match *next {
-                        '/' if *i != last_index => {
+                        '/' if Some(*i) != last_index => {
                             // Consume the next char
                             // let _ = chars.next().unwrap();
                             // treat "\/" as whole normal data
@@ -60,7 +67,7 @@ fn tokenize(s: &str) -> Result<Vec<String>> {
                             acc.push(*next);
                             chars.next();
                         }
-                        '/' if *i == last_index => {
+                        '/' if Some(*i) == last_index => {
                             // Consume the next char
                             // let _ = chars.next().unwrap();
                             // treat "\/" as whole normal data
@@ -118,12 +125,13 @@ pub fn parse_packet(buf: &[u8]) -> Result<LrcpMessage> {
     // Skip the leading '/'
     let rest = &s[1..];
 
-    let parts = tokenize(rest)?;
-    let parts: Vec<&str> = parts
-        .iter()
-        .map(|s| s.as_str())
-        .filter(|s| *s != "")
-        .collect();
+    let mut parts = tokenize(rest)?;
+    // The closing '/' always produces a trailing empty token, drop only that one.
+    // Other empty fields are meaningful, e.g. the empty data field in "/data/1/0//".
+    if parts.last().is_some_and(|s| s.is_empty()) {
+        parts.pop();
+    }
+    let parts: Vec<&str> = parts.iter().map(|s| s.as_str()).collect();
 
     // debug!("parsed parts: {:?}", parts);
 
@@ -135,7 +143,7 @@ pub fn parse_packet(buf: &[u8]) -> Result<LrcpMessage> {
         }
         ["close", session_str] => {
             let session_id = parse_int(session_str)?;
-            Ok(LrcpMessage::ClientClose { session_id })
+            Ok(LrcpMessage::Close { session_id })
         }
         ["ack", session_str, pos_str] => {
             let session_id = parse_int(session_str)?;
@@ -163,6 +171,59 @@ pub fn unescape_data(s: &str) -> String {
     s.replace("\\/", "/").replace("\\\\", "\\")
 }
 
+impl LrcpMessage {
+    /// A /data/ message carrying `data` unescaped.
+    pub fn data(session_id: u64, pos: u64, data: &str) -> Self {
+        LrcpMessage::Data {
+            session_id,
+            pos,
+            escaped_data: escape_data(data),
+        }
+    }
+}
+
+/// Every LRCP message is a whole UDP datagram: `decode` parses all of `src` as one
+/// packet, `encode` writes a packet as it goes on the wire.
+#[derive(Debug, Default)]
+pub struct LrcpCodec;
+
+impl Encoder<LrcpMessage> for LrcpCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: LrcpMessage, dst: &mut BytesMut) -> Result<()> {
+        let packet = match item {
+            LrcpMessage::Connect { session_id } => format!("/connect/{}/", session_id),
+            LrcpMessage::Close { session_id } => format!("/close/{}/", session_id),
+            LrcpMessage::Data {
+                session_id,
+                pos,
+                escaped_data,
+            } => format!("/data/{}/{}/{}/", session_id, pos, escaped_data),
+            LrcpMessage::Ack { session_id, length } => {
+                format!("/ack/{}/{}/", session_id, length)
+            }
+            LrcpMessage::SessionTerminate { .. } => {
+                return Err(Error::Other("session terminate is not sent".into()));
+            }
+        };
+        dst.put_slice(packet.as_bytes());
+        Ok(())
+    }
+}
+
+impl Decoder for LrcpCodec {
+    type Error = Error;
+    type Item = LrcpMessage;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        let packet = src.split();
+        parse_packet(&packet).map(Some)
+    }
+}
+
 #[cfg(test)]
 mod protocol_parser_tests {
     #![allow(unused)]
@@ -204,7 +265,7 @@ mod protocol_parser_tests {
     fn test_parse_close_valid() {
         let input = b"/close/999/";
         let packet = parse_packet(input).unwrap();
-        assert_eq!(packet, LrcpMessage::ClientClose { session_id: 999 });
+        assert_eq!(packet, LrcpMessage::Close { session_id: 999 });
     }
 
     #[test]
@@ -272,6 +333,20 @@ mod protocol_parser_tests {
         );
     }
 
+    #[test]
+    fn test_parse_data_empty() {
+        let input = b"/data/1/5//";
+        let packet = parse_packet(input).unwrap();
+        assert_eq!(
+            packet,
+            LrcpMessage::Data {
+                session_id: 1,
+                pos: 5,
+                escaped_data: "".to_string()
+            }
+        );
+    }
+
     #[test]
     fn test_parse_data_invalid_field_count() {
         let input = b"/data/1/0/";
@@ -306,6 +381,12 @@ mod protocol_parser_tests {
         assert!(parse_packet(input).is_err());
     }
 
+    #[test]
+    fn test_parse_lone_slash_is_rejected() {
+        // found by fuzzing: the empty rest after the leading '/' used to underflow
+        assert!(parse_packet(b"/").is_err());
+    }
+
     #[test]
     fn test_escape_data() {
         assert_eq!(escape_data("hello"), "hello");
@@ -330,4 +411,56 @@ mod protocol_parser_tests {
         let unescaped = unescape_data(&escaped);
         assert_eq!(original, unescaped);
     }
+
+    fn round_trip(message: LrcpMessage) -> String {
+        let mut buf = BytesMut::new();
+        LrcpCodec.encode(message.clone(), &mut buf).unwrap();
+        let packet = String::from_utf8(buf.to_vec()).unwrap();
+        assert_eq!(LrcpCodec.decode(&mut buf).unwrap(), Some(message));
+        assert!(buf.is_empty());
+        packet
+    }
+
+    #[test]
+    fn codec_round_trips_connect_and_close() {
+        assert_eq!(
+            round_trip(LrcpMessage::Connect { session_id: 7 }),
+            "/connect/7/"
+        );
+        assert_eq!(
+            round_trip(LrcpMessage::Close { session_id: 7 }),
+            "/close/7/"
+        );
+    }
+
+    #[test]
+    fn codec_round_trips_ack() {
+        let ack = LrcpMessage::Ack {
+            session_id: 7,
+            length: 1024,
+        };
+        assert_eq!(round_trip(ack), "/ack/7/1024/");
+    }
+
+    #[test]
+    fn codec_round_trips_data_escaping_slashes_and_backslashes() {
+        assert_eq!(
+            round_trip(LrcpMessage::data(7, 0, "hello\n")),
+            "/data/7/0/hello\n/"
+        );
+        assert_eq!(
+            round_trip(LrcpMessage::data(7, 12, "a/b\\c\n")),
+            "/data/7/12/a\\/b\\\\c\n/"
+        );
+        assert_eq!(round_trip(LrcpMessage::data(7, 5, "")), "/data/7/5//");
+    }
+
+    #[test]
+    fn codec_does_not_encode_session_terminate() {
+        let mut buf = BytesMut::new();
+        let terminate = LrcpMessage::SessionTerminate { session_id: 7 };
+        assert!(LrcpCodec.encode(terminate, &mut buf).is_err());
+        assert!(buf.is_empty());
+        assert_eq!(LrcpCodec.decode(&mut buf).unwrap(), None);
+    }
 }
@@ -0,0 +1,111 @@
+//! Retransmission timeout derived from the measured round-trip time,
+//! following Jacobson/Karels as specified in RFC 6298.
+use std::time::Duration;
+
+/// Never retransmit faster than this, however short the round trips are.
+pub const MIN_RTO: Duration = Duration::from_millis(200);
+/// Never wait longer than this, a session idle for 60 seconds is closed anyway.
+pub const MAX_RTO: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+    /// Smoothed round-trip time, `None` until the first sample.
+    srtt: Option<Duration>,
+    /// Round-trip time variation.
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl RttEstimator {
+    /// `initial_rto` applies until a round trip has been measured.
+    pub fn new(initial_rto: Duration) -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: initial_rto,
+        }
+    }
+
+    /// The current retransmission timeout.
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Account for the round-trip time of a chunk sent only once: the ack of a
+    /// retransmitted chunk can not tell which of its transmissions it answers (Karn).
+    pub fn observe(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                // RTTVAR <- 3/4 * RTTVAR + 1/4 * |SRTT - R|, then SRTT <- 7/8 * SRTT + 1/8 * R
+                self.rttvar = self.rttvar * 3 / 4 + srtt.abs_diff(rtt) / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or(rtt);
+        self.rto = (srtt + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn initial_rto_applies_until_the_first_sample() {
+        let mut rtt = RttEstimator::new(millis(3000));
+        assert_eq!(rtt.rto(), millis(3000));
+
+        // SRTT = 100, RTTVAR = 50, RTO = 100 + 4 * 50
+        rtt.observe(millis(100));
+        assert_eq!(rtt.rto(), millis(300));
+    }
+
+    #[test]
+    fn steady_latency_converges_to_the_lower_bound() {
+        let mut rtt = RttEstimator::new(millis(3000));
+        let mut previous = rtt.rto();
+        for _ in 0..50 {
+            rtt.observe(millis(40));
+            // the variation decays, so the timeout only shrinks
+            assert!(rtt.rto() <= previous);
+            previous = rtt.rto();
+        }
+        assert_eq!(rtt.rto(), MIN_RTO);
+    }
+
+    #[test]
+    fn jittery_latency_keeps_a_margin_above_the_average() {
+        let mut rtt = RttEstimator::new(millis(3000));
+        for sample in [300, 500, 300, 500, 300, 500, 300, 500, 300, 500] {
+            rtt.observe(millis(sample));
+            assert!(rtt.rto() >= millis(500), "{:?}", rtt.rto());
+            assert!(rtt.rto() <= millis(1500), "{:?}", rtt.rto());
+        }
+    }
+
+    #[test]
+    fn slower_link_raises_the_timeout_within_the_upper_bound() {
+        let mut rtt = RttEstimator::new(millis(3000));
+        for _ in 0..10 {
+            rtt.observe(millis(50));
+        }
+        let fast = rtt.rto();
+
+        rtt.observe(millis(2000));
+        assert!(rtt.rto() > fast);
+        assert!(rtt.rto() > millis(2000), "{:?}", rtt.rto());
+
+        for _ in 0..10 {
+            rtt.observe(Duration::from_secs(20));
+        }
+        assert_eq!(rtt.rto(), MAX_RTO);
+    }
+}
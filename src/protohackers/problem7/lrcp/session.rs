@@ -1,19 +1,65 @@
 use super::protocol::*;
+use super::rtt::RttEstimator;
+use super::stream::SendWindow;
 use crate::{Error, Result};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use std::collections::VecDeque;
 use std::fmt;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio::task::AbortHandle;
 use tokio::time::{Interval, interval};
+use tokio_util::codec::Encoder;
 
+use tracing::Instrument;
 #[allow(unused)]
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, info_span};
 
+/// Default for `LrcpConfig::max_data_length`.
 const MAX_DATA_LENGTH: usize = 3000;
+/// Default for `LrcpConfig::retransmit_interval`.
 pub const RETRANSMIT_MILLIS: usize = 3000;
+/// Default for `LrcpConfig::idle_timeout`.
 const IDLE_TIMEOUT_SECOND: usize = 60;
+/// Unacked bytes a session buffers before writes to its stream wait for acks.
+pub const DEFAULT_MAX_PENDING_BYTES: usize = 1024 * 1024;
+
+/// Tunables of a LRCP session.
+#[derive(Debug, Clone)]
+pub struct LrcpConfig {
+    /// Numeric fields in LRCP must be smaller than 2^31, so a session whose
+    /// incoming or outgoing stream position would reach this value is closed.
+    pub max_position: u64,
+    /// A session is closed once it has been open this long, however active it is.
+    /// Unlimited when `None`, only the idle timeout applies then.
+    pub max_lifetime: Option<Duration>,
+    /// Writes to the stream wait once this many written bytes are not acked yet,
+    /// until acks bring them down to half of it.
+    pub max_pending_bytes: usize,
+    /// A session which hears nothing from its peer for this long is closed.
+    pub idle_timeout: Duration,
+    /// Retransmission timeout until a round trip has been measured,
+    /// then it follows the round-trip times, see `RttEstimator`.
+    pub retransmit_interval: Duration,
+    /// Outgoing data is split into /data/ messages carrying at most this many bytes,
+    /// fewer when the escaped message could reach `MAX_PACKET_SIZE` otherwise.
+    pub max_data_length: usize,
+}
+
+impl Default for LrcpConfig {
+    fn default() -> Self {
+        Self {
+            max_position: MAX_INT,
+            max_lifetime: None,
+            max_pending_bytes: DEFAULT_MAX_PENDING_BYTES,
+            idle_timeout: Duration::from_secs(IDLE_TIMEOUT_SECOND as u64),
+            retransmit_interval: Duration::from_millis(RETRANSMIT_MILLIS as u64),
+            max_data_length: MAX_DATA_LENGTH,
+        }
+    }
+}
 
 /// It is the communication channel from the application layer
 /// down into the LRCP session state machine.
@@ -21,6 +67,9 @@ const IDLE_TIMEOUT_SECOND: usize = 60;
 pub enum SessionCommand {
     /// App wants to write data to the stream
     Write { data: Vec<u8> },
+    /// App wants to know when all previous writes have been sent (not necessarily acked).
+    /// Commands are handled in order, so replying on `done` acts as a fence.
+    Flush { done: oneshot::Sender<()> },
     /// App wants to read data (non-blocking poll)
     /// We'll use a different mechanism for AsyncRead (see LrcpStream)
     #[allow(unused)]
@@ -50,6 +99,7 @@ pub enum SessionEvent {
     Ack {
         length: u64,
     },
+    /// From network: /connect/ for this session, which is already open
     RepeatedConnect,
     /// From network: close
     Close {
@@ -64,6 +114,7 @@ pub enum SessionEvent {
 pub struct Session {
     session_id: u64,
     peer: std::net::SocketAddr,
+    config: LrcpConfig,
     udp_packet_pair_tx: mpsc::UnboundedSender<UdpMessage>,
     session_event_tx: mpsc::UnboundedSender<SessionEvent>,
     lrcp_message_tx: mpsc::UnboundedSender<(LrcpMessage, SocketAddr)>,
@@ -78,16 +129,36 @@ pub struct Session {
     // how many bytes the client has acknowledged
     acked_out_position: u64,
 
+    // sent bytes not acked yet, always `acked_out_position..out_position`
     pending_out_payload: Vec<u8>,
+    // shared with the stream, acked bytes are released from it
+    send_window: Arc<SendWindow>,
 
     last_activity: Instant,
     // ✅ New: channel to send received data to the application
     // It is used to send received data upto the application layer
     bytes_tx: mpsc::UnboundedSender<Bytes>,
     retransmit_handle: Option<AbortHandle>,
+    // retransmissions since the last ack which advanced `acked_out_position`
+    retransmit_attempts: u32,
+    // unacked /data/ chunks in position order, to measure round trips
+    sent_chunks: VecDeque<SentChunk>,
+    rtt: RttEstimator,
+    // set once the session sent its /close/, no /data/ may follow it
+    closing: bool,
     timeout_interval: Interval,
 }
 
+/// When an outgoing chunk was sent, its ack measures a round trip.
+#[derive(Debug)]
+struct SentChunk {
+    /// Stream position right after the chunk.
+    end: u64,
+    sent_at: tokio::time::Instant,
+    /// Sent more than once, its ack can not be matched to one transmission.
+    retransmitted: bool,
+}
+
 #[derive(Debug)]
 pub struct UdpMessage {
     pub target: std::net::SocketAddr,
@@ -103,71 +174,131 @@ impl fmt::Display for UdpMessage {
 }
 
 impl UdpMessage {
-    pub fn new(target: std::net::SocketAddr, s: String) -> Self {
+    /// Encode `message` for `target`, it must be one sent on the wire.
+    pub fn new(target: std::net::SocketAddr, message: LrcpMessage) -> Self {
+        let mut payload = BytesMut::new();
+        LrcpCodec
+            .encode(message, &mut payload)
+            .expect("only wire messages are sent");
         Self {
             target,
-            payload: s.into_bytes(),
+            payload: payload.to_vec(),
         }
     }
 }
 
+/// The channels a session talks through, set up by the listener when a peer connects.
+pub struct SessionChannels {
+    /// Datagrams to send to the peer.
+    pub udp_packet_pair_tx: mpsc::UnboundedSender<UdpMessage>,
+    /// Commands from the `LrcpStream` of the session.
+    pub session_cmd_rx: mpsc::UnboundedReceiver<SessionCommand>,
+    /// Events of the session, the session schedules its own retransmits through it.
+    pub session_event_tx: mpsc::UnboundedSender<SessionEvent>,
+    pub session_event_rx: mpsc::UnboundedReceiver<SessionEvent>,
+    /// Received data, up to the application.
+    pub bytes_tx: mpsc::UnboundedSender<Bytes>,
+    pub lrcp_message_tx: mpsc::UnboundedSender<(LrcpMessage, SocketAddr)>,
+}
+
 impl Session {
     pub async fn spawn(
         session_id: u64,
         peer: SocketAddr,
-        udp_packet_pair_tx: mpsc::UnboundedSender<UdpMessage>,
-        mut session_cmd_rx: mpsc::UnboundedReceiver<SessionCommand>,
-        session_event_tx: mpsc::UnboundedSender<SessionEvent>,
-        mut session_event_rx: mpsc::UnboundedReceiver<SessionEvent>,
-        bytes_tx: mpsc::UnboundedSender<Bytes>,
-        lrcp_message_tx: mpsc::UnboundedSender<(LrcpMessage, SocketAddr)>,
+        channels: SessionChannels,
+        send_window: Arc<SendWindow>,
+        config: LrcpConfig,
     ) -> Result<()> {
+        let SessionChannels {
+            udp_packet_pair_tx,
+            mut session_cmd_rx,
+            session_event_tx,
+            mut session_event_rx,
+            bytes_tx,
+            lrcp_message_tx,
+        } = channels;
+        let rtt = RttEstimator::new(config.retransmit_interval);
+        let timeout_interval = interval(config.idle_timeout);
         let mut session = Self {
             session_id,
             peer,
+            config,
             udp_packet_pair_tx,
             session_event_tx,
             in_position: 0,
             out_position: 0,
             acked_out_position: 0,
             pending_out_payload: Vec::new(),
+            send_window,
             last_activity: Instant::now(),
             bytes_tx,
             retransmit_handle: None,
-            timeout_interval: interval(Duration::from_secs(IDLE_TIMEOUT_SECOND as u64)),
+            retransmit_attempts: 0,
+            sent_chunks: VecDeque::new(),
+            rtt,
+            closing: false,
+            timeout_interval,
             lrcp_message_tx,
         };
 
-        loop {
-            tokio::select! {
-                // Command from LrcpStream (app)
-                Some(cmd) = session_cmd_rx.recv() => {
-                    session.handle_command(cmd).await?;
+        // Every log of this session is attributable to it through the span fields
+        let span = info_span!("lrcp_session", session_id, %peer);
+        async move {
+            info!("session opened");
+            let max_lifetime = session.config.max_lifetime;
+            let lifetime_elapsed = async {
+                match max_lifetime {
+                    Some(lifetime) => tokio::time::sleep(lifetime).await,
+                    None => std::future::pending().await,
                 }
+            };
+            tokio::pin!(lifetime_elapsed);
 
-                // Event from network or timer
-                Some(event) = session_event_rx.recv() => {
-                    let _ = session.handle_event(event).await?;
-                }
-                // Idle check
-                _ = session.timeout_interval.tick() => {
-                    session.handle_event(SessionEvent::CheckSessionExpiry).await?;
+            loop {
+                tokio::select! {
+                    // Command from LrcpStream (app)
+                    Some(cmd) = session_cmd_rx.recv() => {
+                        session.handle_command(cmd).await?;
+                    }
+
+                    // Event from network or timer
+                    Some(event) = session_event_rx.recv() => {
+                        let _ = session.handle_event(event).await?;
+                    }
+                    // Idle check
+                    _ = session.timeout_interval.tick() => {
+                        session.handle_event(SessionEvent::CheckSessionExpiry).await?;
+                    }
+                    // Absolute lifetime, independent of activity
+                    _ = &mut lifetime_elapsed => {
+                        session.handle_close();
+                        return Err(Error::Other(format!(
+                            "session reached its maximum lifetime of {:?}",
+                            max_lifetime.unwrap_or_default()
+                        )));
+                    }
+                    else => break,
                 }
-                else => break,
             }
-        }
 
-        Ok(())
+            Ok(())
+        }
+        .instrument(span)
+        .await
     }
 
     fn handle_close(&mut self) {
+        info!("session closed");
+        self.closing = true;
         // Send close on exit
         if let Some(handle) = self.retransmit_handle.take() {
             handle.abort();
         }
         let _ = self.udp_packet_pair_tx.send(UdpMessage::new(
             self.peer,
-            format!("/close/{}/", self.session_id),
+            LrcpMessage::Close {
+                session_id: self.session_id,
+            },
         ));
 
         let _ = self.lrcp_message_tx.send((
@@ -180,7 +311,7 @@ impl Session {
 
     fn reset_session_expriry_timer(&mut self) {
         // debug!("== reset session {} exprity ==", self.session_id);
-        self.timeout_interval = interval(Duration::from_secs(IDLE_TIMEOUT_SECOND as u64));
+        self.timeout_interval = interval(self.config.idle_timeout);
         self.last_activity = Instant::now();
     }
 
@@ -188,9 +319,18 @@ impl Session {
     async fn handle_command(&mut self, cmd: SessionCommand) -> Result<()> {
         match cmd {
             SessionCommand::Write { data } => {
-                self.pending_out_payload.extend_from_slice(&data);
+                if self.out_position + data.len() as u64 >= self.config.max_position {
+                    self.handle_close();
+                    return Err(Error::Other(format!(
+                        "session {} outgoing position would exceed {}",
+                        self.session_id, self.config.max_position
+                    )));
+                }
                 let _ = self.send_data(data).await?;
             }
+            SessionCommand::Flush { done } => {
+                let _ = done.send(());
+            }
             SessionCommand::Shutdown => {
                 // Graceful shutdown
             }
@@ -210,39 +350,61 @@ impl Session {
             }
             SessionEvent::CheckSessionExpiry => {
                 // debug!("== check session: {} idle ==", self.session_id);
-                if self.last_activity.elapsed() > Duration::from_secs(IDLE_TIMEOUT_SECOND as u64) {
+                if self.last_activity.elapsed() >= self.config.idle_timeout {
                     self.handle_close();
 
                     return Err(Error::Other(format!(
-                        "client is idle more than: {} seconds, close it",
-                        IDLE_TIMEOUT_SECOND
+                        "client is idle more than: {:?}, close it",
+                        self.config.idle_timeout
                     )));
                 }
             }
             SessionEvent::RepeatedConnect => {
-                // let _ = self.reset_session_expriry_timer();
+                // the peer missed our ack of its connect, tell it where the stream is
+                self.send_ack(self.in_position).await;
             }
             SessionEvent::Data { pos, escaped_data } => {
+                debug!(pos, len = escaped_data.len(), "received data");
                 let _ = self.reset_session_expriry_timer();
 
-                // It means the next byte position the server expects is correct
-                if pos == self.in_position {
-                    let unescaped = unescape_data(&escaped_data);
-                    let bytes = Bytes::from(unescaped.into_bytes());
-                    let byte_len = bytes.len();
-
-                    self.in_position += byte_len as u64;
+                // An empty data field carries no bytes, but it still has to be acked
+                // so the peer knows we are in sync. Nothing is delivered to the app.
+                if escaped_data.is_empty() {
                     self.send_ack(self.in_position).await;
+                    return Ok(());
+                }
 
-                    // Send to application layer
-                    let _x = self.bytes_tx.send(bytes);
-                } else {
-                    // Request retransmission by re-acking current position
+                let unescaped = unescape_data(&escaped_data).into_bytes();
+                let end = pos + unescaped.len() as u64;
+                // A gap before the segment, or nothing past what we already have:
+                // request retransmission by re-acking current position
+                if pos > self.in_position || end <= self.in_position {
                     self.send_ack(self.in_position).await;
+                    return Ok(());
+                }
+
+                // The segment starts at or before the next byte we expect (e.g. a retransmission
+                // overlapping received data), only the bytes past in_position are new
+                let bytes = Bytes::from(unescaped).slice((self.in_position - pos) as usize..);
+                let byte_len = bytes.len();
+
+                if self.in_position + byte_len as u64 >= self.config.max_position {
+                    self.handle_close();
+                    return Err(Error::Other(format!(
+                        "session {} incoming position would exceed {}",
+                        self.session_id, self.config.max_position
+                    )));
                 }
+
+                self.in_position += byte_len as u64;
+                self.send_ack(self.in_position).await;
+
+                // Send to application layer
+                let _x = self.bytes_tx.send(bytes);
             }
 
             SessionEvent::Ack { length } => {
+                debug!(length, "received ack");
                 // let _ = self.reset_session_expriry_timer();
 
                 // 1. Duplicate or stale ACK: ignore
@@ -259,45 +421,68 @@ impl Session {
                     return Err(Error::Other("client acked more bytes than sent".into()));
                 }
 
-                // 3. Valid new ACK: update state and trim send buffer
-                if length < (self.acked_out_position + self.pending_out_payload.len() as u64) {
-                    let transmitted_bytes = length - self.acked_out_position;
+                // 3. Valid new ACK: update state and trim send buffer.
+                // Bytes enter `pending_out_payload` only when `out_position` moves past them
+                // and leave it only when `acked_out_position` does, so the buffer ends at
+                // `out_position` and `acked_out_position < length <= out_position` here.
+                debug_assert_eq!(
+                    self.acked_out_position + self.pending_out_payload.len() as u64,
+                    self.out_position
+                );
+                let transmitted_bytes = length - self.acked_out_position;
+                self.send_window.release(transmitted_bytes as usize);
+                let _ = self.pending_out_payload.drain(..transmitted_bytes as usize);
+                self.measure_round_trip(length);
+                self.acked_out_position = length;
+                self.retransmit_attempts = 0;
 
-                    let _ = self.pending_out_payload.drain(..transmitted_bytes as usize);
-
-                    let payload = format!(
-                        "/data/{}/{}/{}/",
-                        self.session_id,
-                        self.acked_out_position + transmitted_bytes,
-                        escape_data(std::str::from_utf8(&self.pending_out_payload).unwrap()),
-                    );
-
-                    let _ = self
-                        .udp_packet_pair_tx
-                        .send(UdpMessage::new(self.peer, payload));
-
-                    self.acked_out_position = length;
-
-                    return Ok(());
+                if length < self.out_position {
+                    // the peer is missing what follows, send it again right away
+                    return self.retransmit_pending();
                 }
-
-                if length == self.out_position {
-                    self.acked_out_position = length;
-                    self.pending_out_payload.clear();
-                    return Ok(());
+                // everything sent is acked, nothing is left to retransmit
+                if let Some(handle) = self.retransmit_handle.take() {
+                    handle.abort();
                 }
-
-                return Err(Error::Other("should not reach this".into()));
             }
 
             SessionEvent::RetransmitPendingData => {
-                self.out_position = self.out_position - self.pending_out_payload.len() as u64;
-                let _x = self.send_data(self.pending_out_payload.clone()).await;
+                // the timer may have fired just before the session was closed,
+                // or just before the last ack arrived
+                if self.closing || self.pending_out_payload.is_empty() {
+                    return Ok(());
+                }
+                self.retransmit_attempts += 1;
+                debug!(
+                    offset = self.acked_out_position,
+                    len = self.pending_out_payload.len(),
+                    attempt = self.retransmit_attempts,
+                    "retransmit pending data"
+                );
+                self.retransmit_pending()?;
+                self.schedule_retransmit();
             }
         }
         Ok(())
     }
 
+    /// Forget the chunks acked by `length`, the newest of them gives a round-trip sample
+    /// unless it was retransmitted.
+    fn measure_round_trip(&mut self, length: u64) {
+        let mut newest_acked = None;
+        while let Some(chunk) = self.sent_chunks.front()
+            && chunk.end <= length
+        {
+            newest_acked = self.sent_chunks.pop_front();
+        }
+        if let Some(chunk) = newest_acked
+            && !chunk.retransmitted
+        {
+            self.rtt.observe(chunk.sent_at.elapsed());
+            debug!(rto = ?self.rtt.rto(), "round trip measured");
+        }
+    }
+
     fn schedule_retransmit(&mut self) {
         // Cancel any previous retransmit task
         if let Some(handle) = self.retransmit_handle.take() {
@@ -305,8 +490,9 @@ impl Session {
         }
 
         let tx = self.session_event_tx.clone();
+        let rto = self.rtt.rto();
         let handle = tokio::spawn(async move {
-            tokio::time::sleep(Duration::from_millis(RETRANSMIT_MILLIS as u64)).await;
+            tokio::time::sleep(rto).await;
             let _ = tx.send(SessionEvent::RetransmitPendingData);
         });
 
@@ -314,15 +500,58 @@ impl Session {
     }
 
     async fn send_ack(&self, pos: u64) {
-        let ack = format!("/ack/{}/{}/", self.session_id, pos);
+        let ack = LrcpMessage::Ack {
+            session_id: self.session_id,
+            length: pos,
+        };
         let _ = self
             .udp_packet_pair_tx
             .send(UdpMessage::new(self.peer, ack));
     }
 
     async fn send_data(&mut self, data: Vec<u8>) -> Result<()> {
-        for each in produce_chunks(data.clone(), MAX_DATA_LENGTH) {
-            let each_str = match std::str::from_utf8(&each) {
+        if self.closing {
+            return Ok(());
+        }
+        let pos = self.out_position;
+        self.out_position += data.len() as u64;
+        self.pending_out_payload.extend_from_slice(&data);
+        self.send_chunks(pos, data, false)?;
+
+        self.schedule_retransmit();
+
+        Ok(())
+    }
+
+    /// Send everything the peer has not acked again, from `acked_out_position`.
+    /// The bytes were counted in `out_position` when first sent, it does not change.
+    fn retransmit_pending(&mut self) -> Result<()> {
+        if self.closing {
+            return Ok(());
+        }
+        // the chunks are cut anew, the previous ones can not give round-trip samples anymore
+        self.sent_chunks.clear();
+        self.send_chunks(
+            self.acked_out_position,
+            self.pending_out_payload.clone(),
+            true,
+        )
+    }
+
+    /// Send `data` in /data/ messages of at most `max_data_length` bytes, the first one
+    /// at stream position `pos`, and remember each chunk to measure its round trip.
+    fn send_chunks(&mut self, mut pos: u64, data: Vec<u8>, retransmitted: bool) -> Result<()> {
+        let mut remaining = &data[..];
+        while !remaining.is_empty() {
+            let max = self
+                .config
+                .max_data_length
+                .min(data_capacity(self.session_id, pos));
+            let take = utf8_chunk_length(remaining, max);
+            let (each, rest) = remaining.split_at(take);
+            remaining = rest;
+
+            let each_str = match std::str::from_utf8(each) {
                 Ok(s) => s,
                 Err(_) => {
                     return Err(Error::Other("Non-UTF8 data in send_data".into()));
@@ -331,34 +560,544 @@ impl Session {
 
             let _ = self.udp_packet_pair_tx.send(UdpMessage::new(
                 self.peer,
-                format!(
-                    "/data/{}/{}/{}/",
-                    self.session_id,
-                    self.out_position,
-                    escape_data(each_str)
-                ),
+                LrcpMessage::data(self.session_id, pos, each_str),
             ));
-            self.out_position = self.out_position + each.len() as u64;
+            pos += each.len() as u64;
+            self.sent_chunks.push_back(SentChunk {
+                end: pos,
+                sent_at: tokio::time::Instant::now(),
+                retransmitted,
+            });
         }
+        Ok(())
+    }
+}
 
-        self.schedule_retransmit();
+impl Drop for Session {
+    fn drop(&mut self) {
+        // wake a writer waiting for acks which will never come
+        self.send_window.close();
+    }
+}
 
-        Ok(())
+/// How many bytes of `data`, at most `max`, make a chunk ending on a character boundary.
+/// When not even the first character fits it is taken whole, a chunk is never empty.
+fn utf8_chunk_length(data: &[u8], max: usize) -> usize {
+    let is_continuation = |byte: u8| byte & 0xC0 == 0x80;
+    if data.len() <= max {
+        return data.len();
+    }
+    let mut length = max;
+    while length > 0 && is_continuation(data[length]) {
+        length -= 1;
+    }
+    if length == 0 {
+        length = 1;
+        while length < data.len() && is_continuation(data[length]) {
+            length += 1;
+        }
     }
+    length
 }
 
-fn produce_chunks(data: Vec<u8>, chunk_size: usize) -> Vec<Vec<u8>> {
-    if chunk_size == 0 {
-        return vec![];
+/// How many bytes fit in a /data/ message of `session_id` at `pos` which must stay below
+/// `MAX_PACKET_SIZE` even if every byte is a `/` or `\` and doubles when escaped.
+fn data_capacity(session_id: u64, pos: u64) -> usize {
+    let mut framing = BytesMut::new();
+    LrcpCodec
+        .encode(LrcpMessage::data(session_id, pos, ""), &mut framing)
+        .expect("data messages always encode");
+    let framing = framing.len();
+    (MAX_PACKET_SIZE - 1).saturating_sub(framing) / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::capture_logs;
+    use tokio::task::JoinHandle;
+
+    const SESSION_ID: u64 = 42;
+
+    /// Drive a single `Session` directly through its channels, without a UDP socket.
+    struct SessionTest {
+        cmd_tx: mpsc::UnboundedSender<SessionCommand>,
+        event_tx: mpsc::UnboundedSender<SessionEvent>,
+        udp_rx: mpsc::UnboundedReceiver<UdpMessage>,
+        bytes_rx: mpsc::UnboundedReceiver<Bytes>,
+        send_window: Arc<SendWindow>,
+        handle: JoinHandle<Result<()>>,
+    }
+
+    fn spawn_session() -> SessionTest {
+        spawn_session_with_config(LrcpConfig::default())
+    }
+
+    fn spawn_session_with_config(config: LrcpConfig) -> SessionTest {
+        let peer: SocketAddr = "127.0.0.1:10".parse().unwrap();
+        let (udp_tx, udp_rx) = mpsc::unbounded_channel();
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let (bytes_tx, bytes_rx) = mpsc::unbounded_channel();
+        let (lrcp_message_tx, _lrcp_message_rx) = mpsc::unbounded_channel();
+        let send_window = SendWindow::new(config.max_pending_bytes);
+
+        let session_event_tx = event_tx.clone();
+        let session_send_window = send_window.clone();
+        let handle = tokio::spawn(async move {
+            let channels = SessionChannels {
+                udp_packet_pair_tx: udp_tx,
+                session_cmd_rx: cmd_rx,
+                session_event_tx,
+                session_event_rx: event_rx,
+                bytes_tx,
+                lrcp_message_tx,
+            };
+            Session::spawn(SESSION_ID, peer, channels, session_send_window, config).await
+        });
+
+        SessionTest {
+            cmd_tx,
+            event_tx,
+            udp_rx,
+            bytes_rx,
+            send_window,
+            handle,
+        }
+    }
+
+    impl SessionTest {
+        fn send_data(&self, pos: u64, data: &str) {
+            self.event_tx
+                .send(SessionEvent::Data {
+                    pos,
+                    escaped_data: escape_data(data),
+                })
+                .unwrap();
+        }
+
+        fn write(&self, data: &str) {
+            self.cmd_tx
+                .send(SessionCommand::Write {
+                    data: data.as_bytes().to_vec(),
+                })
+                .unwrap();
+        }
+
+        async fn recv_udp(&mut self) -> String {
+            let msg = self.udp_rx.recv().await.unwrap();
+            String::from_utf8(msg.payload).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_data_is_acked_without_delivering_bytes() {
+        let mut session = spawn_session();
+
+        session.send_data(0, "hello\n");
+        assert_eq!(session.recv_udp().await, format!("/ack/{SESSION_ID}/6/"));
+        assert_eq!(session.bytes_rx.recv().await.unwrap(), "hello\n");
+
+        session.send_data(6, "");
+        assert_eq!(session.recv_udp().await, format!("/ack/{SESSION_ID}/6/"));
+        assert!(session.bytes_rx.try_recv().is_err());
+        assert!(!session.handle.is_finished());
+    }
+
+    #[tokio::test]
+    async fn overlapping_data_delivers_only_the_new_tail() {
+        let mut session = spawn_session();
+
+        session.send_data(0, "abcdef");
+        assert_eq!(session.recv_udp().await, format!("/ack/{SESSION_ID}/6/"));
+        assert_eq!(session.bytes_rx.recv().await.unwrap(), "abcdef");
+
+        // bytes 3 to 8, the first half was already received
+        session.send_data(3, "defghi");
+        assert_eq!(session.recv_udp().await, format!("/ack/{SESSION_ID}/9/"));
+        assert_eq!(session.bytes_rx.recv().await.unwrap(), "ghi");
+
+        // the same segment again holds nothing new
+        session.send_data(3, "defghi");
+        assert_eq!(session.recv_udp().await, format!("/ack/{SESSION_ID}/9/"));
+        assert!(session.bytes_rx.try_recv().is_err());
+
+        // a segment after a gap is still refused
+        session.send_data(12, "xyz");
+        assert_eq!(session.recv_udp().await, format!("/ack/{SESSION_ID}/9/"));
+        assert!(session.bytes_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn incoming_position_reaching_limit_closes_session() {
+        let mut session = spawn_session_with_config(LrcpConfig {
+            max_position: 10,
+            ..LrcpConfig::default()
+        });
+
+        session.send_data(0, "hello\n");
+        assert_eq!(session.recv_udp().await, format!("/ack/{SESSION_ID}/6/"));
+
+        session.send_data(6, "world\n");
+        assert_eq!(session.recv_udp().await, format!("/close/{SESSION_ID}/"));
+        assert!(session.handle.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn outgoing_position_reaching_limit_closes_session() {
+        let mut session = spawn_session_with_config(LrcpConfig {
+            max_position: 10,
+            ..LrcpConfig::default()
+        });
+
+        session.write("olleh\n");
+        assert_eq!(
+            session.recv_udp().await,
+            format!("/data/{SESSION_ID}/0/olleh\n/")
+        );
+
+        session.write("dlrow\n");
+        assert_eq!(session.recv_udp().await, format!("/close/{SESSION_ID}/"));
+        assert!(session.handle.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn flush_completes_after_data_is_sent() {
+        use super::super::stream::LrcpStream;
+        use tokio::io::AsyncWriteExt;
+
+        let mut test = spawn_session();
+        let (_read_tx, read_rx) = mpsc::unbounded_channel();
+        let mut stream = LrcpStream::new(test.cmd_tx.clone(), read_rx, test.send_window.clone());
+
+        stream.write_all(b"hello\n").await.unwrap();
+        // the session task has not run yet, nothing is on the wire
+        assert!(test.udp_rx.try_recv().is_err());
+
+        stream.flush().await.unwrap();
+        let msg = test.udp_rx.try_recv().unwrap();
+        assert_eq!(
+            String::from_utf8(msg.payload).unwrap(),
+            format!("/data/{}/0/hello\n/", SESSION_ID)
+        );
+    }
+
+    #[tokio::test]
+    async fn write_waits_for_acks_once_the_send_buffer_is_full() {
+        use super::super::stream::LrcpStream;
+        use tokio::io::AsyncWriteExt;
+
+        let test = spawn_session_with_config(LrcpConfig {
+            max_pending_bytes: 10,
+            ..LrcpConfig::default()
+        });
+        let (_read_tx, read_rx) = mpsc::unbounded_channel();
+        let mut stream = LrcpStream::new(test.cmd_tx.clone(), read_rx, test.send_window.clone());
+
+        // 13 unacked bytes, beyond the cap of 10
+        stream.write_all(b"hello\n").await.unwrap();
+        stream.write_all(b"world!\n").await.unwrap();
+        stream.flush().await.unwrap();
+
+        let writer = tokio::spawn(async move {
+            stream.write_all(b"again\n").await.unwrap();
+            stream
+        });
+        tokio::task::yield_now().await;
+        assert!(!writer.is_finished());
+
+        // 7 bytes still pending, above the low-water mark of 5
+        test.event_tx.send(SessionEvent::Ack { length: 6 }).unwrap();
+        tokio::task::yield_now().await;
+        assert!(!writer.is_finished());
+
+        test.event_tx
+            .send(SessionEvent::Ack { length: 13 })
+            .unwrap();
+        let mut stream = tokio::time::timeout(Duration::from_secs(1), writer)
+            .await
+            .expect("the write completes once acks free the buffer")
+            .unwrap();
+        stream.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn session_events_carry_session_id() {
+        let (captured, _guard) = capture_logs();
+
+        let mut test = spawn_session();
+        test.send_data(0, "hello\n");
+        assert_eq!(test.recv_udp().await, format!("/ack/{}/6/", SESSION_ID));
+
+        let logs = captured.text();
+        let data_log = logs
+            .lines()
+            .find(|line| line.contains("received data"))
+            .expect("data event should be logged");
+        assert!(data_log.contains(&format!("session_id={}", SESSION_ID)));
+        assert!(data_log.contains("peer=127.0.0.1:10"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retransmit_attempts_are_logged_until_acked() {
+        let (captured, _guard) = capture_logs();
+
+        let mut test = spawn_session();
+        test.write("hello\n");
+        let data = format!("/data/{}/0/hello\n/", SESSION_ID);
+        assert_eq!(test.recv_udp().await, data);
+
+        // no ack, the data is resent once per retransmit interval
+        assert_eq!(test.recv_udp().await, data);
+        assert_eq!(test.recv_udp().await, data);
+
+        let attempts: Vec<String> = captured
+            .text()
+            .lines()
+            .filter(|line| line.contains("retransmit pending data"))
+            .map(|line| {
+                assert!(line.contains("offset=0"));
+                line.split_whitespace()
+                    .find(|field| field.starts_with("attempt="))
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(attempts, vec!["attempt=1", "attempt=2"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn nothing_is_retransmitted_once_everything_is_acked() {
+        let (captured, _guard) = capture_logs();
+
+        let mut test = spawn_session();
+        test.write("hello\n");
+        assert_eq!(
+            test.recv_udp().await,
+            format!("/data/{SESSION_ID}/0/hello\n/")
+        );
+        test.event_tx.send(SessionEvent::Ack { length: 6 }).unwrap();
+
+        // many retransmit intervals, but well within the idle timeout
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert!(test.udp_rx.try_recv().is_err());
+        assert!(!captured.text().contains("retransmit pending data"));
+        assert!(!test.handle.is_finished());
+    }
+
+    #[tokio::test]
+    async fn retransmit_after_a_partial_ack_resends_from_the_acked_position() {
+        let mut test = spawn_session_with_config(LrcpConfig {
+            max_data_length: 3,
+            ..LrcpConfig::default()
+        });
+        test.write("abcdefgh");
+        for data in ["/0/abc/", "/3/def/", "/6/gh/"] {
+            assert_eq!(test.recv_udp().await, format!("/data/{SESSION_ID}{data}"));
+        }
+
+        // the peer got "abcd", the rest is resent at once from position 4
+        test.event_tx.send(SessionEvent::Ack { length: 4 }).unwrap();
+        for data in ["/4/efg/", "/7/h/"] {
+            assert_eq!(test.recv_udp().await, format!("/data/{SESSION_ID}{data}"));
+        }
+
+        // and again from there when the retransmit timer fires
+        test.event_tx
+            .send(SessionEvent::RetransmitPendingData)
+            .unwrap();
+        for data in ["/4/efg/", "/7/h/"] {
+            assert_eq!(test.recv_udp().await, format!("/data/{SESSION_ID}{data}"));
+        }
+
+        // retransmissions did not move the end of the stream
+        test.event_tx.send(SessionEvent::Ack { length: 8 }).unwrap();
+        test.write("ij");
+        assert_eq!(test.recv_udp().await, format!("/data/{SESSION_ID}/8/ij/"));
+    }
+
+    #[tokio::test]
+    async fn ack_at_a_chunk_boundary_then_full_ack_empties_the_send_buffer() {
+        let mut test = spawn_session_with_config(LrcpConfig {
+            max_data_length: 3,
+            ..LrcpConfig::default()
+        });
+        test.write("abcdefgh");
+        for data in ["/0/abc/", "/3/def/", "/6/gh/"] {
+            assert_eq!(test.recv_udp().await, format!("/data/{SESSION_ID}{data}"));
+        }
+
+        // the first chunk exactly, the rest is resent from its boundary
+        test.event_tx.send(SessionEvent::Ack { length: 3 }).unwrap();
+        for data in ["/3/def/", "/6/gh/"] {
+            assert_eq!(test.recv_udp().await, format!("/data/{SESSION_ID}{data}"));
+        }
+
+        // everything acked: a late retransmit timer has nothing left to send
+        test.event_tx.send(SessionEvent::Ack { length: 8 }).unwrap();
+        test.event_tx
+            .send(SessionEvent::RetransmitPendingData)
+            .unwrap();
+        test.write("ij");
+        assert_eq!(test.recv_udp().await, format!("/data/{SESSION_ID}/8/ij/"));
+        assert!(!test.handle.is_finished());
+    }
+
+    #[tokio::test]
+    async fn escaped_data_messages_stay_below_the_packet_limit() {
+        let mut test = spawn_session();
+        // every other byte doubles when escaped, and the rest a third of the time
+        let data = "/\\a".repeat(1500);
+        test.write(&data);
+
+        let mut received = String::new();
+        while received.len() < data.len() {
+            let packet = test.recv_udp().await;
+            assert!(packet.len() < MAX_PACKET_SIZE, "{} bytes", packet.len());
+            let Ok(LrcpMessage::Data {
+                session_id,
+                pos,
+                escaped_data,
+            }) = parse_packet(packet.as_bytes())
+            else {
+                panic!("expected a data message, got {packet}");
+            };
+            assert_eq!(session_id, SESSION_ID);
+            assert_eq!(pos, received.len() as u64);
+            received.push_str(&unescape_data(&escaped_data));
+        }
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn chunks_are_not_cut_inside_a_character() {
+        let mut test = spawn_session_with_config(LrcpConfig {
+            max_data_length: 5,
+            ..Default::default()
+        });
+        // 2 bytes each, a 5 byte cut would split the third one
+        test.write("ééé");
+        assert_eq!(test.recv_udp().await, format!("/data/{SESSION_ID}/0/éé/"));
+        assert_eq!(test.recv_udp().await, format!("/data/{SESSION_ID}/4/é/"));
+        assert!(!test.handle.is_finished());
+    }
+
+    #[test]
+    fn a_character_longer_than_the_chunk_is_sent_whole() {
+        let data = "😀a".as_bytes();
+        assert_eq!(utf8_chunk_length(data, 2), 4);
+        assert_eq!(utf8_chunk_length(&data[4..], 2), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retransmit_timeout_follows_the_measured_round_trip() {
+        let mut test = spawn_session();
+        test.write("hello\n");
+        assert_eq!(
+            test.recv_udp().await,
+            format!("/data/{}/0/hello\n/", SESSION_ID)
+        );
+
+        // acked after 100ms, the timeout becomes 100ms + 4 * 50ms
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        test.event_tx.send(SessionEvent::Ack { length: 6 }).unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        test.write("world\n");
+        let data = format!("/data/{}/6/world\n/", SESSION_ID);
+        assert_eq!(test.recv_udp().await, data);
+        let sent = tokio::time::Instant::now();
+        assert_eq!(test.recv_udp().await, data);
+        assert_eq!(sent.elapsed(), Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn split_halves_read_and_write_concurrently() {
+        use super::super::stream::LrcpStream;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let mut test = spawn_session();
+        let (_, unused_rx) = mpsc::unbounded_channel();
+        let bytes_rx = std::mem::replace(&mut test.bytes_rx, unused_rx);
+        let (read_half, mut write_half) =
+            LrcpStream::new(test.cmd_tx.clone(), bytes_rx, test.send_window.clone()).split();
+
+        let reader = tokio::spawn(async move {
+            let mut line = String::new();
+            BufReader::new(read_half)
+                .read_line(&mut line)
+                .await
+                .unwrap();
+            line
+        });
+        let writer = tokio::spawn(async move {
+            write_half.write_all(b"olleh\n").await.unwrap();
+            write_half.flush().await.unwrap();
+        });
+
+        test.send_data(0, "hello\n");
+        writer.await.unwrap();
+        assert_eq!(reader.await.unwrap(), "hello\n");
+
+        let mut packets = vec![test.recv_udp().await, test.recv_udp().await];
+        packets.sort();
+        assert_eq!(
+            packets,
+            vec![
+                format!("/ack/{}/6/", SESSION_ID),
+                format!("/data/{}/0/olleh\n/", SESSION_ID),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_data_is_sent_after_close() {
+        let mut test = spawn_session();
+        test.write("hello\n");
+        assert_eq!(
+            test.recv_udp().await,
+            format!("/data/{}/0/hello\n/", SESSION_ID)
+        );
+
+        // the data is still unacked, so a retransmit is pending when the close arrives
+        test.event_tx
+            .send(SessionEvent::Close {
+                reason: "peer closed".into(),
+            })
+            .unwrap();
+        test.event_tx
+            .send(SessionEvent::RetransmitPendingData)
+            .unwrap();
+        assert_eq!(test.recv_udp().await, format!("/close/{}/", SESSION_ID));
+
+        tokio::time::sleep(Duration::from_millis(RETRANSMIT_MILLIS as u64 * 3)).await;
+        // the session is gone without sending anything else
+        assert!(test.udp_rx.recv().await.is_none());
+        assert!(test.handle.await.unwrap().is_err());
     }
 
-    let mut chunks = Vec::new();
-    let mut remaining = data;
-    while !remaining.is_empty() {
-        let take = remaining.len().min(chunk_size);
-        let (chunk, rest) = remaining.split_at(take);
-        chunks.push(chunk.to_vec());
-        remaining = rest.to_vec();
+    #[tokio::test(start_paused = true)]
+    async fn active_session_is_closed_after_max_lifetime() {
+        let mut test = spawn_session_with_config(LrcpConfig {
+            max_lifetime: Some(Duration::from_secs(10)),
+            ..LrcpConfig::default()
+        });
+
+        // keep the session busy, well within the idle timeout
+        let mut pos = 0;
+        for _ in 0..4 {
+            test.send_data(pos, "ping\n");
+            pos += 5;
+            assert_eq!(
+                test.recv_udp().await,
+                format!("/ack/{}/{}/", SESSION_ID, pos)
+            );
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+        assert!(!test.handle.is_finished());
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(test.recv_udp().await, format!("/close/{}/", SESSION_ID));
+        assert!(test.handle.await.unwrap().is_err());
     }
-    chunks
 }
@@ -1,10 +1,14 @@
 use super::session::SessionCommand;
 use bytes::Bytes;
+use futures::task::AtomicWaker;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tracing::Level;
 use tracing::span;
 
@@ -20,51 +24,206 @@ impl LrcpStreamPair {
 
 // Application-facing, for integration with higher-level code
 pub struct LrcpStream {
-    pub session_cmd_tx: mpsc::UnboundedSender<SessionCommand>,
+    read: LrcpReadHalf,
+    write: LrcpWriteHalf,
+}
+
+/// The reading half of a `LrcpStream`, see `LrcpStream::split`.
+pub struct LrcpReadHalf {
     // For reads, you'd add: read_rx: mpsc::UnboundedReceiver<Vec<u8>>
     // But for line reversal, you might just buffer in session and expose lines
-    pub read_rx: mpsc::UnboundedReceiver<Bytes>,
+    read_rx: mpsc::UnboundedReceiver<Bytes>,
     // Buffer for partial reads (important!)
-    pub read_buf: Bytes,
+    read_buf: Bytes,
+}
+
+/// The writing half of a `LrcpStream`, see `LrcpStream::split`.
+pub struct LrcpWriteHalf {
+    session_cmd_tx: mpsc::UnboundedSender<SessionCommand>,
+    send_window: Arc<SendWindow>,
     // // ✅ New: store the pending write reply future
     // pending_write: Option<oneshot::Receiver<std::io::Result<usize>>>,
+    // Fence of an in-flight flush, resolved once the session has sent all previous writes
+    pending_flush: Option<oneshot::Receiver<()>>,
 }
 
 impl LrcpStream {
     pub(crate) fn new(
         cmd_tx: mpsc::UnboundedSender<SessionCommand>,
         read_rx: mpsc::UnboundedReceiver<Bytes>,
+        send_window: Arc<SendWindow>,
     ) -> Self {
         Self {
-            session_cmd_tx: cmd_tx,
-            read_rx,
-            read_buf: Bytes::new(),
-            // pending_write: None,
+            read: LrcpReadHalf {
+                read_rx,
+                read_buf: Bytes::new(),
+            },
+            write: LrcpWriteHalf {
+                session_cmd_tx: cmd_tx,
+                send_window,
+                // pending_write: None,
+                pending_flush: None,
+            },
+        }
+    }
+
+    /// Split into halves which can be moved into separate tasks,
+    /// like `TcpStream::into_split`. Both halves still talk to the same session.
+    pub fn split(self) -> (LrcpReadHalf, LrcpWriteHalf) {
+        (self.read, self.write)
+    }
+}
+
+/// Bytes written by the application which the peer has not acked yet, shared between
+/// the writing half of a stream and its session. Once `max` bytes are pending, writes
+/// wait until acks bring them down to the low-water mark, half of `max`.
+#[derive(Debug)]
+pub(crate) struct SendWindow {
+    max: usize,
+    pending: AtomicUsize,
+    blocked: AtomicBool,
+    closed: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl SendWindow {
+    pub(crate) fn new(max: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max,
+            pending: AtomicUsize::new(0),
+            blocked: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        })
+    }
+
+    fn low_water(&self) -> usize {
+        self.max / 2
+    }
+
+    fn is_blocked(&self) -> bool {
+        self.blocked.load(Ordering::Acquire) && !self.closed.load(Ordering::Acquire)
+    }
+
+    /// Account for `len` more bytes, or register `cx` to be woken once there is room.
+    fn poll_reserve(&self, cx: &mut Context<'_>, len: usize) -> Poll<()> {
+        if self.is_blocked() {
+            self.waker.register(cx.waker());
+            // an ack may have drained the buffer before the waker was registered
+            if self.is_blocked() {
+                return Poll::Pending;
+            }
+        }
+
+        let pending = self.pending.fetch_add(len, Ordering::AcqRel) + len;
+        if pending >= self.max {
+            self.blocked.store(true, Ordering::Release);
+            // the session may have released everything in between
+            if self.pending.load(Ordering::Acquire) <= self.low_water() {
+                self.blocked.store(false, Ordering::Release);
+            }
+        }
+        Poll::Ready(())
+    }
+
+    /// The peer acked `len` pending bytes.
+    pub(crate) fn release(&self, len: usize) {
+        // saturating: bytes written to the session without going through the stream
+        // (as tests do) were never reserved
+        let previous = self
+            .pending
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| {
+                Some(pending.saturating_sub(len))
+            })
+            .unwrap_or_default();
+        let pending = previous.saturating_sub(len);
+        if pending <= self.low_water() && self.blocked.swap(false, Ordering::AcqRel) {
+            self.waker.wake();
         }
     }
+
+    /// The session is gone, a waiting writer must find out.
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+}
+
+fn closed() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "closed")
 }
 
-// Make sure LrcpStream is Unpin (it is, by default, since no !Unpin fields)
-impl Unpin for LrcpStream {}
 impl AsyncWrite for LrcpStream {
     fn poll_write(
         self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().write).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().write).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().write).poll_shutdown(cx)
+    }
+}
+
+impl AsyncRead for LrcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().read).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LrcpWriteHalf {
+    /// Pending while the session holds too many unacked bytes, see `SendWindow`.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.send_window.closed.load(Ordering::Acquire) {
+            return Poll::Ready(Err(closed()));
+        }
+        if self.send_window.poll_reserve(cx, buf.len()).is_pending() {
+            return Poll::Pending;
+        }
         let cmd = SessionCommand::Write { data: buf.to_vec() };
         if self.session_cmd_tx.send(cmd).is_err() {
-            return Poll::Ready(Err(std::io::Error::new(
-                std::io::ErrorKind::BrokenPipe,
-                "closed",
-            )));
+            return Poll::Ready(Err(closed()));
         }
         Poll::Ready(Ok(buf.len()))
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        // For now, flush is a no-op since writes are immediate
-        Poll::Ready(Ok(()))
+    /// Resolves once the session has put every previously written byte on the wire.
+    /// It does not wait for the peer to ack them.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.pending_flush.is_none() {
+            let (done_tx, done_rx) = oneshot::channel();
+            let cmd = SessionCommand::Flush { done: done_tx };
+            if this.session_cmd_tx.send(cmd).is_err() {
+                return Poll::Ready(Err(closed()));
+            }
+            this.pending_flush = Some(done_rx);
+        }
+
+        let done_rx = this.pending_flush.as_mut().unwrap();
+        match Pin::new(done_rx).poll(cx) {
+            Poll::Ready(result) => {
+                this.pending_flush = None;
+                // The session dropped the fence without replying: it is gone
+                Poll::Ready(result.map_err(|_| closed()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
@@ -73,7 +232,7 @@ impl AsyncWrite for LrcpStream {
     }
 }
 
-impl AsyncRead for LrcpStream {
+impl AsyncRead for LrcpReadHalf {
     /// handles the raw byte streaming from the session to the application
     /// `BufReader::read_line()`` calls `poll_read()`
     /// BufReader has an internal buffer (typically 8KB)
@@ -2,6 +2,12 @@ mod client;
 mod lrcp;
 mod server;
 
+#[allow(unused)]
+pub use lrcp::LrcpListener;
 #[allow(unused)]
 pub use lrcp::RETRANSMIT_MILLIS;
-pub use server::run;
+#[allow(unused)]
+pub use lrcp::parse_packet;
+pub use server::{EchoHandler, LineApp, run, run_with};
+#[allow(unused)]
+pub use server::{LineHandler, ReverseHandler, serve_with};
@@ -2,41 +2,113 @@ use super::lrcp::*;
 use crate::Result;
 use crate::protohackers::HOST;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::io::AsyncBufReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::io::BufReader;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 use tracing::debug;
 use tracing::error;
+use tracing::info;
 
-pub async fn run(port: u32) -> Result<()> {
+/// The application run over each LRCP session: one response line per received line.
+pub trait LineHandler: Send + Sync + 'static {
+    /// `line` comes without its newline, which is added back to the response.
+    fn respond(&self, line: &str) -> String;
+}
+
+/// Line reversal, the application of problem 7.
+pub struct ReverseHandler;
+
+impl LineHandler for ReverseHandler {
+    fn respond(&self, line: &str) -> String {
+        line.chars().rev().collect::<String>().trim().to_string()
+    }
+}
+
+/// Sends every line back unchanged.
+pub struct EchoHandler;
+
+impl LineHandler for EchoHandler {
+    fn respond(&self, line: &str) -> String {
+        line.to_string()
+    }
+}
+
+/// The applications selectable from the command line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum LineApp {
+    /// `ReverseHandler`
+    #[default]
+    Reverse,
+    /// `EchoHandler`
+    Echo,
+}
+
+pub async fn run(port: u32, shutdown: CancellationToken) -> Result<()> {
+    run_with(port, ReverseHandler, shutdown).await
+}
+
+/// Serve `handler` over LRCP until `shutdown` is cancelled.
+pub async fn run_with<H: LineHandler>(
+    port: u32,
+    handler: H,
+    shutdown: CancellationToken,
+) -> Result<()> {
     let address = format!("{}:{}", HOST, port);
-    let mut listener = LrcpListener::bind(&address).await?;
+    serve_with(LrcpListener::bind(&address).await?, handler, shutdown).await
+}
 
-    loop {
-        let (stream, peer_addr) = listener.accept().await?;
+/// Serve `handler` over the sessions of an already bound `listener`.
+pub async fn serve_with<H: LineHandler>(
+    mut listener: LrcpListener,
+    handler: H,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let handler = Arc::new(handler);
+    let started = Instant::now();
+    let mut sessions = 0usize;
+
+    let result = loop {
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => break Err(e),
+            },
+            _ = shutdown.cancelled() => break Ok(()),
+        };
+        sessions += 1;
+        let handler = handler.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_session(stream, peer_addr).await {
+            if let Err(e) = handle_session(stream, peer_addr, handler.as_ref()).await {
                 error!("Session error ({}): {}", peer_addr, e);
             }
         });
-    }
+    };
+    info!(sessions, uptime = ?started.elapsed(), "Server stopped");
+    result
 }
 
-async fn handle_session(stream: LrcpStream, _peer_addr: SocketAddr) -> Result<()> {
-    let mut buffered_stream = BufReader::new(stream);
+async fn handle_session<H: LineHandler>(
+    stream: LrcpStream,
+    _peer_addr: SocketAddr,
+    handler: &H,
+) -> Result<()> {
+    let (read_half, mut write_half) = stream.split();
+    let mut reader = BufReader::new(read_half);
     let mut line = String::new();
 
     loop {
-        let bytes_read = buffered_stream.read_line(&mut line).await?;
+        let bytes_read = reader.read_line(&mut line).await?;
 
         if bytes_read == 0 {
             debug!("EOF reached");
             break;
         }
 
-        let reversed: String = line.chars().rev().collect();
-        let response: String = reversed.trim().to_string() + "\n";
-        if let Err(e) = buffered_stream.write_all(response.as_bytes()).await {
+        let response = handler.respond(line.strip_suffix('\n').unwrap_or(&line)) + "\n";
+        if let Err(e) = write_half.write_all(response.as_bytes()).await {
             error!("Write failed: {}", e);
             break;
         }
@@ -0,0 +1,203 @@
+//! Record every byte exchanged on a connection into a per-connection file,
+//! so a failing checker session can be inspected and replayed.
+use crate::Result;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::sync::mpsc;
+use tracing::error;
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Wraps a stream (usually a `TcpStream`, before it is framed) and logs what is read
+/// from and written to it, as hex and ASCII.
+/// With transcripts disabled it only passes the bytes through.
+///
+/// The file is written by a task of its own, so polling the stream never waits for the disk.
+pub struct TranscriptStream<S> {
+    inner: S,
+    records: Option<mpsc::UnboundedSender<String>>,
+}
+
+impl<S> TranscriptStream<S> {
+    /// Log the traffic of `inner` into `dir`, in a file named after `peer`.
+    /// Transcripts are disabled when `dir` is `None`.
+    pub async fn new(inner: S, dir: Option<&Path>, peer: SocketAddr) -> Result<Self> {
+        let records = match dir {
+            Some(dir) => {
+                tokio::fs::create_dir_all(dir).await?;
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(transcript_path(dir, peer))
+                    .await?;
+                let (records, records_rx) = mpsc::unbounded_channel();
+                tokio::spawn(write_records(file, records_rx));
+                Some(records)
+            }
+            None => None,
+        };
+        Ok(Self { inner, records })
+    }
+
+    fn record(&mut self, direction: char, bytes: &[u8]) {
+        let Some(records) = &self.records else {
+            return;
+        };
+        // the writer is gone once writing failed, it logged why
+        if records.send(format_record(direction, bytes)).is_err() {
+            self.records = None;
+        }
+    }
+}
+
+/// Append each record to `file` until the stream is dropped, flushing whenever
+/// no more records are waiting.
+async fn write_records(file: File, mut records: mpsc::UnboundedReceiver<String>) {
+    let mut file = BufWriter::new(file);
+    while let Some(record) = records.recv().await {
+        let mut result = file.write_all(record.as_bytes()).await;
+        if result.is_ok() && records.is_empty() {
+            result = file.flush().await;
+        }
+        if let Err(e) = result {
+            // a broken transcript must not break the connection
+            error!("Stop writing transcript: {}", e);
+            return;
+        }
+    }
+}
+
+/// Where the transcript of a connection from `peer` is written.
+pub fn transcript_path(dir: &Path, peer: SocketAddr) -> PathBuf {
+    dir.join(format!("{}_{}.log", peer.ip(), peer.port()))
+}
+
+/// `<` for bytes read from the peer, `>` for bytes written to it, e.g.
+///
+/// ```text
+/// < 6 bytes
+/// 0000  68 65 6c 6c 6f 0a                                 hello.
+/// ```
+fn format_record(direction: char, bytes: &[u8]) -> String {
+    let mut record = format!("{} {} bytes\n", direction, bytes.len());
+    for (i, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        let _ = write!(record, "{:04x} ", i * BYTES_PER_LINE);
+        for byte in line {
+            let _ = write!(record, " {:02x}", byte);
+        }
+        record.push_str(&"   ".repeat(BYTES_PER_LINE - line.len()));
+        record.push_str("  ");
+        record.extend(line.iter().map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            }
+        }));
+        record.push('\n');
+    }
+    record
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TranscriptStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if !read.is_empty() {
+            this.record('<', read);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TranscriptStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.record('>', &buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn transcript_records_reads_and_writes() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("transcript-test-{}", std::process::id()));
+        let peer: SocketAddr = "127.0.0.1:4242".parse().unwrap();
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = TranscriptStream::new(server, Some(&dir), peer).await?;
+
+        client.write_all(b"hello\n").await?;
+        let mut buf = [0u8; 6];
+        server.read_exact(&mut buf).await?;
+        server.write_all(b"HI\x00\n").await?;
+        client.read_exact(&mut buf[..4]).await?;
+        drop(server);
+
+        let expected = format!(
+            "< 6 bytes\n0000  68 65 6c 6c 6f 0a{}  hello.\n\
+             > 4 bytes\n0000  48 49 00 0a{}  HI..\n",
+            "   ".repeat(10),
+            "   ".repeat(12),
+        );
+        // the records reach the file in the background
+        let path = transcript_path(&dir, peer);
+        let transcript = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                let transcript = tokio::fs::read_to_string(&path).await.unwrap();
+                if transcript.len() >= expected.len() {
+                    return transcript;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the transcript should be written");
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(transcript, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn disabled_transcript_passes_bytes_through() -> Result<()> {
+        let peer: SocketAddr = "127.0.0.1:4242".parse().unwrap();
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = TranscriptStream::new(server, None, peer).await?;
+
+        server.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+
+        Ok(())
+    }
+}
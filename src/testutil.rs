@@ -0,0 +1,150 @@
+//! Helpers shared by the channel-based tests.
+use crate::{Error, Result};
+use bytes::BytesMut;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// The receiving half of a tokio channel, bounded or not.
+pub trait Receiver<T> {
+    fn recv(&mut self) -> impl Future<Output = Option<T>>;
+}
+
+impl<T> Receiver<T> for mpsc::Receiver<T> {
+    fn recv(&mut self) -> impl Future<Output = Option<T>> {
+        mpsc::Receiver::recv(self)
+    }
+}
+
+impl<T> Receiver<T> for mpsc::UnboundedReceiver<T> {
+    fn recv(&mut self) -> impl Future<Output = Option<T>> {
+        mpsc::UnboundedReceiver::recv(self)
+    }
+}
+
+/// Wait for the next message on `rx`, so a test whose message never arrives
+/// fails with an error instead of hanging.
+pub async fn recv_timeout<T>(rx: &mut impl Receiver<T>, duration: Duration) -> Result<T> {
+    match tokio::time::timeout(duration, rx.recv()).await {
+        Ok(Some(msg)) => Ok(msg),
+        Ok(None) => Err(Error::Other(
+            "channel closed while waiting for a message".into(),
+        )),
+        Err(_) => Err(Error::Other(format!(
+            "no message received within {:?}",
+            duration
+        ))),
+    }
+}
+
+/// Check that `codec` encodes `message` to exactly `bytes`, decodes `bytes` back to `message`,
+/// and also decodes it when the bytes arrive one at a time.
+/// `new_codec` is called for each check so they do not share codec state.
+pub fn assert_roundtrip<C, T>(new_codec: impl Fn() -> C, message: T, bytes: &[u8])
+where
+    C: Encoder<T> + Decoder<Item = T>,
+    <C as Encoder<T>>::Error: Debug,
+    <C as Decoder>::Error: Debug,
+    T: Clone + Debug + PartialEq,
+{
+    let mut encoded = BytesMut::new();
+    new_codec()
+        .encode(message.clone(), &mut encoded)
+        .expect("encode failed");
+    assert_eq!(&encoded[..], bytes, "encoding {:?}", message);
+
+    let mut buf = BytesMut::from(bytes);
+    let decoded = new_codec().decode(&mut buf).expect("decode failed");
+    assert_eq!(decoded.as_ref(), Some(&message), "decoding {:02x?}", bytes);
+    assert!(buf.is_empty(), "decoding left {:02x?}", &buf[..]);
+
+    let mut codec = new_codec();
+    let mut buf = BytesMut::new();
+    for (i, byte) in bytes.iter().enumerate() {
+        buf.extend_from_slice(&[*byte]);
+        let decoded = codec.decode(&mut buf).expect("streaming decode failed");
+        if i + 1 < bytes.len() {
+            assert_eq!(decoded, None, "decoded early after {} bytes", i + 1);
+        } else {
+            assert_eq!(decoded.as_ref(), Some(&message), "streaming {:02x?}", bytes);
+        }
+    }
+    assert!(buf.is_empty(), "streaming decode left {:02x?}", &buf[..]);
+}
+
+/// Generate a test which checks with `assert_roundtrip` that a codec encodes and decodes
+/// a message to and from its expected bytes.
+///
+/// ```ignore
+/// roundtrip_codec!(heartbeat, MessageCodec::new(), Message::Heartbeat, &[0x41]);
+/// ```
+macro_rules! roundtrip_codec {
+    ($name:ident, $codec:expr, $message:expr, $bytes:expr $(,)?) => {
+        #[test]
+        fn $name() {
+            $crate::testutil::assert_roundtrip(|| $codec, $message, $bytes);
+        }
+    };
+}
+pub(crate) use roundtrip_codec;
+
+/// Log output captured by a test subscriber.
+#[derive(Clone)]
+pub struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CapturedLogs {
+    pub fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+/// Capture debug logs of this thread until the guard is dropped. With the current thread
+/// runtime of `#[tokio::test]` the spawned tasks log through this subscriber too.
+pub fn capture_logs() -> (CapturedLogs, tracing::subscriber::DefaultGuard) {
+    let captured = CapturedLogs(Arc::new(Mutex::new(Vec::new())));
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    (captured, tracing::subscriber::set_default(subscriber))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_message_times_out() {
+        let (_tx, mut rx) = mpsc::channel::<u8>(1);
+        let result = recv_timeout(&mut rx, Duration::from_secs(1)).await;
+        assert!(matches!(result, Err(Error::Other(e)) if e.contains("no message received")));
+    }
+
+    #[tokio::test]
+    async fn closed_channel_fails() {
+        let (tx, mut rx) = mpsc::unbounded_channel::<u8>();
+        tx.send(1).unwrap();
+        drop(tx);
+        assert_eq!(
+            recv_timeout(&mut rx, Duration::from_secs(1)).await.unwrap(),
+            1
+        );
+        assert!(recv_timeout(&mut rx, Duration::from_secs(1)).await.is_err());
+    }
+}
@@ -2,12 +2,15 @@
 mod line_reversal_tests {
     #[allow(unused)]
     use ::tracing::debug;
-    use protohacker_in_rust::protohackers::problem7::{RETRANSMIT_MILLIS, run};
+    use protohacker_in_rust::protohackers::problem7::{
+        EchoHandler, LineHandler, LrcpListener, RETRANSMIT_MILLIS, ReverseHandler, run, serve_with,
+    };
     use protohacker_in_rust::tracer;
     use protohacker_in_rust::{Error, Result};
     use std::time::Duration;
     use tokio::net::UdpSocket;
     use tokio::time::timeout;
+    use tokio_util::sync::CancellationToken;
 
     use std::sync::Once;
 
@@ -45,7 +48,7 @@ mod line_reversal_tests {
 
         // Start the server in the background
         let server_handle = tokio::spawn(async {
-            if let Err(e) = run(SERVER_PORT).await {
+            if let Err(e) = run(SERVER_PORT, CancellationToken::new()).await {
                 eprintln!("Server error: {:?}", e);
             }
         });
@@ -144,7 +147,7 @@ mod line_reversal_tests {
 
         // Start the server in the background
         let server_handle = tokio::spawn(async {
-            if let Err(e) = run(SERVER_PORT).await {
+            if let Err(e) = run(SERVER_PORT, CancellationToken::new()).await {
                 eprintln!("Server error: {:?}", e);
             }
         });
@@ -223,7 +226,7 @@ mod line_reversal_tests {
 
         // Start the server in the background
         let server_handle = tokio::spawn(async {
-            if let Err(e) = run(SERVER_PORT).await {
+            if let Err(e) = run(SERVER_PORT, CancellationToken::new()).await {
                 eprintln!("Server error: {:?}", e);
             }
         });
@@ -321,7 +324,7 @@ mod line_reversal_tests {
 
         // Start the server in the background
         let server_handle = tokio::spawn(async {
-            if let Err(e) = run(SERVER_PORT).await {
+            if let Err(e) = run(SERVER_PORT, CancellationToken::new()).await {
                 eprintln!("Server error: {:?}", e);
             }
         });
@@ -409,4 +412,73 @@ mod line_reversal_tests {
 
         Ok(())
     }
+
+    /// Run `handler` on a free port, send two lines in one session and return what comes back.
+    async fn session_with_handler<H: LineHandler>(handler: H) -> Result<Vec<String>> {
+        let listener = LrcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = listener.local_addr().to_string();
+        let server_handle = tokio::spawn(serve_with(listener, handler, CancellationToken::new()));
+
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await?;
+        udp_send(
+            &client_socket,
+            &server_addr,
+            &format!("/connect/{SESSION_ID}/"),
+        )
+        .await?;
+        assert_eq!(
+            udp_recv(&client_socket).await?,
+            format!("/ack/{SESSION_ID}/0/")
+        );
+
+        udp_send(
+            &client_socket,
+            &server_addr,
+            &format!("/data/{SESSION_ID}/0/hello\nfoo\\/bar\n/"),
+        )
+        .await?;
+        assert_eq!(
+            udp_recv(&client_socket).await?,
+            format!("/ack/{SESSION_ID}/14/")
+        );
+
+        let mut replies = Vec::new();
+        let mut received = 0;
+        while replies.len() < 2 {
+            let packet = udp_recv(&client_socket).await?;
+            let prefix = format!("/data/{SESSION_ID}/{received}/");
+            let data = packet
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix('/'))
+                .unwrap_or_else(|| panic!("unexpected packet {:?}", packet));
+            let line = data.replace("\\/", "/");
+            received += line.len();
+            udp_send(
+                &client_socket,
+                &server_addr,
+                &format!("/ack/{SESSION_ID}/{received}/"),
+            )
+            .await?;
+            replies.push(line);
+        }
+
+        server_handle.abort();
+        Ok(replies)
+    }
+
+    #[tokio::test]
+    async fn test_reverse_handler_session() -> Result<()> {
+        let replies = session_with_handler(ReverseHandler).await?;
+        assert_eq!(replies, ["olleh\n", "rab/oof\n"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_echo_handler_session() -> Result<()> {
+        let replies = session_with_handler(EchoHandler).await?;
+        assert_eq!(replies, ["hello\n", "foo/bar\n"]);
+
+        Ok(())
+    }
 }
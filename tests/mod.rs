@@ -1,2 +1,3 @@
 mod lrcp_e2e;
 mod maelstrom;
+mod speed_daemon_e2e;
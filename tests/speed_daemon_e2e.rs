@@ -0,0 +1,105 @@
+#[cfg(test)]
+mod speed_daemon_tests {
+    use protohacker_in_rust::protohackers::ServerBuilder;
+    use protohacker_in_rust::protohackers::problem6::{TicketConfig, run};
+    use protohacker_in_rust::{Error, Result};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::timeout;
+
+    const HEARTBEAT: u8 = 0x41;
+    const TICKET: u8 = 0x21;
+
+    async fn connect(port: u16) -> Result<TcpStream> {
+        loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => return Ok(stream),
+                // the server may not be listening yet
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }
+
+    /// IAmCamera{road, mile, limit}
+    fn i_am_camera(road: u16, mile: u16, limit: u16) -> Vec<u8> {
+        let mut frame = vec![0x80];
+        for field in [road, mile, limit] {
+            frame.extend_from_slice(&field.to_be_bytes());
+        }
+        frame
+    }
+
+    /// Plate{plate, timestamp}
+    fn plate(plate: &str, timestamp: u32) -> Vec<u8> {
+        let mut frame = vec![0x20, plate.len() as u8];
+        frame.extend_from_slice(plate.as_bytes());
+        frame.extend_from_slice(&timestamp.to_be_bytes());
+        frame
+    }
+
+    async fn read_byte(stream: &mut TcpStream) -> Result<u8> {
+        timeout(Duration::from_secs(2), stream.read_u8())
+            .await
+            .map_err(|_| Error::Other("Timeout waiting for the server".into()))?
+            .map_err(Error::from)
+    }
+
+    #[tokio::test]
+    /// Example session from protohacker, with the dispatcher asking for heartbeats
+    async fn test_ticket_and_heartbeats_over_tcp() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let server_handle = tokio::spawn(run(
+            ServerBuilder::new(0).listener(listener),
+            None,
+            TicketConfig::default(),
+        ));
+
+        // the dispatcher for road 123 wants a heartbeat every 100ms
+        let mut dispatcher = connect(port).await?;
+        dispatcher.write_all(&[0x81, 0x01, 0x00, 0x7b]).await?;
+        dispatcher
+            .write_all(&[0x40, 0x00, 0x00, 0x00, 0x01])
+            .await?;
+        assert_eq!(read_byte(&mut dispatcher).await?, HEARTBEAT);
+
+        // UN1X drives 1 mile in 45 seconds, 80 mph on a 60 mph road
+        let mut camera1 = connect(port).await?;
+        camera1.write_all(&i_am_camera(123, 8, 60)).await?;
+        camera1.write_all(&plate("UN1X", 0)).await?;
+        let mut camera2 = connect(port).await?;
+        camera2.write_all(&i_am_camera(123, 9, 60)).await?;
+        camera2.write_all(&plate("UN1X", 45)).await?;
+
+        // heartbeats keep coming until the ticket arrives
+        loop {
+            match read_byte(&mut dispatcher).await? {
+                HEARTBEAT => continue,
+                TICKET => break,
+                other => panic!("unexpected message type {:#04x}", other),
+            }
+        }
+        let mut ticket = [0u8; 21];
+        timeout(Duration::from_secs(2), dispatcher.read_exact(&mut ticket))
+            .await
+            .map_err(|_| Error::Other("Timeout waiting for the ticket".into()))??;
+        assert_eq!(
+            ticket,
+            [
+                0x04, 0x55, 0x4e, 0x31, 0x58, // plate "UN1X"
+                0x00, 0x7b, // road 123
+                0x00, 0x08, 0x00, 0x00, 0x00, 0x00, // mile 8 at 0
+                0x00, 0x09, 0x00, 0x00, 0x00, 0x2d, // mile 9 at 45
+                0x1f, 0x40, // 80.00 mph
+            ]
+        );
+
+        // and after it
+        assert_eq!(read_byte(&mut dispatcher).await?, HEARTBEAT);
+
+        server_handle.abort();
+
+        Ok(())
+    }
+}